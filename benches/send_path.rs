@@ -0,0 +1,199 @@
+//! Measures how fast points get from a batch into the TCP buffer, the way the WebSocket tasks
+//! send them, against the way they used to.
+//!
+//! Run it on a computer with
+//! `cargo +stable bench --target x86_64-unknown-linux-gnu --bench send_path`. The numbers are
+//! only good for comparing the two, the scope's cores are a lot slower and its heap allocations a
+//! lot more expensive, so those are counted too.
+
+use core::{
+    convert::Infallible,
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+use embedded_io_async::{ErrorType, Write};
+use just_a_scope::{
+    adc::AdcSettings,
+    calibration::{Calibration, ProbeCalibration},
+    protocol::{StreamEncoder, WireFormat},
+    websocket_logistics::{send_points, OscilliscopePoint, CHANNELS, CODE_FRACTION_BITS},
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+/// As big as the TCP buffers of the WebSocket sockets.
+const TCP_BUFFER_SIZE: usize = 500;
+/// The messages the WebSocket tasks used to send, seven points each, and the ones they send now.
+const OLD_MESSAGE_SIZE: usize = 112;
+/// The voltage and the second of a point, as the buffer used to keep it.
+const OLD_POINT_LENGTH: usize = 16;
+const NEW_FRAME_SIZE: usize = TCP_BUFFER_SIZE - 4;
+const POINTS_PER_BATCH: usize = 4096;
+const ROUNDS: usize = 2000;
+
+/// Counts the heap allocations.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(pointer, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Copies what is written into a buffer as big as the TCP buffer, which is sent off whenever it
+/// is full.
+struct TcpBuffer {
+    buffer: [u8; TCP_BUFFER_SIZE],
+    filled: usize,
+    bytes: usize,
+    writes: usize,
+}
+
+impl ErrorType for TcpBuffer {
+    type Error = Infallible;
+}
+
+impl Write for TcpBuffer {
+    async fn write(&mut self, data: &[u8]) -> Result<usize, Infallible> {
+        if self.filled == TCP_BUFFER_SIZE {
+            self.filled = 0;
+        }
+        let length = data.len().min(TCP_BUFFER_SIZE - self.filled);
+        self.buffer[self.filled..self.filled + length].copy_from_slice(&data[..length]);
+        self.filled += length;
+        self.bytes += length;
+        self.writes += 1;
+        Ok(length)
+    }
+}
+
+/// Runs a future that never has to wait, like writing to a `TcpBuffer`.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    match future
+        .as_mut()
+        .poll(&mut Context::from_waker(Waker::noop()))
+    {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("The future had to wait."),
+    }
+}
+
+/// How frames were sent before: header and data copied into a new frame on the heap.
+async fn old_send_message<W: Write>(to: &mut W, data: &[u8]) -> Result<(), W::Error> {
+    let len = data.len() as u64;
+    if len == 0 {
+        Ok(())
+    } else if len <= 126 {
+        let header = [0b10000010u8, len as u8];
+        to.write_all(&[&header, data].concat()).await
+    } else {
+        let header = [0b10000010u8, 126u8];
+        let payload_length = (len as u16).to_be_bytes();
+        to.write_all(&[&header, &payload_length, data].concat())
+            .await
+    }
+}
+
+/// How points were sent before, straight from the buffer in messages of `OLD_MESSAGE_SIZE`.
+async fn old_send_points<W: Write>(to: &mut W, bytes: &[u8]) -> Result<(), W::Error> {
+    for message in bytes.chunks(OLD_MESSAGE_SIZE) {
+        old_send_message(to, message).await?;
+    }
+    Ok(())
+}
+
+/// The points as the buffer used to keep them, ready to send.
+fn old_bytes(points: &[OscilliscopePoint]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(points.len() * OLD_POINT_LENGTH);
+    for point in points {
+        bytes.extend_from_slice(&point.voltage.to_le_bytes());
+        bytes.extend_from_slice(&(point.microsecond as f64 / 1_000_000f64).to_le_bytes());
+    }
+    bytes
+}
+
+/// Points of both channels taking turns, a millisecond apart.
+fn batch() -> Vec<OscilliscopePoint> {
+    (0..POINTS_PER_BATCH)
+        .map(|i| OscilliscopePoint {
+            voltage: 0f64,
+            microsecond: 1_000_000 + i as u64 * 1000,
+            code: ((i * 37 % 4096) as u16) << CODE_FRACTION_BITS,
+            index: (i / CHANNELS) as u32,
+            channel: (i % CHANNELS) as u8,
+            trigger: false,
+            heartbeat: false,
+            adc: AdcSettings::DEFAULT,
+        })
+        .collect()
+}
+
+fn encoder() -> StreamEncoder {
+    let curve = ProbeCalibration::linear(Calibration {
+        volts_per_code: 0.001,
+        offset_volts: 0f32,
+    });
+    StreamEncoder::new(WireFormat::V2, [[curve; AdcSettings::COUNT]; CHANNELS])
+}
+
+/// Sends the batch `ROUNDS` times with `send`, and prints how fast it went.
+fn measure(name: &str, points: &[OscilliscopePoint], mut send: impl FnMut(&mut TcpBuffer)) {
+    let mut to = TcpBuffer {
+        buffer: [0u8; TCP_BUFFER_SIZE],
+        filled: 0,
+        bytes: 0,
+        writes: 0,
+    };
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        send(&mut to);
+    }
+    let seconds = start.elapsed().as_secs_f64();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    black_box(&to.buffer);
+
+    let points = (points.len() * ROUNDS) as f64;
+    println!(
+        "{name}: {:.1} M points/s, {:.2} bytes per point, {:.1} bytes per write, {:.1} allocations per 1000 points",
+        points / seconds / 1e6,
+        to.bytes as f64 / points,
+        to.bytes as f64 / to.writes as f64,
+        allocations as f64 / points * 1000f64,
+    );
+}
+
+fn main() {
+    let points = batch();
+    let bytes = old_bytes(&points);
+    measure("before", &points, |to| {
+        block_on(old_send_points(to, black_box(&bytes))).unwrap()
+    });
+    // A new encoder every round, since it skips points it has sent already.
+    let mut frame = [0u8; NEW_FRAME_SIZE];
+    measure("after", &points, |to| {
+        block_on(send_points(
+            to,
+            &mut encoder(),
+            black_box(&points),
+            &mut frame,
+        ))
+        .unwrap()
+    });
+}
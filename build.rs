@@ -15,25 +15,89 @@ struct AccessPoint {
 }
 
 #[derive(Deserialize)]
-struct Voltages {
-    adc_reference_voltage: f64,
+struct Channel {
+    enabled: bool,
     probes_shorted: f64,
     max_voltage_absolute: f64,
+    attenuation: String,
+    adc_calibration: String,
+    autorange: bool,
+}
+
+#[derive(Deserialize)]
+struct AutoRange {
+    window_ms: f64,
+}
+
+#[derive(Deserialize)]
+struct Filter {
+    chain: String,
+}
+
+#[derive(Deserialize)]
+struct Calibration {
+    samples: u32,
 }
 
 #[derive(Deserialize)]
 struct Precision {
     tolerance_factor: f64,
     min_voltage_difference: f64,
+    adaptive_thresholds: bool,
+    max_gap_ms: f64,
+    heartbeat_ms: f64,
     samples_per_point: u32,
+    decimation: String,
+}
+
+#[derive(Deserialize)]
+struct Sampling {
+    backend: String,
+    sample_rate_hz: u32,
+}
+
+#[derive(Deserialize)]
+struct Buffer {
+    overflow_policy: String,
+    block_timeout_us: u32,
+    backpressure: bool,
+    wakeup_points: u32,
+    wakeup_latency_ms: u32,
+}
+
+#[derive(Deserialize)]
+struct History {
+    megabytes: u32,
+    seconds: f64,
+}
+
+#[derive(Deserialize)]
+struct Trigger {
+    channel: u8,
+    mode: String,
+    auto_timeout_ms: f64,
+    slope: String,
+    level: f64,
+    pre_trigger_percent: u8,
+    frame_ms: f64,
+    holdoff_ms: f64,
+    pre_trigger_points: u32,
 }
 
 #[derive(Deserialize)]
 struct Config {
     station: Station,
     access_point: AccessPoint,
-    voltages: Voltages,
+    channel1: Channel,
+    channel2: Channel,
+    autorange: AutoRange,
+    filter: Filter,
+    calibration: Calibration,
     precision: Precision,
+    sampling: Sampling,
+    buffer: Buffer,
+    history: History,
+    trigger: Trigger,
 }
 
 fn add_env_var(name: &str, value: &str) {
@@ -65,18 +129,85 @@ fn main() {
     );
     add_env_var("access_point_password", &ap_creds.password);
 
-    // Voltages
-    let voltages = config.voltages;
-    add_env_var(
-        "adc_reference_voltage",
-        &voltages.adc_reference_voltage.to_string(),
+    // Channels
+    assert!(
+        config.channel1.enabled || config.channel2.enabled,
+        "At least one channel has to be enabled."
+    );
+    for (name, channel) in [("channel1", config.channel1), ("channel2", config.channel2)] {
+        assert!(
+            ["0db", "2.5db", "6db", "11db"].contains(&channel.attenuation.as_str()),
+            "Attenuation must be one of 0db, 2.5db, 6db or 11db."
+        );
+        assert!(
+            ["basic", "line", "curve"].contains(&channel.adc_calibration.as_str()),
+            "ADC calibration must be one of basic, line or curve."
+        );
+        add_env_var(&format!("{name}_enabled"), &channel.enabled.to_string());
+        add_env_var(
+            &format!("{name}_probes_shorted"),
+            &channel.probes_shorted.to_string(),
+        );
+        add_env_var(
+            &format!("{name}_max_voltage_absolute"),
+            &channel.max_voltage_absolute.to_string(),
+        );
+        add_env_var(&format!("{name}_attenuation"), &channel.attenuation);
+        add_env_var(&format!("{name}_adc_calibration"), &channel.adc_calibration);
+        add_env_var(&format!("{name}_autorange"), &channel.autorange.to_string());
+    }
+
+    // Auto-ranging
+    let autorange = config.autorange;
+    assert!(
+        autorange.window_ms > 0.0,
+        "The auto-ranging window has to be longer than 0 ms."
     );
-    add_env_var("probes_shorted", &voltages.probes_shorted.to_string());
     add_env_var(
-        "max_voltage_absolute",
-        &voltages.max_voltage_absolute.to_string(),
+        "autorange_window_us",
+        &((autorange.window_ms * 1000.0) as u64).to_string(),
     );
 
+    // Filter
+    let filter = config.filter;
+    if filter.chain != "off" {
+        let words: Vec<&str> = filter.chain.split_whitespace().collect();
+        assert!(
+            !words.is_empty() && words.len() & 1 == 0 && words.len() <= 8,
+            "The filter chain must be off, or one to four stages with an argument each."
+        );
+        for stage in words.chunks(2) {
+            assert!(
+                ["average", "median", "lowpass", "biquad", "oversample"].contains(&stage[0]),
+                "Filter stages must be one of average, median, lowpass, biquad or oversample."
+            );
+            let argument: u32 = stage[1]
+                .parse()
+                .expect("Every filter stage needs a whole number after it.");
+            let range = match stage[0] {
+                "average" | "median" => 1..=32,
+                "oversample" => 1..=4,
+                _ => 1..=83_333,
+            };
+            assert!(
+                range.contains(&argument),
+                "The argument of filter stage {} must be from {} to {}.",
+                stage[0],
+                range.start(),
+                range.end()
+            );
+        }
+    }
+    add_env_var("filter_chain", &filter.chain);
+
+    // Calibration
+    let calibration = config.calibration;
+    assert!(
+        calibration.samples > 0,
+        "Calibrating has to average at least one sample."
+    );
+    add_env_var("calibration_samples", &calibration.samples.to_string());
+
     // Precision
     let precision = config.precision;
     assert!(precision.tolerance_factor >= 0.0 && precision.tolerance_factor <= 1.0);
@@ -85,10 +216,124 @@ fn main() {
         "min_voltage_difference",
         &precision.min_voltage_difference.to_string(),
     );
+    add_env_var(
+        "adaptive_thresholds",
+        &precision.adaptive_thresholds.to_string(),
+    );
+    assert!(
+        precision.max_gap_ms > 0.0,
+        "The longest gap between points has to be longer than 0 ms."
+    );
+    assert!(precision.heartbeat_ms >= 0.0);
+    add_env_var(
+        "max_point_gap_us",
+        &((precision.max_gap_ms * 1000.0) as u64).to_string(),
+    );
+    add_env_var(
+        "heartbeat_us",
+        &((precision.heartbeat_ms * 1000.0) as u64).to_string(),
+    );
     add_env_var(
         "samples_per_point",
         &precision.samples_per_point.to_string(),
     );
+    let decimation: Vec<&str> = precision.decimation.split_whitespace().collect();
+    assert!(
+        match decimation[..] {
+            ["slope"] => true,
+            ["peak", bucket_us] => bucket_us
+                .parse::<u32>()
+                .is_ok_and(|bucket_us| bucket_us > 0),
+            ["swingdoor", volts] => volts.parse::<f32>().is_ok_and(|volts| volts > 0.0),
+            _ => false,
+        },
+        "Decimation must be slope, peak with a bucket length in microseconds, or swingdoor with \
+         the largest error in volts."
+    );
+    add_env_var("decimation_mode", &precision.decimation);
+
+    // Sampling
+    let sampling = config.sampling;
+    assert!(
+        ["dma", "oneshot"].contains(&sampling.backend.as_str()),
+        "Sampling backend must be one of dma or oneshot."
+    );
+    assert!(
+        sampling.sample_rate_hz <= 83_333,
+        "Sample rate must be at most 83333 Hz."
+    );
+    add_env_var("sampling_backend", &sampling.backend);
+    add_env_var("sampling_rate_hz", &sampling.sample_rate_hz.to_string());
+
+    // Buffer
+    let buffer = config.buffer;
+    assert!(
+        ["drop_newest", "overwrite_oldest", "block"].contains(&buffer.overflow_policy.as_str()),
+        "Overflow policy must be one of drop_newest, overwrite_oldest or block."
+    );
+    add_env_var("overflow_policy", &buffer.overflow_policy);
+    add_env_var("block_timeout_us", &buffer.block_timeout_us.to_string());
+    add_env_var("backpressure", &buffer.backpressure.to_string());
+    assert!(
+        buffer.wakeup_points >= 1,
+        "The WebSocket tasks have to wake up for at least one point."
+    );
+    assert!(
+        buffer.wakeup_latency_ms >= 1,
+        "Points have to be allowed to wait at least a millisecond."
+    );
+    add_env_var("wakeup_points", &buffer.wakeup_points.to_string());
+    add_env_var("wakeup_latency_ms", &buffer.wakeup_latency_ms.to_string());
+
+    // History
+    let history = config.history;
+    assert!(
+        history.megabytes <= 7,
+        "The history has to fit in the 8 MB of PSRAM, next to whatever else ends up there."
+    );
+    add_env_var("history_megabytes", &history.megabytes.to_string());
+    add_env_var("history_seconds", &history.seconds.to_string());
+
+    // Trigger
+    let trigger = config.trigger;
+    assert!(
+        ["off", "rising", "falling", "either"].contains(&trigger.slope.as_str()),
+        "Trigger slope must be one of off, rising, falling or either."
+    );
+    assert!(
+        ["auto", "normal", "single"].contains(&trigger.mode.as_str()),
+        "Trigger mode must be one of auto, normal or single."
+    );
+    assert!(
+        (1..=2).contains(&trigger.channel),
+        "Trigger channel must be 1 or 2."
+    );
+    assert!(trigger.pre_trigger_percent <= 100);
+    assert!(trigger.frame_ms > 0.0);
+    add_env_var("trigger_channel", &(trigger.channel - 1).to_string());
+    add_env_var("trigger_mode", &trigger.mode);
+    add_env_var(
+        "trigger_auto_timeout_us",
+        &((trigger.auto_timeout_ms * 1000.0) as u64).to_string(),
+    );
+    add_env_var("trigger_slope", &trigger.slope);
+    add_env_var("trigger_level", &trigger.level.to_string());
+    add_env_var(
+        "trigger_pre_trigger_percent",
+        &trigger.pre_trigger_percent.to_string(),
+    );
+    add_env_var(
+        "trigger_frame_us",
+        &((trigger.frame_ms * 1000.0) as u64).to_string(),
+    );
+    add_env_var(
+        "trigger_holdoff_us",
+        &((trigger.holdoff_ms * 1000.0) as u64).to_string(),
+    );
+    add_env_var(
+        "trigger_pre_trigger_points",
+        &trigger.pre_trigger_points.to_string(),
+    );
 
     println!("cargo:rustc-link-arg-bins=-Tlinkall.x");
 }
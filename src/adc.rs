@@ -0,0 +1,173 @@
+//! The ADC's input ranges, and how its codes are corrected with the calibration in the chip's
+//! eFuses. Both can be picked for every channel.
+
+/// How far the ADC attenuates its input, which sets the range of voltages it measures. Less
+/// attenuation resolves smaller signals better.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Attenuation {
+    Db0,
+    Db2_5,
+    Db6,
+    Db11,
+}
+
+impl Attenuation {
+    pub const ALL: [Attenuation; 4] = [
+        Attenuation::Db0,
+        Attenuation::Db2_5,
+        Attenuation::Db6,
+        Attenuation::Db11,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Attenuation> {
+        Self::ALL
+            .into_iter()
+            .find(|attenuation| attenuation.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Attenuation::Db0 => "0db",
+            Attenuation::Db2_5 => "2.5db",
+            Attenuation::Db6 => "6db",
+            Attenuation::Db11 => "11db",
+        }
+    }
+
+    /// The highest voltage at the ADC's pin that it measures, from the ESP32-S3's datasheet.
+    pub fn full_scale_voltage(&self) -> f64 {
+        match self {
+            Attenuation::Db0 => 0.95,
+            Attenuation::Db2_5 => 1.25,
+            Attenuation::Db6 => 1.75,
+            Attenuation::Db11 => 3.1,
+        }
+    }
+}
+
+/// How the ADC's codes are corrected with the calibration in the eFuses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CalibrationScheme {
+    /// Only corrects the ADC's offset, codes stay codes.
+    Basic,
+    /// Turns codes into millivolts at the pin along a straight line.
+    Line,
+    /// Turns codes into millivolts at the pin along the chip's curve, which is straighter near
+    /// the ends of the range.
+    Curve,
+}
+
+impl CalibrationScheme {
+    pub const ALL: [CalibrationScheme; 3] = [
+        CalibrationScheme::Basic,
+        CalibrationScheme::Line,
+        CalibrationScheme::Curve,
+    ];
+
+    pub fn from_name(name: &str) -> Option<CalibrationScheme> {
+        Self::ALL.into_iter().find(|scheme| scheme.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CalibrationScheme::Basic => "basic",
+            CalibrationScheme::Line => "line",
+            CalibrationScheme::Curve => "curve",
+        }
+    }
+}
+
+/// How a channel's ADC is set up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdcSettings {
+    pub attenuation: Attenuation,
+    pub scheme: CalibrationScheme,
+}
+
+impl AdcSettings {
+    /// How many different settings there are, see `index`.
+    pub const COUNT: usize = Attenuation::ALL.len() * CalibrationScheme::ALL.len();
+
+    /// What the scope did before the settings could be picked.
+    pub const DEFAULT: AdcSettings = AdcSettings {
+        attenuation: Attenuation::Db11,
+        scheme: CalibrationScheme::Basic,
+    };
+
+    /// The voltage at the ADC's pin that the code 4095 stands for. Codes corrected to millivolts
+    /// go up to 4095 mV, even though the ADC never measures that much.
+    pub fn reference_voltage(&self) -> f64 {
+        match self.scheme {
+            CalibrationScheme::Basic => self.attenuation.full_scale_voltage(),
+            CalibrationScheme::Line | CalibrationScheme::Curve => 4.095,
+        }
+    }
+
+    /// Numbers all settings from 0 to `COUNT`, for looking things up by settings.
+    pub fn index(&self) -> usize {
+        self.scheme as usize * Attenuation::ALL.len() + self.attenuation as usize
+    }
+
+    pub fn from_index(index: usize) -> AdcSettings {
+        AdcSettings {
+            attenuation: Attenuation::ALL[index % Attenuation::ALL.len()],
+            scheme: CalibrationScheme::ALL[index / Attenuation::ALL.len()],
+        }
+    }
+
+    /// Packs the settings into a byte, for atomics and flash.
+    pub const fn to_bits(self) -> u8 {
+        self.attenuation as u8 | (self.scheme as u8) << 4
+    }
+
+    pub fn from_bits(bits: u8) -> Option<AdcSettings> {
+        Some(AdcSettings {
+            attenuation: *Attenuation::ALL.get((bits & 0xf) as usize)?,
+            scheme: *CalibrationScheme::ALL.get((bits >> 4) as usize)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_survive_being_packed() {
+        for attenuation in Attenuation::ALL {
+            for scheme in CalibrationScheme::ALL {
+                let settings = AdcSettings {
+                    attenuation,
+                    scheme,
+                };
+                assert_eq!(AdcSettings::from_bits(settings.to_bits()), Some(settings));
+            }
+        }
+        for index in 0..AdcSettings::COUNT {
+            assert_eq!(AdcSettings::from_index(index).index(), index);
+        }
+        assert_eq!(AdcSettings::from_bits(0x04), None);
+        assert_eq!(AdcSettings::from_bits(0x30), None);
+    }
+
+    #[test]
+    fn names_are_read_back() {
+        assert_eq!(Attenuation::from_name("2.5db"), Some(Attenuation::Db2_5));
+        assert_eq!(Attenuation::from_name("3db"), None);
+        assert_eq!(
+            CalibrationScheme::from_name("curve"),
+            Some(CalibrationScheme::Curve)
+        );
+        assert_eq!(CalibrationScheme::from_name("fancy"), None);
+    }
+
+    #[test]
+    fn reference_voltage_follows_the_settings() {
+        let mut settings = AdcSettings::DEFAULT;
+        assert_eq!(settings.reference_voltage(), 3.1);
+        settings.attenuation = Attenuation::Db6;
+        assert_eq!(settings.reference_voltage(), 1.75);
+        settings.scheme = CalibrationScheme::Line;
+        assert_eq!(settings.reference_voltage(), 4.095);
+    }
+}
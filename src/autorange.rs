@@ -0,0 +1,157 @@
+//! Switching a channel's attenuation to suit its signal: more of it as soon as the signal clips
+//! at the top of the ADC's range, less once the signal stayed in a small part of the range for
+//! a while.
+//!
+//! Less attenuation is only picked when the signal would still stay well below the top of the
+//! smaller range, so a signal that is just right does not switch back and forth.
+
+use crate::adc::Attenuation;
+
+/// Codes at or above this clip at the top of the range.
+const TOP_CLIPPING_CODE: u16 = 4095 - 64;
+/// Codes at or below this clip at the bottom. The bottom is 0 V at the ADC with any attenuation,
+/// so there is nothing to switch to, but a clipped signal does not show how small it is either.
+const BOTTOM_CLIPPING_CODE: u16 = 64;
+/// How much of the smaller range the signal may use, for switching to less attenuation.
+const HEADROOM: f64 = 0.8;
+
+/// Watches one channel's codes for a better attenuation.
+pub struct AutoRanger {
+    window_us: u64,
+    window_start_us: Option<u64>,
+    highest_code: u16,
+    clipped_at_bottom: bool,
+}
+
+impl AutoRanger {
+    /// Switches to less attenuation once the signal stayed small for `window_us`.
+    pub fn new(window_us: u64) -> AutoRanger {
+        AutoRanger {
+            window_us,
+            window_start_us: None,
+            highest_code: 0,
+            clipped_at_bottom: false,
+        }
+    }
+
+    /// Starts watching over, for when the attenuation changed.
+    pub fn restart(&mut self) {
+        self.window_start_us = None;
+        self.highest_code = 0;
+        self.clipped_at_bottom = false;
+    }
+
+    /// Takes the next code the ADC converted with `attenuation`, before it was corrected, and
+    /// returns the attenuation to switch to, if any.
+    pub fn push(
+        &mut self,
+        code: u16,
+        microsecond: u64,
+        attenuation: Attenuation,
+    ) -> Option<Attenuation> {
+        if code >= TOP_CLIPPING_CODE {
+            self.restart();
+            return more(attenuation);
+        }
+
+        let window_start_us = *self.window_start_us.get_or_insert(microsecond);
+        self.highest_code = self.highest_code.max(code);
+        self.clipped_at_bottom |= code <= BOTTOM_CLIPPING_CODE;
+        if microsecond.saturating_sub(window_start_us) < self.window_us {
+            return None;
+        }
+
+        let highest_voltage = self.highest_code as f64 * attenuation.full_scale_voltage() / 4095f64;
+        let clipped_at_bottom = self.clipped_at_bottom;
+        self.restart();
+        let less = less(attenuation)?;
+        (!clipped_at_bottom && highest_voltage < less.full_scale_voltage() * HEADROOM)
+            .then_some(less)
+    }
+}
+
+fn more(attenuation: Attenuation) -> Option<Attenuation> {
+    Attenuation::ALL.get(attenuation as usize + 1).copied()
+}
+
+fn less(attenuation: Attenuation) -> Option<Attenuation> {
+    (attenuation as usize)
+        .checked_sub(1)
+        .map(|index| Attenuation::ALL[index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The code the ADC reads `volts` at its pin as.
+    fn code(volts: f64, attenuation: Attenuation) -> u16 {
+        (volts / attenuation.full_scale_voltage() * 4095f64).min(4095f64) as u16
+    }
+
+    /// Feeds a constant voltage every 10 microseconds for `us`, following the switches.
+    fn feed(
+        ranger: &mut AutoRanger,
+        volts: f64,
+        us: u64,
+        attenuation: &mut Attenuation,
+    ) -> Vec<(u64, Attenuation)> {
+        let mut switches = Vec::new();
+        for microsecond in (0..us).step_by(10) {
+            if let Some(switched) =
+                ranger.push(code(volts, *attenuation), microsecond, *attenuation)
+            {
+                *attenuation = switched;
+                switches.push((microsecond, switched));
+            }
+        }
+        switches
+    }
+
+    #[test]
+    fn clipping_switches_to_more_attenuation_right_away() {
+        let mut ranger = AutoRanger::new(1000);
+        let mut attenuation = Attenuation::Db0;
+        let switches = feed(&mut ranger, 2f64, 100, &mut attenuation);
+        assert_eq!(
+            switches,
+            [
+                (0, Attenuation::Db2_5),
+                (10, Attenuation::Db6),
+                (20, Attenuation::Db11)
+            ]
+        );
+    }
+
+    #[test]
+    fn small_signals_switch_to_less_attenuation_after_a_while() {
+        let mut ranger = AutoRanger::new(1000);
+        let mut attenuation = Attenuation::Db11;
+        let switches = feed(&mut ranger, 0.5, 5000, &mut attenuation);
+        assert_eq!(
+            switches,
+            [
+                (1000, Attenuation::Db6),
+                (2010, Attenuation::Db2_5),
+                (3020, Attenuation::Db0)
+            ]
+        );
+    }
+
+    #[test]
+    fn signals_near_the_top_stay_where_they_are() {
+        // 1.1 V is too much for 80% of 1.25 V, yet does not clip at 2.5 dB either.
+        for start in [Attenuation::Db6, Attenuation::Db2_5] {
+            let mut ranger = AutoRanger::new(1000);
+            let mut attenuation = start;
+            assert!(feed(&mut ranger, 1.1, 10_000, &mut attenuation).is_empty());
+        }
+    }
+
+    #[test]
+    fn clipping_at_the_bottom_does_not_switch_down() {
+        let mut ranger = AutoRanger::new(1000);
+        let mut attenuation = Attenuation::Db11;
+        assert!(feed(&mut ranger, 0f64, 10_000, &mut attenuation).is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+//! Thinning out points more while a client falls behind, instead of losing them.
+//!
+//! The measuring core watches how full the buffer of the slowest client is. Past half full, it
+//! goes a level coarser, where the decimator keeps about half as many points, up to
+//! `MAX_LEVEL`. Once the buffer is nearly empty again, it goes back a level at a time. Levels
+//! only change every so often, so the decimator's new points have time to show in the buffer.
+
+/// The coarsest level, which decimates with 2^`MAX_LEVEL` times the tolerances.
+pub const MAX_LEVEL: u8 = 4;
+/// Going coarser from this fill of the buffer on.
+const HIGH_WATER: f64 = 0.5;
+/// Going finer again from this fill of the buffer down.
+const LOW_WATER: f64 = 0.125;
+/// Shortest time between two changes of the level.
+const STEP_US: u64 = 100_000;
+
+/// Picks how coarse to decimate for how far behind the slowest client is.
+pub struct Backpressure {
+    level: u8,
+    changed_at_us: Option<u64>,
+}
+
+impl Backpressure {
+    pub fn new() -> Backpressure {
+        Backpressure {
+            level: 0,
+            changed_at_us: None,
+        }
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// Takes how many of the buffer's `capacity` entries are unread at `microsecond`, and
+    /// returns the new level when it changes.
+    pub fn push(&mut self, unread: usize, capacity: usize, microsecond: u64) -> Option<u8> {
+        if let Some(changed_at_us) = self.changed_at_us {
+            if microsecond.saturating_sub(changed_at_us) < STEP_US {
+                return None;
+            }
+        }
+
+        let fill = unread as f64 / capacity as f64;
+        let level = if fill >= HIGH_WATER {
+            (self.level + 1).min(MAX_LEVEL)
+        } else if fill <= LOW_WATER {
+            self.level.saturating_sub(1)
+        } else {
+            self.level
+        };
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        self.changed_at_us = Some(microsecond);
+        Some(level)
+    }
+}
+
+impl Default for Backpressure {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Feeds the same fill every millisecond for `us`, collecting the changes.
+    fn feed(backpressure: &mut Backpressure, unread: usize, from_us: u64, us: u64) -> Vec<u8> {
+        (from_us..from_us + us)
+            .step_by(1000)
+            .filter_map(|microsecond| backpressure.push(unread, 100, microsecond))
+            .collect()
+    }
+
+    #[test]
+    fn goes_coarser_a_level_at_a_time_while_the_buffer_fills() {
+        let mut backpressure = Backpressure::new();
+        assert!(feed(&mut backpressure, 30, 0, 1_000_000).is_empty());
+        assert_eq!(feed(&mut backpressure, 60, 1_000_000, 250_000), [1, 2, 3]);
+        assert_eq!(feed(&mut backpressure, 90, 1_250_000, 1_000_000), [4]);
+        assert_eq!(backpressure.level(), MAX_LEVEL);
+    }
+
+    #[test]
+    fn relaxes_once_the_client_caught_up() {
+        let mut backpressure = Backpressure::new();
+        feed(&mut backpressure, 90, 0, 1_000_000);
+        // In between the two marks, the level stays where it is.
+        assert!(feed(&mut backpressure, 30, 1_000_000, 1_000_000).is_empty());
+        assert_eq!(
+            feed(&mut backpressure, 5, 2_000_000, 1_000_000),
+            [3, 2, 1, 0]
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Continuous sampling, with the ADC's digital controller writing every conversion to memory
+//! through DMA.
+//!
+//! esp-hal only drives the ADC one conversion at a time, so this sets up the digital controller
+//! and a DMA channel through their registers, the same way ESP-IDF's continuous mode driver does.
+//! The controller's own timer paces the conversions, and the DMA channel fills two blocks in
+//! turn, so one block can be read while the other one fills. The DMA never writes to a block that
+//! is still being read, it stops instead, and the transfer starts over once it is read.
+
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{fence, Ordering},
+};
+use esp_hal::{
+    analog::adc::{Adc, Attenuation},
+    dma::{Dma, DmaDescriptor, Owner},
+    peripherals::{ADC1, APB_SARADC, DMA, SENS},
+    time::now,
+};
+use just_a_scope::{
+    control::SampleRate,
+    sampling::{DmaSample, SampleClock},
+};
+
+/// The controller's timer counts the 80 MHz APB clock divided by 16, two counts per step.
+pub const CLOCK_HZ: u32 = 80_000_000 / 16 / 2;
+/// The timer only has 12 bits.
+pub const MIN_SAMPLE_RATE_HZ: u32 = 611;
+/// The fastest the SAR ADC converts.
+pub const MAX_SAMPLE_RATE_HZ: u32 = 83_333;
+
+const SAMPLES_PER_BLOCK: usize = 256;
+const BLOCK_SIZE: usize = SAMPLES_PER_BLOCK * DmaSample::SIZE;
+/// The DMA peripheral selection of the ADC.
+const DMA_PERIPHERAL_ADC: u8 = 8;
+/// Nothing else on the scope uses DMA, so the ADC gets the first channel.
+const DMA_CHANNEL: usize = 0;
+/// The controller's clock source selection of the APB clock.
+const CLOCK_SOURCE_APB: u8 = 2;
+
+#[repr(C, align(4))]
+struct Blocks([[u8; BLOCK_SIZE]; 2]);
+
+// Only ever handed to the DMA channel by `DmaSampler::new`, which takes the one DMA peripheral.
+static mut BLOCKS: Blocks = Blocks([[0; BLOCK_SIZE]; 2]);
+static mut DESCRIPTORS: [DmaDescriptor; 2] = [DmaDescriptor::EMPTY; 2];
+
+/// Samples that came in together, see `DmaSampler::read_block`.
+pub struct Block<'b> {
+    /// Conversions are counted for all channels together.
+    pub first_index: u64,
+    /// Turns the indices of the conversions into their timestamps.
+    pub clock: SampleClock,
+    pub data: &'b [u8],
+}
+
+/// Samples ADC1 channels continuously at a fixed rate, taking turns.
+pub struct DmaSampler<'a, 'd> {
+    _adc: &'a mut Adc<'d, ADC1>,
+    _dma: Dma<'static>,
+    adc_channels: heapless::Vec<u8, 4>,
+    rate: SampleRate,
+    clock: SampleClock,
+    next_index: u64,
+    /// The block handed out by the last `read_block`, which the DMA can't fill until it returns.
+    held: Option<usize>,
+    /// The DMA found both blocks taken and stopped, conversions are going nowhere.
+    stalled: bool,
+}
+
+impl<'a, 'd> DmaSampler<'a, 'd> {
+    /// Takes over ADC1 from the oneshot driver, which has already powered it and configured the
+    /// pins, and starts converting `adc_channels` in turn, each with its attenuation and at the
+    /// closest rate to `rate` it can.
+    pub fn new(
+        adc: &'a mut Adc<'d, ADC1>,
+        dma: DMA,
+        adc_channels: &[u8],
+        attenuations: &[Attenuation],
+        rate: SampleRate,
+    ) -> DmaSampler<'a, 'd> {
+        let dma = Dma::new(dma);
+
+        // Link the two blocks into a ring. The controller ends a block every SAMPLES_PER_BLOCK
+        // conversions, then the DMA moves on to the other one.
+        let descriptors = unsafe { &mut *addr_of_mut!(DESCRIPTORS) };
+        let blocks = unsafe { &mut *addr_of_mut!(BLOCKS) };
+        let first: *mut DmaDescriptor = &mut descriptors[0];
+        let second: *mut DmaDescriptor = &mut descriptors[1];
+        for (number, (descriptor, block)) in descriptors.iter_mut().zip(&mut blocks.0).enumerate() {
+            descriptor.buffer = block.as_mut_ptr();
+            descriptor.set_size(BLOCK_SIZE);
+            descriptor.set_length(0);
+            descriptor.set_suc_eof(false);
+            descriptor.set_owner(Owner::Dma);
+            descriptor.next = if number == 0 { second } else { first };
+        }
+
+        // Hand ADC1 from the RTC controller the oneshot driver uses to the digital controller.
+        let sens = unsafe { &*SENS::PTR };
+        sens.sar_meas1_mux()
+            .modify(|_, w| w.sar1_dig_force().set_bit());
+        sens.sar_meas1_ctrl2().modify(|_, w| {
+            w.meas1_start_force().set_bit();
+            w.sar1_en_pad_force().set_bit()
+        });
+        sens.sar_power_xpd_sar()
+            .modify(|_, w| unsafe { w.force_xpd_sar().bits(0b11) });
+
+        let saradc = unsafe { &*APB_SARADC::PTR };
+        saradc.apb_adc_clkm_conf().modify(|_, w| unsafe {
+            w.clkm_div_num().bits(15);
+            w.clkm_div_b().bits(1);
+            w.clkm_div_a().bits(0);
+            w.clk_sel().bits(CLOCK_SOURCE_APB);
+            w.clk_en().set_bit()
+        });
+        assert!(
+            (1..=4).contains(&adc_channels.len()),
+            "Can only sample one to four channels."
+        );
+        saradc.ctrl().modify(|_, w| unsafe {
+            w.sar_clk_gated().set_bit();
+            w.sar_clk_div().bits(1);
+            w.work_mode().bits(0);
+            w.sar_sel().clear_bit()
+        });
+        saradc.dma_conf().modify(|_, w| unsafe {
+            w.apb_adc_eof_num().bits(SAMPLES_PER_BLOCK as u16);
+            w.apb_adc_trans().set_bit()
+        });
+        saradc.ctrl2().modify(|_, w| {
+            w.meas_num_limit().clear_bit();
+            w.sar1_inv().clear_bit()
+        });
+
+        let mut sampler = DmaSampler {
+            _adc: adc,
+            _dma: dma,
+            adc_channels: adc_channels.iter().copied().collect(),
+            rate,
+            clock: SampleClock::for_rate(0, MAX_SAMPLE_RATE_HZ, CLOCK_HZ),
+            next_index: 0,
+            held: None,
+            stalled: false,
+        };
+        sampler.write_pattern(attenuations);
+        sampler.set_rate(rate);
+        sampler
+    }
+
+    /// Starts over with other attenuations, one for every channel. Indices start over at 0.
+    pub fn set_attenuations(&mut self, attenuations: &[Attenuation]) {
+        self.stop();
+        self.write_pattern(attenuations);
+        self.set_rate(self.rate);
+    }
+
+    /// Stops converting, until the rate or the attenuations are set again.
+    pub fn stop(&mut self) {
+        let saradc = unsafe { &*APB_SARADC::PTR };
+        saradc.ctrl2().modify(|_, w| w.timer_en().clear_bit());
+    }
+
+    /// The controller goes through a pattern of up to four entries per register, the first in the
+    /// highest bits. Every entry has the channel in its upper 4 bits, the attenuation below.
+    fn write_pattern(&self, attenuations: &[Attenuation]) {
+        assert_eq!(attenuations.len(), self.adc_channels.len());
+        let saradc = unsafe { &*APB_SARADC::PTR };
+        let pattern = self.adc_channels.iter().zip(attenuations).enumerate().fold(
+            0u32,
+            |pattern, (entry, (&channel, &attenuation))| {
+                let entry_bits = (channel as u32) << 2 | attenuation as u32;
+                pattern | entry_bits << (18 - 6 * entry)
+            },
+        );
+        saradc
+            .sar1_patt_tab1()
+            .write(|w| unsafe { w.sar1_patt_tab1().bits(pattern) });
+        saradc.ctrl().modify(|_, w| unsafe {
+            w.sar1_patt_len().bits(self.adc_channels.len() as u8 - 1);
+            w.sar1_patt_p_clear().set_bit()
+        });
+        saradc
+            .ctrl()
+            .modify(|_, w| w.sar1_patt_p_clear().clear_bit());
+    }
+
+    /// Starts over at the closest rate to `rate` per channel the controller can do. Indices start
+    /// over at 0.
+    pub fn set_rate(&mut self, rate: SampleRate) {
+        self.stop();
+        self.rate = rate;
+        self.start_transfer();
+
+        // Timestamps count from the first conversion on. The channels share the conversions.
+        let rate_hz = rate
+            .hz()
+            .map_or(MAX_SAMPLE_RATE_HZ, |hz| {
+                hz.saturating_mul(self.adc_channels.len() as u32)
+            })
+            .clamp(MIN_SAMPLE_RATE_HZ, MAX_SAMPLE_RATE_HZ);
+        self.clock = SampleClock::for_rate(now().ticks(), rate_hz, CLOCK_HZ);
+        self.next_index = 0;
+        let saradc = unsafe { &*APB_SARADC::PTR };
+        saradc.ctrl2().modify(|_, w| unsafe {
+            w.timer_target().bits(self.clock.ticks_per_sample() as u16);
+            w.timer_en().set_bit()
+        });
+    }
+
+    /// Gives both blocks to the DMA and has it start filling the first one again, with whatever
+    /// the controller converts from now on.
+    fn start_transfer(&mut self) {
+        let saradc = unsafe { &*APB_SARADC::PTR };
+        let descriptors = unsafe { &mut *addr_of_mut!(DESCRIPTORS) };
+        for descriptor in descriptors.iter_mut() {
+            descriptor.set_owner(Owner::Dma);
+        }
+        let channel = unsafe { &*DMA::PTR }.ch(DMA_CHANNEL);
+        channel.in_conf0().modify(|_, w| w.in_rst().set_bit());
+        channel.in_conf0().modify(|_, w| w.in_rst().clear_bit());
+        // Rather than overwrite a block that is still being read, stop at it.
+        channel
+            .in_conf1()
+            .modify(|_, w| w.in_check_owner().set_bit());
+        channel
+            .in_peri_sel()
+            .modify(|_, w| unsafe { w.peri_in_sel().bits(DMA_PERIPHERAL_ADC) });
+        channel.in_link().modify(|_, w| unsafe {
+            w.inlink_addr()
+                .bits(addr_of!(descriptors[0]) as u32 & 0xfffff)
+        });
+        channel.in_int().clr().write(|w| {
+            w.in_suc_eof().clear_bit_by_one();
+            w.in_dscr_empty().clear_bit_by_one()
+        });
+        channel.in_link().modify(|_, w| w.inlink_start().set_bit());
+        saradc
+            .dma_conf()
+            .modify(|_, w| w.apb_adc_reset_fsm().set_bit());
+        saradc
+            .dma_conf()
+            .modify(|_, w| w.apb_adc_reset_fsm().clear_bit());
+        self.held = None;
+        self.stalled = false;
+    }
+
+    /// Waits for the next block to fill, and keeps it from the DMA until the next call.
+    ///
+    /// When the DMA found the other block still taken, it stopped and the conversions since were
+    /// lost. The block it filled before that still comes first, then the transfer starts over and
+    /// the indices skip the conversions the controller's timer made in between.
+    pub fn read_block(&mut self) -> Block<'_> {
+        let channel = unsafe { &*DMA::PTR }.ch(DMA_CHANNEL);
+        // The last block has been decoded by now, so the DMA can have it back.
+        if let Some(held) = self.held.take() {
+            fence(Ordering::Release);
+            unsafe { (*addr_of_mut!(DESCRIPTORS))[held].set_owner(Owner::Dma) };
+        }
+        let raw = channel.in_int().raw().read();
+        if self.stalled || raw.in_dscr_empty().bit_is_set() && raw.in_suc_eof().bit_is_clear() {
+            // The timer kept running, so the conversions still line up with the clock.
+            let restarted_at = now().ticks();
+            self.start_transfer();
+            self.next_index = (self.clock.index_at(restarted_at) + 1).max(self.next_index);
+        } else if raw.in_dscr_empty().bit_is_set() {
+            self.stalled = true;
+        }
+
+        while channel.in_int().raw().read().in_suc_eof().bit_is_clear() {}
+        channel
+            .in_int()
+            .clr()
+            .write(|w| w.in_suc_eof().clear_bit_by_one());
+        let finished_descriptor = channel.in_suc_eof_des_addr().read().bits();
+        fence(Ordering::Acquire);
+
+        let finished = if finished_descriptor == unsafe { addr_of!(DESCRIPTORS[1]) } as u32 {
+            1
+        } else {
+            0
+        };
+        self.held = Some(finished);
+
+        let first_index = self.next_index;
+        self.next_index += SAMPLES_PER_BLOCK as u64;
+        let blocks = unsafe { &*addr_of!(BLOCKS) };
+        Block {
+            first_index,
+            clock: self.clock,
+            data: &blocks.0[finished],
+        }
+    }
+}
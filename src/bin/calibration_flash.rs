@@ -0,0 +1,214 @@
+//! Keeps what the probes were calibrated with on the scope in flash, so it survives a restart.
+//!
+//! Erasing and writing the flash turns its cache off for both cores, so nothing may run from
+//! flash on the other core meanwhile. The measuring core hands the records over to a task on
+//! the first core with [`FlashRequests`], and waits in RAM until they are written.
+
+use core::{
+    cell::UnsafeCell,
+    future::poll_fn,
+    sync::atomic::{AtomicU8, Ordering},
+    task::Poll,
+};
+use embedded_storage::{ReadStorage, Storage};
+use esp_hal::{macros::ram, xtensa_lx};
+use esp_println::println;
+use esp_storage::{FlashStorage, FlashStorageError};
+use just_a_scope::{
+    calibration::{
+        decode_fits, decode_zero_offsets, encode_fits, encode_zero_offsets, FittedCurve,
+        ZeroOffset, FITS_LENGTH, ZERO_OFFSETS_LENGTH,
+    },
+    wakeup::Wakeup,
+    websocket_logistics::CHANNELS,
+};
+
+/// Where the bootloader expects the partition table, see partitions.csv.
+const PARTITION_TABLE_ADDRESS: u32 = 0x8000;
+/// Every entry is a magic number, the type and subtype, the offset and size, a label of up to 16
+/// bytes and flags.
+const PARTITION_ENTRY_LENGTH: usize = 32;
+const PARTITION_ENTRY_MAGIC: [u8; 2] = [0xaa, 0x50];
+const MAX_PARTITIONS: u32 = 95;
+const CALIBRATION_LABEL: &[u8] = b"calibration";
+/// Every record gets a sector of its own.
+const SECTOR_SIZE: u32 = 0x1000;
+const ZERO_OFFSETS_SECTOR: u32 = 0;
+const FITS_SECTOR: u32 = 1;
+
+/// Nothing to write.
+const IDLE: u8 = 0;
+/// The measuring core left records to write, and is on its way to wait in RAM.
+const REQUESTED: u8 = 1;
+/// The measuring core waits in RAM, so the flash can be written.
+const PARKED: u8 = 2;
+
+pub struct CalibrationFlash {
+    flash: FlashStorage,
+    /// Where the calibration partition starts.
+    offset: u32,
+}
+
+impl CalibrationFlash {
+    /// Looks the calibration partition up in the partition table. Panics when it is missing or
+    /// too small for the records, since the records would end up in some other partition.
+    pub fn new() -> CalibrationFlash {
+        let mut flash = FlashStorage::new();
+        let (offset, size) = find_partition(&mut flash, CALIBRATION_LABEL)
+            .expect("The partition table has no calibration partition.");
+        assert!(
+            offset % SECTOR_SIZE == 0 && size >= (FITS_SECTOR + 1) * SECTOR_SIZE,
+            "The calibration partition has to start on a sector and hold two of them."
+        );
+        CalibrationFlash { flash, offset }
+    }
+
+    fn address(&self, sector: u32) -> u32 {
+        self.offset + sector * SECTOR_SIZE
+    }
+
+    /// The stored zero offsets, none at all when nothing was stored yet or the record is damaged.
+    pub fn load_zero_offsets(&mut self) -> [Option<ZeroOffset>; CHANNELS] {
+        let mut record = [0u8; ZERO_OFFSETS_LENGTH];
+        let address = self.address(ZERO_OFFSETS_SECTOR);
+        match self.flash.read(address, &mut record) {
+            Ok(()) => decode_zero_offsets(&record).unwrap_or([None; CHANNELS]),
+            Err(_) => [None; CHANNELS],
+        }
+    }
+
+    /// The stored curves, none at all when nothing was stored yet or the record is damaged.
+    pub fn load_fits(&mut self) -> [Option<FittedCurve>; CHANNELS] {
+        let mut record = [0u8; FITS_LENGTH];
+        let address = self.address(FITS_SECTOR);
+        match self.flash.read(address, &mut record) {
+            Ok(()) => decode_fits(&record).unwrap_or([None; CHANNELS]),
+            Err(_) => [None; CHANNELS],
+        }
+    }
+
+    /// Replaces the stored zero offsets. Takes a few milliseconds for erasing the sector.
+    pub fn save_zero_offsets(
+        &mut self,
+        offsets: &[Option<ZeroOffset>; CHANNELS],
+    ) -> Result<(), FlashStorageError> {
+        let address = self.address(ZERO_OFFSETS_SECTOR);
+        self.flash.write(address, &encode_zero_offsets(offsets))
+    }
+
+    /// Replaces the stored curves, like `save_zero_offsets`.
+    pub fn save_fits(
+        &mut self,
+        fits: &[Option<FittedCurve>; CHANNELS],
+    ) -> Result<(), FlashStorageError> {
+        let address = self.address(FITS_SECTOR);
+        self.flash.write(address, &encode_fits(fits))
+    }
+}
+
+/// The offset and size of the partition with the given label, if the partition table has one.
+fn find_partition(flash: &mut FlashStorage, label: &[u8]) -> Option<(u32, u32)> {
+    let mut entry = [0u8; PARTITION_ENTRY_LENGTH];
+    for number in 0..MAX_PARTITIONS {
+        let address = PARTITION_TABLE_ADDRESS + number * PARTITION_ENTRY_LENGTH as u32;
+        flash.read(address, &mut entry).ok()?;
+        // The table ends at the first entry without the magic number.
+        if entry[..2] != PARTITION_ENTRY_MAGIC {
+            return None;
+        }
+        let entry_label = &entry[12..28];
+        let label_length = entry_label.iter().position(|&byte| byte == 0).unwrap_or(16);
+        if &entry_label[..label_length] == label {
+            let word = |at: usize| u32::from_le_bytes(entry[at..at + 4].try_into().unwrap());
+            return Some((word(4), word(8)));
+        }
+    }
+    None
+}
+
+/// Records the measuring core wants stored, for the task that writes them on the first core.
+pub struct FlashRequests {
+    state: AtomicU8,
+    zero_offsets: UnsafeCell<Option<[Option<ZeroOffset>; CHANNELS]>>,
+    fits: UnsafeCell<Option<[Option<FittedCurve>; CHANNELS]>>,
+    wakeup: Wakeup,
+}
+
+// The records are only touched by one core at a time, as `state` says.
+unsafe impl Sync for FlashRequests {}
+
+impl FlashRequests {
+    pub fn new() -> FlashRequests {
+        FlashRequests {
+            state: AtomicU8::new(IDLE),
+            zero_offsets: UnsafeCell::new(None),
+            fits: UnsafeCell::new(None),
+            wakeup: Wakeup::new(),
+        }
+    }
+
+    /// Has the records that are not `None` written, and returns once they are. Only the
+    /// measuring core calls it. Stalls the sampling while the sectors are erased.
+    pub fn store(
+        &self,
+        zero_offsets: Option<[Option<ZeroOffset>; CHANNELS]>,
+        fits: Option<[Option<FittedCurve>; CHANNELS]>,
+    ) {
+        // The writer is done with the records until the state goes back from idle.
+        unsafe {
+            *self.zero_offsets.get() = zero_offsets;
+            *self.fits.get() = fits;
+        }
+        self.state.store(REQUESTED, Ordering::Release);
+        self.wakeup.wake();
+        park_until_written(&self.state);
+    }
+
+    /// Waits for records from the measuring core and writes them, forever.
+    pub async fn serve(&self, flash: &mut CalibrationFlash) -> ! {
+        loop {
+            poll_fn(|context| {
+                self.wakeup.register(context.waker());
+                match self.state.load(Ordering::Acquire) {
+                    IDLE => Poll::Pending,
+                    _ => Poll::Ready(()),
+                }
+            })
+            .await;
+            // The measuring core gets there right away, it only has to call into RAM.
+            while self.state.load(Ordering::Acquire) != PARKED {
+                core::hint::spin_loop();
+            }
+
+            let (zero_offsets, fits) =
+                unsafe { ((*self.zero_offsets.get()).take(), (*self.fits.get()).take()) };
+            if let Some(offsets) = zero_offsets {
+                if let Err(e) = flash.save_zero_offsets(&offsets) {
+                    println!("Could not store the zero offsets: {e:?}");
+                }
+            }
+            if let Some(fits) = fits {
+                if let Err(e) = flash.save_fits(&fits) {
+                    println!("Could not store the fitted curves: {e:?}");
+                }
+            }
+            self.state.store(IDLE, Ordering::Release);
+        }
+    }
+}
+
+/// Tells the first core the flash is free to write, and spins until it is written. Runs from
+/// RAM with interrupts off, since the flash cache is off meanwhile and a handler could run from
+/// flash.
+///
+/// Only interrupts on this core are turned off. `critical_section::with` would also take the lock
+/// esp-storage takes on the first core to write, and neither core would get anywhere.
+#[ram]
+fn park_until_written(state: &AtomicU8) {
+    let enabled = xtensa_lx::interrupt::disable();
+    state.store(PARKED, Ordering::Release);
+    while state.load(Ordering::Acquire) != IDLE {
+        core::hint::spin_loop();
+    }
+    unsafe { xtensa_lx::interrupt::set_mask(enabled) };
+}
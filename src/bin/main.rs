@@ -5,9 +5,10 @@ extern crate alloc;
 
 use alloc::boxed::Box;
 use core::{
-    fmt::Debug,
+    alloc::Layout,
+    fmt::{Debug, Write as _},
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    ptr::addr_of_mut,
+    ptr::{addr_of, addr_of_mut},
 };
 use edge_http::{
     io::{
@@ -16,16 +17,17 @@ use edge_http::{
     },
     Method,
 };
-use edge_nal::{TcpAccept, TcpBind};
+use edge_nal::{TcpAccept, TcpBind, TcpSplit};
 use edge_nal_embassy::{TcpBuffers, TcpError};
+use embassy_futures::select::{select, Either};
 use embassy_net::{Config, Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::{Read, Write};
 use embedded_websocket::{self as ws};
 use esp_backtrace as _;
 use esp_hal::{
     cpu_control::CpuControl,
-    gpio::{Level, Output},
+    gpio::{Input, Level, Output, Pull},
     peripherals::Peripherals,
     prelude::*,
     rng::Rng,
@@ -38,18 +40,46 @@ use esp_wifi::{
     wifi::{AccessPointConfiguration, Configuration},
 };
 use heapless::String;
-use websocket_logistics::{send_message, CyclicBuffer, OscilliscopePoint};
+use just_a_scope::{
+    adc::{AdcSettings, Attenuation, CalibrationScheme},
+    calibrator::{CalibrationStep, FrontEnd, ProbeCalibrator, SharedCalibrations},
+    control::{
+        AcquisitionControl, AcquisitionMode, AcquisitionState, Command, SampleRate, SamplingReport,
+    },
+    decimation::{DecimationMode, Thresholds},
+    filter::FilterSettings,
+    handshake::{self, HandshakeError},
+    history::History,
+    protocol::{StreamEncoder, MIN_FRAME_LENGTH},
+    trigger::{Slope, TriggerSettings},
+    websocket_logistics::{
+        receive_message, send_points, send_text, BroadcastBuffer, ClientMessage, CyclicReader,
+        DropCounts, OscilliscopePoint, OverflowPolicy, ReceiveError, CHANNELS,
+    },
+};
 
+mod adc_dma;
+mod calibration_flash;
 mod measure;
-mod websocket_logistics;
+
+use calibration_flash::{CalibrationFlash, FlashRequests};
+use measure::SamplingBackend;
 
 const POINTS_BUFFER_SIZE: usize = 128;
+const WEBSOCKET_CLIENTS_PER_INTERFACE: usize = 2;
+const MAX_WEBSOCKET_CLIENTS: usize = 2 * WEBSOCKET_CLIENTS_PER_INTERFACE;
 const SOCKETS_PER_STACK: usize = 16;
-const TCP_SOCKETS_PER_WEBSOCKET: usize = 8;
+const TCP_SOCKETS_PER_WEBSOCKET: usize = 2;
 const WEBSOCKET_SOCKET_BUFFERS_SIZE: usize = 500; // Probably too small...
 const TCP_SOCKETS_PER_HTTP_SERVER: usize = 8;
 const HTTP_SOCKET_BUFFERS_SIZE: usize = 500;
 const CONNECTION_TIMEOUT_MS: u32 = 30_000;
+const STATUS_INTERVAL: Duration = Duration::from_millis(500);
+/// How many points are copied out of the history or a point buffer before they are sent.
+const POINTS_PER_READ: usize = 32;
+// Messages this long fill the TCP buffer, along with their 4 bytes of WebSocket header.
+const MAX_FRAME_SIZE: usize = WEBSOCKET_SOCKET_BUFFERS_SIZE - 4;
+const COMMAND_BUFFER_SIZE: usize = 64;
 const WEBSOCKET_PORT: u16 = 43822;
 const HTTP_SERVER_PORT: u16 = 80;
 const AP_GATEWAY_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
@@ -59,11 +89,25 @@ const STA_STATIC_IP_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 83);
 const STA_WEBSOCKET_ENDPOINT: SocketAddrV4 =
     SocketAddrV4::new(STA_STATIC_IP_ADDRESS, WEBSOCKET_PORT);
 
-static mut APP_CORE_STACK: esp_hal::cpu_control::Stack<640> =
-    esp_hal::cpu_control::Stack::<640>::new();
+/// The measuring task keeps every channel's state on the app core's stack, about 4 KB for both
+/// channels with the filter chains taking most of it, and calls down from there. The status
+/// printed on this core says how much of it was ever used.
+const APP_CORE_STACK_SIZE: usize = 16 * 1024;
+/// What the app core's stack is filled with before it starts, so the words still holding it
+/// were never used.
+const STACK_PAINT: u32 = 0x5ca1_ab1e;
+
+static mut APP_CORE_STACK: esp_hal::cpu_control::Stack<APP_CORE_STACK_SIZE> =
+    esp_hal::cpu_control::Stack::<APP_CORE_STACK_SIZE>::new();
 
 static mut CONNECTED_TO_AP: bool = false;
 
+// Commands from the clients for the measuring core, kept in internal RAM.
+static ACQUISITION_CONTROL: AcquisitionControl = AcquisitionControl::new();
+
+// The calibrations the measuring core goes by, for the clients.
+static CALIBRATIONS: SharedCalibrations = SharedCalibrations::new();
+
 // Get it?
 #[embassy_executor::task]
 async fn access_point_marathon(mut runner: Runner<'static, WifiDevice<'static, WifiApDevice>>) {
@@ -137,10 +181,19 @@ async fn http_server(stack: Stack<'static>, ip: Ipv4Addr) {
         .expect("Actually running the http server failed.");
 }
 
-#[embassy_executor::task(pool_size = 2)]
+#[embassy_executor::task(pool_size = 4)] // MAX_WEBSOCKET_CLIENTS
 async fn web_socket_server(
     stack: Stack<'static>,
-    point_buffer: &'static CyclicBuffer<POINTS_BUFFER_SIZE, OscilliscopePoint>,
+    point_buffer: &'static BroadcastBuffer<
+        MAX_WEBSOCKET_CLIENTS,
+        POINTS_BUFFER_SIZE,
+        OscilliscopePoint,
+    >,
+    history: &'static History<OscilliscopePoint>,
+    trigger_settings: Option<TriggerSettings>,
+    sampling_backend: SamplingBackend,
+    wakeup_points: usize,
+    wakeup_latency: Duration,
     address_and_port: SocketAddr,
 ) {
     'single_web_socket: loop {
@@ -169,7 +222,7 @@ async fn web_socket_server(
         let mut header_buffer = [0u8; WEBSOCKET_SOCKET_BUFFERS_SIZE];
         let mut bytes_read: usize = 0;
         loop {
-            match web_socket.read(&mut header_buffer).await {
+            match web_socket.read(&mut header_buffer[bytes_read..]).await {
                 Ok(0) => break,
                 Ok(s) => bytes_read += s,
                 Err(e) => {
@@ -180,7 +233,7 @@ async fn web_socket_server(
                     continue 'single_web_socket;
                 }
             }
-            if &header_buffer[bytes_read - 4..bytes_read] == b"\r\n\r\n" {
+            if handshake::is_complete(&header_buffer[..bytes_read]) {
                 // Data is definitely a http message at least.
                 break;
             } else {
@@ -189,68 +242,517 @@ async fn web_socket_server(
         }
 
         // Definitely received a http message at this point. Extract WebSocket handshake data from the headers.
-        let mut headers = [httparse::EMPTY_HEADER; 16];
-        let mut request = httparse::Request::new(&mut headers);
-        request.parse(&header_buffer).unwrap();
-        let headers = request.headers.iter().map(|f| (f.name, f.value));
-        let ws_context = ws::read_http_header(headers).unwrap().unwrap();
+        // Pages that know about the compact wire format ask for it as a subprotocol.
+        let request = match handshake::parse(&header_buffer[..bytes_read]) {
+            Ok(request) => request,
+            Err(HandshakeError::NotAnUpgrade) => {
+                println!("Received a http request that was not a WebSocket handshake.");
+                continue 'single_web_socket;
+            }
+            Err(e) => {
+                println!(
+                    "Received an unusable WebSocket handshake request. Error: {:?}",
+                    e
+                );
+                continue 'single_web_socket;
+            }
+        };
+        let wire_format = request.format;
+        // Clients can ask for shorter messages, but not for ones that don't fit a point.
+        let frame_size = request.max_frame.map_or(MAX_FRAME_SIZE, |length| {
+            length.clamp(MIN_FRAME_LENGTH, MAX_FRAME_SIZE)
+        });
+        let mut key = ws::WebSocketKey::new();
+        key.push_str(&request.key).unwrap();
+        let accepted_protocol = request.subprotocol.as_ref().map(|name| {
+            let mut protocol = ws::WebSocketSubProtocol::new();
+            protocol.push_str(name).unwrap();
+            protocol
+        });
 
         // At this point we have a definite handshake request. Send a handshake approval response.
         let mut ws_server = ws::WebSocketServer::new_server();
         let mut handshake_approval = [0u8; WEBSOCKET_SOCKET_BUFFERS_SIZE];
         let len = ws_server
-            .server_accept(
-                &ws_context.sec_websocket_key,
-                ws_context.sec_websocket_protocol_list.first(),
-                &mut handshake_approval,
-            )
+            .server_accept(&key, accepted_protocol.as_ref(), &mut handshake_approval)
             .expect("Creating WebSocket handshake response failed.");
-        web_socket
-            .write_all(&handshake_approval[..len])
-            .await
-            .expect("Could not write the WebSocket handshake response to the socket.");
+        if let Err(e) = web_socket.write_all(&handshake_approval[..len]).await {
+            println!(
+                "Could not write the WebSocket handshake response to {}. Error: {:?}",
+                endpoint, e
+            );
+            continue 'single_web_socket;
+        }
 
         println!(
-            "WebSocket connection should be successfully opened on {}. Taking ownership of buffer reader...",
-            endpoint
+            "WebSocket connection should be successfully opened on {} ({:?} format, messages of up to {} bytes). Subscribing to the point buffer...",
+            endpoint, wire_format, frame_size
         );
 
-        let reader = match point_buffer.take_reader() {
+        let mut reader = match point_buffer.take_reader() {
             Some(r) => r,
             None => {
-                println!("Reader was already taken.");
+                println!("All {MAX_WEBSOCKET_CLIENTS} point buffer readers are already taken.");
                 continue 'single_web_socket;
             }
         };
+        let mut calibrations_generation = CALIBRATIONS.generation();
+        let mut encoder = StreamEncoder::new(wire_format, CALIBRATIONS.load_curves());
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let frame = &mut frame[..frame_size];
+        let mut reported_drops: Option<DropCounts> = None;
+        let mut last_status = Instant::now();
 
-        loop {
-            // Send data to the WebSocket.
-            let batches = &reader.get_batch_holder().batches;
-
-            for batch in batches {
-                for batch in batch.chunks(112) {
-                    assert!(
-                        batch.len() % 8 == 0,
-                        "Trying to send data with length not a multiple of 8"
-                    );
+        // The live points are already being buffered for this client, catch it up on the past first.
+        // It needs to know how to line up triggered frames before it gets any.
+        let caught_up = match send_text(&mut web_socket, &trigger_status(trigger_settings)).await {
+            Ok(_) => send_text(&mut web_socket, &calibration_status()).await,
+            Err(e) => Err(e),
+        };
+        let caught_up = match caught_up {
+            Ok(_) => send_history(&mut web_socket, &mut encoder, history, frame).await,
+            Err(e) => Err(e),
+        };
+        match caught_up {
+            Ok(_) => (),
+            Err(TcpError::General(embassy_net::tcp::Error::ConnectionReset)) => {
+                println!(
+                    "WebSocket connection to {} was closed while catching up.",
+                    endpoint
+                );
+                continue 'single_web_socket;
+            }
+            Err(e) => {
+                println!(
+                    "Sending to the WebSocket connection to {} failed while catching up. Error: {:?}",
+                    endpoint, e
+                );
+                continue 'single_web_socket;
+            }
+        }
 
-                    match send_message(&mut web_socket, batch).await {
-                        Ok(_) => (),
-                        Err(TcpError::General(embassy_net::tcp::Error::ConnectionReset)) => {
-                            println!("WebSocket connection was closed.");
-                            continue 'single_web_socket;
+        // Commands from the client come in on their own, while the points keep going out.
+        let (mut commands, mut web_socket) = web_socket.split();
+        let mut reported_acquisition: Option<AcquisitionReport> = None;
+        let mut last_acquisition_status = Instant::now();
+        let sending = async {
+            let mut points = [OscilliscopePoint {
+                voltage: 0f64,
+                microsecond: 0,
+                code: 0,
+                index: 0,
+                channel: 0,
+                trigger: false,
+                heartbeat: false,
+                adc: AdcSettings::DEFAULT,
+            }; POINTS_PER_READ];
+            loop {
+                // Wait for the measuring core to hand over enough points, the commands get
+                // through meanwhile. The statuses below go out at least every wakeup_latency.
+                reader
+                    .wait_for_batch(wakeup_points, Timer::after(wakeup_latency))
+                    .await;
+                // The points are copied out before they are sent, so the measuring core can
+                // overwrite the rest while the socket is busy. Only what is unread now goes out,
+                // a writer as fast as the socket must not hold off the statuses.
+                let mut unread = reader.lag();
+                let mut result = 'send: {
+                    while unread > 0 {
+                        let count = reader.read(&mut points[..unread.min(POINTS_PER_READ)]);
+                        if count == 0 {
+                            break;
+                        }
+                        unread -= count;
+                        if let Err(e) =
+                            send_points(&mut web_socket, &mut encoder, &points[..count], frame)
+                                .await
+                        {
+                            break 'send Err(e);
                         }
-                        Err(e) => panic!("Sending WebSocket message failed: {e:?}"),
                     }
+                    Ok(())
+                };
+
+                // New calibrations apply to the points still to come, and the client gets to see
+                // them right away.
+                let generation = CALIBRATIONS.generation();
+                if result.is_ok() && generation != calibrations_generation {
+                    for (channel, curves) in CALIBRATIONS.load_curves().into_iter().enumerate() {
+                        encoder.set_curves(channel as u8, curves);
+                    }
+                    result = send_text(&mut web_socket, &calibration_status()).await;
+                    calibrations_generation = generation;
+                }
+
+                // Tell the client about dropped points, but not more often than every STATUS_INTERVAL.
+                let drops = reader.drop_counts();
+                if result.is_ok()
+                    && reported_drops != Some(drops)
+                    && (reported_drops.is_none() || last_status.elapsed() >= STATUS_INTERVAL)
+                {
+                    result = send_text(&mut web_socket, &buffer_status(&reader)).await;
+                    reported_drops = Some(drops);
+                    last_status = Instant::now();
+                }
+
+                // Changes clients asked for are reported right away. The state changes with every
+                // trigger and the achieved rate every second, so those are reported no more often
+                // than every STATUS_INTERVAL.
+                let acquisition = (
+                    ACQUISITION_CONTROL.mode(),
+                    ACQUISITION_CONTROL.state(),
+                    ACQUISITION_CONTROL.sampling(),
+                );
+                let requested = |(mode, _, sampling): AcquisitionReport| {
+                    (
+                        mode,
+                        sampling.requested,
+                        sampling.adc,
+                        sampling.autorange,
+                        sampling.filter,
+                        sampling.decimation,
+                        sampling.thresholds,
+                    )
+                };
+                if result.is_ok()
+                    && reported_acquisition != Some(acquisition)
+                    && (reported_acquisition.map(requested) != Some(requested(acquisition))
+                        || last_acquisition_status.elapsed() >= STATUS_INTERVAL)
+                {
+                    result = send_text(
+                        &mut web_socket,
+                        &acquisition_status(sampling_backend, acquisition),
+                    )
+                    .await;
+                    reported_acquisition = Some(acquisition);
+                    last_acquisition_status = Instant::now();
+                }
+
+                if let Err(e) = result {
+                    break e;
+                }
+
+                // Flush the WebSocket. Leads to weird behavior if not done.
+                if let Err(e) = web_socket.flush().await {
+                    break e;
+                }
+            }
+        };
+
+        match select(receive_commands(&mut commands), sending).await {
+            Either::First(Ok(())) | Either::First(Err(ReceiveError::Closed)) => println!(
+                "WebSocket connection to {} was closed by the client. It missed {} points.",
+                endpoint,
+                reader.missed()
+            ),
+            Either::First(Err(ReceiveError::Io(e))) | Either::Second(e) => match e {
+                TcpError::General(embassy_net::tcp::Error::ConnectionReset) => println!(
+                    "WebSocket connection to {} was closed. It missed {} points.",
+                    endpoint,
+                    reader.missed()
+                ),
+                // Only this connection is lost, the other clients keep going.
+                e => println!(
+                    "WebSocket connection to {} failed. It missed {} points. Error: {:?}",
+                    endpoint,
+                    reader.missed(),
+                    e
+                ),
+            },
+            Either::First(Err(e)) => println!(
+                "Closing the WebSocket connection to {}, it sent an unusable message: {:?}",
+                endpoint, e
+            ),
+        }
+    }
+}
+
+/// Applies the commands a client sends, until it closes the connection.
+async fn receive_commands<R>(from: &mut R) -> Result<(), ReceiveError<R::Error>>
+where
+    R: Read,
+{
+    let mut buffer = [0u8; COMMAND_BUFFER_SIZE];
+    loop {
+        match receive_message(from, &mut buffer).await? {
+            ClientMessage::Text(length) => {
+                let text = core::str::from_utf8(&buffer[..length]).unwrap_or("");
+                match Command::parse(text) {
+                    Some(command) => ACQUISITION_CONTROL.apply(command),
+                    None => println!("Ignoring the unknown command {:?}.", text),
                 }
             }
+            ClientMessage::Close => return Ok(()),
+            _ => (),
+        }
+    }
+}
+
+/// Sends the last `history_seconds` worth of points, oldest first.
+///
+/// The live buffer is subscribed to first so nothing falls through the crack between the two.
+/// Points that show up in both are only sent once, since the encoder goes by their index.
+async fn send_history<W>(
+    to: &mut W,
+    encoder: &mut StreamEncoder,
+    history: &History<OscilliscopePoint>,
+    frame: &mut [u8],
+) -> Result<(), W::Error>
+where
+    W: Write,
+{
+    let history_seconds: f64 = env!("history_seconds").parse().unwrap();
+    let history_microseconds = (history_seconds * 1_000_000f64) as u64;
+    let end = history.end();
+    let mut position = history.start();
+    let mut points = [OscilliscopePoint {
+        voltage: 0f64,
+        microsecond: 0,
+        code: 0,
+        index: 0,
+        channel: 0,
+        trigger: false,
+        heartbeat: false,
+        adc: AdcSettings::DEFAULT,
+    }; POINTS_PER_READ];
+
+    if end == position {
+        return Ok(()); // Nothing recorded yet.
+    }
+    let cutoff = match history.read(end.wrapping_sub(1), &mut points[..1]) {
+        Some(1) => points[0].microsecond.saturating_sub(history_microseconds),
+        _ => return Ok(()), // Lapped by the writer already, there is nothing older to send.
+    };
+
+    while position != end {
+        let wanted = end.wrapping_sub(position).min(POINTS_PER_READ);
+        let count = match history.read(position, &mut points[..wanted]) {
+            Some(count) => count,
+            None => {
+                // Lapped by the writer, skip ahead to what is still there.
+                position = history.start();
+                if end.wrapping_sub(position) >= history.capacity() {
+                    break;
+                }
+                continue;
+            }
+        };
+        position = position.wrapping_add(count);
+
+        let recent = &points[..count];
+        let first_recent = recent
+            .iter()
+            .position(|point| point.microsecond >= cutoff)
+            .unwrap_or(count);
+        send_points(to, encoder, &recent[first_recent..], frame).await?;
+    }
 
-            // Flush the WebSocket. Leads to weird behavior if not done.
-            web_socket
-                .flush()
-                .await
-                .expect("Failed to flush WebSocket.");
+    to.flush().await
+}
+
+/// Describes how the client's point buffer is doing, as a JSON status message.
+fn buffer_status<const L: usize, T>(reader: &CyclicReader<'_, L, T>) -> String<192> {
+    let drops = reader.drop_counts();
+    let mut status = String::new();
+    write!(
+        status,
+        r#"{{"type":"buffer","overflow_policy":"{}","dropped_newest":{},"overwritten_oldest":{},"lag":{}}}"#,
+        reader.overflow_policy().name(),
+        drops.dropped_newest,
+        drops.overwritten_oldest,
+        reader.lag()
+    )
+    .expect("Buffer status did not fit in its string.");
+    status
+}
+
+/// Describes the trigger as a JSON status message, `"slope":"off"` when free running.
+fn trigger_status(settings: Option<TriggerSettings>) -> String<192> {
+    let mut status = String::new();
+    match settings {
+        Some(settings) => write!(
+            status,
+            r#"{{"type":"trigger","channel":{},"slope":"{}","level":{},"pre_trigger_percent":{},"frame_us":{},"holdoff_us":{}}}"#,
+            settings.channel,
+            settings.slope.name(),
+            settings.level_volts,
+            settings.pre_trigger_percent,
+            settings.frame_us,
+            settings.holdoff_us
+        ),
+        None => write!(status, r#"{{"type":"trigger","slope":"off"}}"#),
+    }
+    .expect("Trigger status did not fit in its string.");
+    status
+}
+
+/// Describes the calibrations of the channels as a JSON status message, with `"measuring":null`
+/// unless a calibration step is being measured and `"calibrated_at":0` when the time is unknown.
+///
+/// The coefficients are the curve's, see `ProbeCalibration`, for the ADC settings the channel has
+/// right now. The points frames only have room for a straight line.
+fn calibration_status() -> String<512> {
+    let mut status = String::new();
+    match CALIBRATIONS.measuring() {
+        Some(step) => write!(
+            status,
+            r#"{{"type":"calibration","measuring":"{}","channels":["#,
+            step
+        ),
+        None => write!(
+            status,
+            r#"{{"type":"calibration","measuring":null,"channels":["#
+        ),
+    }
+    .expect("Calibration status did not fit in its string.");
+    for (channel, calibration) in CALIBRATIONS.load().iter().enumerate() {
+        let [c0, c1, c2, c3] = calibration.probe.coefficients;
+        write!(
+            status,
+            r#"{}{{"source":"{}","calibrated_at":{},"references":{},"attenuation":"{}","calibration":"{}","coefficients":[{},{},{},{}]}}"#,
+            if channel == 0 { "" } else { "," },
+            calibration.source.name(),
+            calibration.calibrated_at,
+            calibration.references,
+            calibration.adc.attenuation.name(),
+            calibration.adc.scheme.name(),
+            c0,
+            c1,
+            c2,
+            c3
+        )
+        .expect("Calibration status did not fit in its string.");
+    }
+    status
+        .push_str("]}")
+        .expect("Calibration status did not fit in its string.");
+    status
+}
+
+/// Zeroes the probes when the boot button is pressed, as if a client had asked to.
+#[embassy_executor::task]
+async fn zero_button(mut button: Input<'static>) {
+    loop {
+        button.wait_for_falling_edge().await;
+        println!("Zeroing the probes, they should be shorted.");
+        ACQUISITION_CONTROL.apply(Command::Calibrate(CalibrationStep::Zero(0)));
+        // Ignore the button bouncing and being held down.
+        Timer::after(Duration::from_millis(1000)).await;
+    }
+}
+
+/// Writes what the measuring core calibrated to flash, on this core.
+#[embassy_executor::task]
+async fn calibration_writer(mut flash: CalibrationFlash, requests: &'static FlashRequests) {
+    requests.serve(&mut flash).await
+}
+
+/// The acquisition mode and state, and how the sampling goes.
+type AcquisitionReport = (AcquisitionMode, AcquisitionState, SamplingReport);
+
+/// Describes the acquisition as a JSON status message. `"range"` is the highest voltage the ADC
+/// measures at its pin with the channel's attenuation, `"auto"` whether the scope picks it.
+fn acquisition_status(
+    backend: SamplingBackend,
+    (mode, state, sampling): AcquisitionReport,
+) -> String<1024> {
+    let mut status = String::new();
+    write!(
+        status,
+        r#"{{"type":"acquisition","mode":"{}","state":"{}","backend":"{}","#,
+        mode.name(),
+        state.name(),
+        backend.name()
+    )
+    .and_then(|_| match sampling.requested.hz() {
+        Some(hz) => write!(status, r#""sample_rate":{hz},"#),
+        None => write!(status, r#""sample_rate":"max","#),
+    })
+    .and_then(|_| {
+        write!(
+            status,
+            r#""achieved_rate":{},"overruns":{},"filter":"{}","decimation":"{}","coarseness":{},"#,
+            sampling.achieved_hz,
+            sampling.overruns,
+            sampling.filter,
+            sampling.decimation,
+            sampling.coarseness
+        )
+    })
+    .and_then(|_| match sampling.thresholds {
+        Some(thresholds) => write!(status, r#""thresholds":"{thresholds}","adc":["#),
+        None => write!(status, r#""thresholds":"auto","adc":["#),
+    })
+    .expect("Acquisition status did not fit in its string.");
+    for (channel, (adc, auto)) in sampling.adc.iter().zip(sampling.autorange).enumerate() {
+        write!(
+            status,
+            r#"{}{{"attenuation":"{}","calibration":"{}","range":{},"auto":{},"#,
+            if channel == 0 { "" } else { "," },
+            adc.attenuation.name(),
+            adc.scheme.name(),
+            adc.attenuation.full_scale_voltage(),
+            auto
+        )
+        .and_then(|_| match sampling.noise_volts[channel] {
+            Some(volts) => write!(status, r#""noise":{volts},"#),
+            None => write!(status, r#""noise":null,"#),
+        })
+        .and_then(|_| {
+            write!(
+                status,
+                r#""thresholds":"{}"}}"#,
+                sampling.used_thresholds[channel]
+            )
+        })
+        .expect("Acquisition status did not fit in its string.");
+    }
+    status
+        .push_str("]}")
+        .expect("Acquisition status did not fit in its string.");
+    status
+}
+
+fn now_us() -> u64 {
+    esp_hal::time::now().ticks()
+}
+
+/// Allocates a slice in PSRAM, which the internal heap is far too small to hold.
+fn psram_slice<T: Copy>(bytes: usize, filler: T) -> &'static mut [T] {
+    let len = bytes / core::mem::size_of::<T>();
+    let layout = Layout::array::<T>(len).unwrap();
+    let pointer =
+        unsafe { esp_alloc::HEAP.alloc_caps(esp_alloc::MemoryCapability::External.into(), layout) }
+            as *mut T;
+    assert!(
+        !pointer.is_null(),
+        "Could not allocate {bytes} bytes of PSRAM."
+    );
+
+    unsafe {
+        for i in 0..len {
+            pointer.add(i).write(filler);
         }
+        core::slice::from_raw_parts_mut(pointer, len)
+    }
+}
+
+/// Moves a value into internal RAM for good. Once PSRAM is part of the heap, boxes can end up
+/// in it, where atomics do not work across the two cores.
+fn internal_leak<T>(value: T) -> &'static mut T {
+    let layout = Layout::new::<T>();
+    let pointer =
+        unsafe { esp_alloc::HEAP.alloc_caps(esp_alloc::MemoryCapability::Internal.into(), layout) }
+            as *mut T;
+    assert!(
+        !pointer.is_null(),
+        "Could not allocate {} bytes of internal RAM.",
+        layout.size()
+    );
+
+    unsafe {
+        pointer.write(value);
+        &mut *pointer
     }
 }
 
@@ -295,16 +797,138 @@ async fn main(spawner: embassy_executor::Spawner) -> ! {
         config.cpu_clock = CpuClock::max();
         config
     });
+    esp_alloc::psram_allocator!(peripherals.PSRAM, esp_hal::psram);
+
+    // How the ADC is set up for every channel, which clients can change.
+    let adc_settings = [
+        (
+            env!("channel1_attenuation"),
+            env!("channel1_adc_calibration"),
+            env!("channel1_autorange"),
+        ),
+        (
+            env!("channel2_attenuation"),
+            env!("channel2_adc_calibration"),
+            env!("channel2_autorange"),
+        ),
+    ];
+    for (channel, (attenuation, scheme, autorange)) in adc_settings.into_iter().enumerate() {
+        ACQUISITION_CONTROL.apply(Command::Adc(
+            channel as u8,
+            AdcSettings {
+                attenuation: Attenuation::from_name(attenuation).unwrap(),
+                scheme: CalibrationScheme::from_name(scheme).unwrap(),
+            },
+        ));
+        ACQUISITION_CONTROL.apply(Command::AutoRange(
+            channel as u8,
+            autorange.parse().unwrap(),
+        ));
+    }
+
+    // The mapping from ADC codes to volts for every probe, which clients need as well. What was
+    // measured on the scope wins over Settings.toml. The front end scales the probes to the
+    // ADC's range at 11 dB.
+    let front_ends = [
+        FrontEnd {
+            reference_voltage: Attenuation::Db11.full_scale_voltage(),
+            probes_shorted_voltage: env!("channel1_probes_shorted").parse().unwrap(),
+            max_voltage: env!("channel1_max_voltage_absolute").parse().unwrap(),
+        },
+        FrontEnd {
+            reference_voltage: Attenuation::Db11.full_scale_voltage(),
+            probes_shorted_voltage: env!("channel2_probes_shorted").parse().unwrap(),
+            max_voltage: env!("channel2_max_voltage_absolute").parse().unwrap(),
+        },
+    ];
+    let mut calibration_flash = CalibrationFlash::new();
+    // Shared with the measuring core, like the buffers below.
+    let calibrator: &mut ProbeCalibrator = internal_leak(ProbeCalibrator::new(
+        front_ends,
+        ACQUISITION_CONTROL.adc_settings(),
+        calibration_flash.load_zero_offsets(),
+        calibration_flash.load_fits(),
+        env!("calibration_samples").parse().unwrap(),
+    ));
+    CALIBRATIONS.store(&calibrator.channels(), &calibrator.curves());
+    let flash_requests: &'static FlashRequests = internal_leak(FlashRequests::new());
+    spawner
+        .spawn(calibration_writer(calibration_flash, flash_requests))
+        .expect("Failed to spawn calibration writer task.");
+    let enabled: [bool; CHANNELS] = [
+        env!("channel1_enabled").parse().unwrap(),
+        env!("channel2_enabled").parse().unwrap(),
+    ];
+
+    // Clients need the trigger settings to line frames up on screen.
+    let trigger_settings = Slope::from_config(env!("trigger_slope")).map(|slope| TriggerSettings {
+        channel: env!("trigger_channel").parse().unwrap(),
+        slope,
+        level_volts: env!("trigger_level").parse().unwrap(),
+        pre_trigger_percent: env!("trigger_pre_trigger_percent").parse().unwrap(),
+        frame_us: env!("trigger_frame_us").parse().unwrap(),
+        holdoff_us: env!("trigger_holdoff_us").parse().unwrap(),
+    });
+    ACQUISITION_CONTROL.apply(Command::Mode(
+        AcquisitionMode::from_name(env!("trigger_mode")).unwrap(),
+    ));
+    let sampling_backend = SamplingBackend::from_name(env!("sampling_backend")).unwrap();
+    ACQUISITION_CONTROL.apply(Command::Rate(
+        SampleRate::parse(env!("sampling_rate_hz")).unwrap(),
+    ));
+    ACQUISITION_CONTROL.apply(Command::Filter(
+        FilterSettings::parse(env!("filter_chain")).unwrap(),
+    ));
+    ACQUISITION_CONTROL.apply(Command::Decimation(
+        DecimationMode::parse(env!("decimation_mode")).unwrap(),
+    ));
 
     // Construct the buffer that will store the voltage measurements.
-    let point_buffer: &'static CyclicBuffer<POINTS_BUFFER_SIZE, OscilliscopePoint> =
-        Box::leak(Box::new(CyclicBuffer::new(OscilliscopePoint {
+    let overflow_policy = OverflowPolicy::from_config(
+        env!("overflow_policy"),
+        env!("block_timeout_us").parse().unwrap(),
+        now_us,
+    );
+    let point_buffer: &'static BroadcastBuffer<
+        MAX_WEBSOCKET_CLIENTS,
+        POINTS_BUFFER_SIZE,
+        OscilliscopePoint,
+    > = internal_leak(BroadcastBuffer::new(
+        OscilliscopePoint {
             voltage: 10f64,
-            second: 10f64,
-        })));
+            microsecond: 0,
+            code: 0,
+            index: 0,
+            channel: 0,
+            trigger: false,
+            heartbeat: false,
+            adc: AdcSettings::DEFAULT,
+        },
+        overflow_policy,
+    ));
 
     let mut writer = point_buffer.take_writer().unwrap();
 
+    // Construct the history that clients get to see when they connect.
+    let history_megabytes: usize = env!("history_megabytes").parse().unwrap();
+    // The history only uses a power of two of points, so no more than that is set aside.
+    let history_points =
+        1 << (history_megabytes * 1024 * 1024 / core::mem::size_of::<OscilliscopePoint>()).ilog2();
+    let history: &'static History<OscilliscopePoint> = internal_leak(History::new(psram_slice(
+        history_points * core::mem::size_of::<OscilliscopePoint>(),
+        OscilliscopePoint {
+            voltage: 0f64,
+            microsecond: 0,
+            code: 0,
+            index: 0,
+            channel: 0,
+            trigger: false,
+            heartbeat: false,
+            adc: AdcSettings::DEFAULT,
+        },
+    )));
+    let mut history_writer = history.take_writer().unwrap();
+
     // Initialize embassy so async works at all.
     let timg1 = TimerGroup::new(peripherals.TIMG1);
     esp_hal_embassy::init(timg1.timer0);
@@ -403,46 +1027,85 @@ async fn main(spawner: embassy_executor::Spawner) -> ! {
         .spawn(http_server(sta_stack, STA_STATIC_IP_ADDRESS))
         .expect("Failed to spawn station http server task.");
     println!("Starting WebSocket servers!");
+    let wakeup_points: usize = env!("wakeup_points").parse().unwrap();
+    let wakeup_latency = Duration::from_millis(env!("wakeup_latency_ms").parse().unwrap());
+    for _ in 0..WEBSOCKET_CLIENTS_PER_INTERFACE {
+        spawner
+            .spawn(web_socket_server(
+                ap_stack,
+                &point_buffer,
+                &history,
+                trigger_settings,
+                sampling_backend,
+                wakeup_points,
+                wakeup_latency,
+                SocketAddr::V4(AP_WEBSOCKET_ENDPOINT),
+            ))
+            .expect("Failed to spawn access point WebSocket server task.");
+        spawner
+            .spawn(web_socket_server(
+                sta_stack,
+                &point_buffer,
+                &history,
+                trigger_settings,
+                sampling_backend,
+                wakeup_points,
+                wakeup_latency,
+                SocketAddr::V4(STA_WEBSOCKET_ENDPOINT),
+            ))
+            .expect("Failed to spawn station WebSocket server task.");
+    }
+
     spawner
-        .spawn(web_socket_server(
-            ap_stack,
-            &point_buffer,
-            SocketAddr::V4(AP_WEBSOCKET_ENDPOINT),
-        ))
-        .expect("Failed to spawn access point WebSocket server task.");
-    spawner
-        .spawn(web_socket_server(
-            sta_stack,
-            &point_buffer,
-            SocketAddr::V4(STA_WEBSOCKET_ENDPOINT),
-        ))
-        .expect("Failed to spawn station WebSocket server task.");
+        .spawn(zero_button(Input::new(peripherals.GPIO0, Pull::Up)))
+        .expect("Failed to spawn zero button task.");
 
     // Spawn the process on the second core that actually performs the measurements.
     let mut cpu_control = CpuControl::new(peripherals.CPU_CTRL);
 
     // Get standard values from config.toml
-    let reference_voltage: f64 = env!("adc_reference_voltage").parse().unwrap();
-    let probes_shorted_voltage: f64 = env!("probes_shorted").parse().unwrap();
-    let max_voltage: f64 = env!("max_voltage_absolute").parse().unwrap();
-    let tolerance_factor: f64 = env!("tolerance_factor").parse().unwrap();
-    let min_voltage_difference: f64 = env!("min_voltage_difference").parse().unwrap();
+    let thresholds = Thresholds {
+        tolerance_factor: env!("tolerance_factor").parse().unwrap(),
+        min_voltage_difference: env!("min_voltage_difference").parse().unwrap(),
+    };
+    // Otherwise the thresholds follow the noise, once it is measured.
+    if !env!("adaptive_thresholds").parse::<bool>().unwrap() {
+        ACQUISITION_CONTROL.apply(Command::Thresholds(Some(thresholds)));
+    }
+    let max_point_gap_us: u64 = env!("max_point_gap_us").parse().unwrap();
+    let heartbeat_us: u64 = env!("heartbeat_us").parse().unwrap();
+    let backpressure: bool = env!("backpressure").parse().unwrap();
     let samples_per_point: u32 = env!("samples_per_point").parse().unwrap();
+    let pre_trigger_points: usize = env!("trigger_pre_trigger_points").parse().unwrap();
+    let auto_timeout_us: u64 = env!("trigger_auto_timeout_us").parse().unwrap();
+    let autorange_window_us: u64 = env!("autorange_window_us").parse().unwrap();
 
     let snd_core_fn = || {
         measure::measuring_task(
             peripherals.ADC1,
-            peripherals.GPIO1,
+            (peripherals.GPIO1, peripherals.GPIO2),
+            peripherals.DMA,
+            sampling_backend,
             &mut writer,
-            reference_voltage,
-            probes_shorted_voltage,
-            max_voltage,
-            tolerance_factor,
-            min_voltage_difference,
+            &mut history_writer,
+            calibrator,
+            flash_requests,
+            &CALIBRATIONS,
+            enabled,
+            thresholds,
+            max_point_gap_us,
+            heartbeat_us,
+            backpressure,
             samples_per_point,
+            trigger_settings,
+            &ACQUISITION_CONTROL,
+            auto_timeout_us,
+            pre_trigger_points,
+            autorange_window_us,
         )
     };
 
+    paint_app_core_stack();
     let _guard = cpu_control
         .start_app_core(unsafe { &mut *addr_of_mut!(APP_CORE_STACK) }, snd_core_fn)
         .unwrap();
@@ -455,14 +1118,36 @@ async fn main(spawner: embassy_executor::Spawner) -> ! {
     println!("My wifi MAC is {:x?}", mac);
     try_connect(&mut controller);
     loop {
-        unsafe {
-            println!("Missed: {}", *point_buffer.missed.get());
-        }
+        println!(
+            "Clients: {}, missed: {}, app core stack: {} of {} bytes used",
+            point_buffer.reader_count(),
+            point_buffer.missed(),
+            app_core_stack_used(),
+            APP_CORE_STACK_SIZE
+        );
         Timer::after(Duration::from_millis(7000)).await;
         led.toggle();
     }
 }
 
+/// Fills the app core's stack with `STACK_PAINT`, before the app core runs on it.
+fn paint_app_core_stack() {
+    let words = addr_of_mut!(APP_CORE_STACK) as *mut u32;
+    for i in 0..APP_CORE_STACK_SIZE / 4 {
+        unsafe { words.add(i).write_volatile(STACK_PAINT) };
+    }
+}
+
+/// The most bytes of its stack the app core used so far. The stack grows down, so the words it
+/// never reached are at the bottom.
+fn app_core_stack_used() -> usize {
+    let words = addr_of!(APP_CORE_STACK) as *const u32;
+    let untouched = (0..APP_CORE_STACK_SIZE / 4)
+        .take_while(|&i| unsafe { words.add(i).read_volatile() } == STACK_PAINT)
+        .count();
+    APP_CORE_STACK_SIZE - untouched * 4
+}
+
 fn try_connect(controller: &mut esp_wifi::wifi::WifiController) {
     unsafe {
         if CONNECTED_TO_AP {
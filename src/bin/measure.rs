@@ -1,89 +1,581 @@
-use crate::websocket_logistics::{
-    is_middle_point_removable_complicated, CyclicWriter, OscilliscopePoint,
-};
+use crate::{adc_dma::DmaSampler, calibration_flash::FlashRequests};
+use core::cell::{Cell, RefCell};
 use esp_hal::{
-    analog::adc::{Adc, AdcCalBasic, AdcChannel, AdcConfig, Attenuation},
+    analog::adc::{
+        Adc, AdcCalBasic, AdcCalCurve, AdcCalLine, AdcCalScheme, AdcChannel, AdcConfig, AdcPin,
+        Attenuation,
+    },
     gpio::{AnalogPin, GpioPin},
-    peripherals::ADC1,
+    peripherals::{ADC1, DMA},
     prelude::nb,
     time::now,
 };
+use just_a_scope::{
+    adc::{AdcSettings, CalibrationScheme},
+    autorange::AutoRanger,
+    backpressure::Backpressure,
+    calibration::ProbeCalibration,
+    calibrator::{Changes, ProbeCalibrator, SharedCalibrations},
+    control::{AcquisitionControl, Command},
+    decimation::{Decimator, Thresholds},
+    filter::FilterChain,
+    history::HistoryWriter,
+    noise::NoiseFloor,
+    sampling::{Averager, DmaSample, Pacer, RateMeter},
+    trigger::{Trigger, TriggerSettings},
+    websocket_logistics::{BroadcastWriter, OscilliscopePoint, CHANNELS, CODE_FRACTION_BITS},
+};
+
+/// What a channel decimates with: the thresholds a client picked, or else the ones its noise
+/// calls for, or else the ones from Settings.toml.
+fn thresholds_for(
+    picked: Option<Thresholds>,
+    noise: &NoiseFloor,
+    volts_per_code: f64,
+    configured: Thresholds,
+) -> Thresholds {
+    picked
+        .or_else(|| noise.thresholds(volts_per_code, configured.tolerance_factor))
+        .unwrap_or(configured)
+}
+
+/// How many volts at the probe a raw code of the ADC is worth, on average over the correction's
+/// range. The noise is measured in raw codes, before they are corrected.
+fn volts_per_raw_code(probe: &ProbeCalibration, correction: &CodeCorrection) -> f64 {
+    let corrected_per_code =
+        (correction.correct(4095) as f64 - correction.correct(0) as f64) / 4095f64;
+    libm::fabs((probe.volts_at(1f64) - probe.volts_at(0f64)) * corrected_per_code)
+}
+
+/// Where the samples come from.
+///
+/// Either way every enabled channel is sampled at the rate clients ask for, one after another,
+/// and points are stamped from their sample's index. Oneshot sampling adds how long after the
+/// tick each channel's conversion started. Only at the maximum rate, oneshot sampling goes as
+/// fast as the loop goes, and points are stamped with the time they were taken at.
+#[derive(Clone, Copy, Debug)]
+pub enum SamplingBackend {
+    /// Converts one sample at a time, paced by the system timer.
+    Oneshot,
+    /// Converts continuously, paced by the ADC controller's timer.
+    Dma,
+}
+
+impl SamplingBackend {
+    pub fn from_name(name: &str) -> Option<SamplingBackend> {
+        match name {
+            "oneshot" => Some(SamplingBackend::Oneshot),
+            "dma" => Some(SamplingBackend::Dma),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SamplingBackend::Oneshot => "oneshot",
+            SamplingBackend::Dma => "dma",
+        }
+    }
+}
 
 fn take_measurement<const PIN: u8>(
     adc: &mut Adc<'_, ADC1>,
-    pin: &mut esp_hal::analog::adc::AdcPin<GpioPin<PIN>, ADC1, AdcCalBasic<ADC1>>,
+    pin: &mut AdcPin<GpioPin<PIN>, ADC1, AdcCalBasic<ADC1>>,
     samples_per_point: u32,
+    mut each_sample: impl FnMut(u16),
 ) -> u16
 where
     GpioPin<PIN>: AdcChannel + AnalogPin,
 {
     let mut sum = 0u32;
     for _ in 0..samples_per_point {
-        sum += nb::block!(adc.read_oneshot(pin)).unwrap() as u32;
+        let code = nb::block!(adc.read_oneshot(pin)).unwrap();
+        each_sample(code);
+        sum += code as u32;
     }
 
     return (sum / samples_per_point).try_into().unwrap();
 }
 
-pub fn measuring_task<const L: usize, const PIN: u8>(
-    adc_peripheral: ADC1,
-    pin: GpioPin<PIN>,
-    point_buffer_writer: &mut CyclicWriter<'_, L, OscilliscopePoint>,
-    reference_voltage: f64,
-    probes_shorted_voltage: f64,
-    max_voltage: f64,
-    tolerance_factor: f64,
-    min_voltage_difference: f64,
+fn hal_attenuation(attenuation: just_a_scope::adc::Attenuation) -> Attenuation {
+    match attenuation {
+        just_a_scope::adc::Attenuation::Db0 => Attenuation::Attenuation0dB,
+        just_a_scope::adc::Attenuation::Db2_5 => Attenuation::Attenuation2p5dB,
+        just_a_scope::adc::Attenuation::Db6 => Attenuation::Attenuation6dB,
+        just_a_scope::adc::Attenuation::Db11 => Attenuation::Attenuation11dB,
+    }
+}
+
+/// The oneshot driver, with the pins of both channels set up for their ADC settings.
+///
+/// The basic calibration is applied by the ADC itself, while converting. Line and curve
+/// corrections come on top of it, see `CodeCorrection`.
+struct OneshotAdc<'d, const PIN1: u8, const PIN2: u8> {
+    adc: Adc<'d, ADC1>,
+    pin1: AdcPin<GpioPin<PIN1>, ADC1, AdcCalBasic<ADC1>>,
+    pin2: AdcPin<GpioPin<PIN2>, ADC1, AdcCalBasic<ADC1>>,
+}
+
+impl<'d, const PIN1: u8, const PIN2: u8> OneshotAdc<'d, PIN1, PIN2>
+where
+    GpioPin<PIN1>: AdcChannel + AnalogPin,
+    GpioPin<PIN2>: AdcChannel + AnalogPin,
+{
+    fn new(
+        adc_peripheral: &'d mut ADC1,
+        pins: (GpioPin<PIN1>, GpioPin<PIN2>),
+        settings: [AdcSettings; CHANNELS],
+    ) -> OneshotAdc<'d, PIN1, PIN2> {
+        let mut adc_config = AdcConfig::new();
+        let pin1 = adc_config.enable_pin_with_cal::<_, AdcCalBasic<_>>(
+            pins.0,
+            hal_attenuation(settings[0].attenuation),
+        );
+        let pin2 = adc_config.enable_pin_with_cal::<_, AdcCalBasic<_>>(
+            pins.1,
+            hal_attenuation(settings[1].attenuation),
+        );
+        OneshotAdc {
+            adc: Adc::new(adc_peripheral, adc_config),
+            pin1,
+            pin2,
+        }
+    }
+
+    /// Gives the pins back, to set the driver up again.
+    fn into_pins(self) -> (GpioPin<PIN1>, GpioPin<PIN2>) {
+        (self.pin1.pin, self.pin2.pin)
+    }
+
+    /// Averages `samples` codes of the channel, handing each one to `each_sample` as well.
+    fn read(&mut self, channel: u8, samples: u32, each_sample: impl FnMut(u16)) -> u16 {
+        match channel {
+            0 => take_measurement(&mut self.adc, &mut self.pin1, samples, each_sample),
+            _ => take_measurement(&mut self.adc, &mut self.pin2, samples, each_sample),
+        }
+    }
+}
+
+/// Turns a channel's codes into millivolts at its pin with the calibration in the eFuses, for
+/// the line and curve schemes, the same way esp-hal's oneshot driver would.
+enum CodeCorrection {
+    None,
+    Line(AdcCalLine<ADC1>),
+    Curve(AdcCalCurve<ADC1>),
+}
+
+impl CodeCorrection {
+    fn new(settings: AdcSettings) -> CodeCorrection {
+        let attenuation = hal_attenuation(settings.attenuation);
+        match settings.scheme {
+            CalibrationScheme::Basic => CodeCorrection::None,
+            CalibrationScheme::Line => CodeCorrection::Line(AdcCalLine::new_cal(attenuation)),
+            CalibrationScheme::Curve => CodeCorrection::Curve(AdcCalCurve::new_cal(attenuation)),
+        }
+    }
+
+    fn correct(&self, code: u16) -> u16 {
+        match self {
+            CodeCorrection::None => code,
+            CodeCorrection::Line(calibration) => calibration.adc_val(code),
+            CodeCorrection::Curve(calibration) => calibration.adc_val(code),
+        }
+    }
+}
+
+/// Has the records that changed written to flash. Stalls the sampling while the sectors are
+/// erased.
+fn store_calibrations(calibrator: &ProbeCalibrator, changes: Changes, flash: &FlashRequests) {
+    flash.store(
+        changes.zero_offsets.then(|| *calibrator.zero_offsets()),
+        changes.fits.then(|| *calibrator.fits()),
+    );
+}
+
+pub fn measuring_task<const N: usize, const L: usize, const PIN1: u8, const PIN2: u8>(
+    mut adc_peripheral: ADC1,
+    pins: (GpioPin<PIN1>, GpioPin<PIN2>),
+    dma_peripheral: DMA,
+    backend: SamplingBackend,
+    point_buffer_writer: &mut BroadcastWriter<'_, N, L, OscilliscopePoint>,
+    history_writer: &mut HistoryWriter<'_, OscilliscopePoint>,
+    calibrator: &mut ProbeCalibrator,
+    flash_requests: &FlashRequests,
+    shared_calibrations: &SharedCalibrations,
+    enabled: [bool; CHANNELS],
+    thresholds: Thresholds,
+    max_point_gap_us: u64,
+    heartbeat_us: u64,
+    backpressure: bool,
     samples_per_point: u32,
+    trigger_settings: Option<TriggerSettings>,
+    control: &AcquisitionControl,
+    auto_timeout_us: u64,
+    pre_trigger_points: usize,
+    autorange_window_us: u64,
 ) -> !
 where
-    GpioPin<PIN>: AdcChannel + AnalogPin,
+    GpioPin<PIN1>: AdcChannel + AnalogPin,
+    GpioPin<PIN2>: AdcChannel + AnalogPin,
 {
-    let mut adc_config = AdcConfig::new();
+    // Clients pick the ADC settings, the first ones are there already.
+    control.take_adc_request();
+    let adc_settings = control.adc_settings();
+    let mut oneshot = OneshotAdc::new(&mut adc_peripheral, pins, adc_settings);
+    let adc_channels = [GpioPin::<PIN1>::CHANNEL, GpioPin::<PIN2>::CHANNEL];
+    let channels: heapless::Vec<u8, CHANNELS> = (0..CHANNELS as u8)
+        .filter(|&channel| enabled[channel as usize])
+        .collect();
 
-    let mut pin =
-        adc_config.enable_pin_with_cal::<_, AdcCalBasic<_>>(pin, Attenuation::Attenuation11dB);
-    let mut adc = Adc::new(adc_peripheral, adc_config);
-
-    let mut before_last: OscilliscopePoint = OscilliscopePoint {
-        voltage: 0f64,
-        second: 0f64,
+    // Every channel's thresholds follow its noise, unless a client picked them. The sampling
+    // loops measure the noise on every raw sample, and the points follow whenever it changed.
+    control.take_thresholds_request();
+    let mut picked_thresholds = control.thresholds();
+    let noise: RefCell<[NoiseFloor; CHANNELS]> =
+        RefCell::new(core::array::from_fn(|_| NoiseFloor::new()));
+    let sample_noise = |channel: u8, code: u16| {
+        noise.borrow_mut()[channel as usize].push(code);
     };
-    let mut last: OscilliscopePoint = OscilliscopePoint {
-        voltage: 0.01f64,
-        second: 0.01f64,
+    let reset_noise = || noise.borrow_mut().iter_mut().for_each(NoiseFloor::reset);
+    let mut followed_noise: [Option<f64>; CHANNELS] = [None; CHANNELS];
+    let used_thresholds = picked_thresholds.unwrap_or(thresholds);
+    for channel in 0..CHANNELS as u8 {
+        control.report_thresholds(channel, None, used_thresholds);
+    }
+    control.take_decimation_request();
+    let decimation_mode = control.decimation_mode();
+    let mut decimators: [Decimator; CHANNELS] = core::array::from_fn(|_| {
+        Decimator::new(
+            decimation_mode,
+            used_thresholds,
+            max_point_gap_us,
+            heartbeat_us,
+        )
+    });
+    let mut trigger = Trigger::new(
+        trigger_settings,
+        control.mode(),
+        auto_timeout_us,
+        pre_trigger_points,
+    );
+    // While the slowest client falls behind, the decimators keep fewer points, so it gets
+    // coarser ones instead of losing some.
+    control.report_coarseness(0);
+    let mut backpressure = backpressure.then(Backpressure::new);
+    let new_coarseness = Cell::new(None);
+    let mut send = |point: OscilliscopePoint| {
+        // Clients that connect later get the points themselves.
+        if !point.heartbeat {
+            history_writer.append(point);
+        }
+        match point_buffer_writer.append(point) {
+            Ok(_) => (),
+            Err(_) => (),
+        }
+        if let Some(backpressure) = backpressure.as_mut() {
+            let lag = point_buffer_writer.lag();
+            if let Some(level) = backpressure.push(lag, L, point.microsecond) {
+                new_coarseness.set(Some(level));
+            }
+        }
     };
-
-    loop {
-        let current_second: f64 = now().ticks() as f64 / 1_000_000f64;
-        let raw_adc_output = take_measurement(&mut adc, &mut pin, samples_per_point);
-        let raw_adc_voltage = raw_adc_output as f64 * reference_voltage / 4095f64;
-        let adjusted_voltage = (raw_adc_voltage - probes_shorted_voltage) * max_voltage * 2f64
-            / reference_voltage
-            - probes_shorted_voltage;
-        let new_point = OscilliscopePoint {
-            voltage: adjusted_voltage,
-            second: current_second,
+    // The sampling loops change the ADC settings while the ADC is stopped, and leave the new
+    // ones here for the codes that come after.
+    let new_adc_settings = Cell::new(Some((adc_settings, adc_settings.map(CodeCorrection::new))));
+    let mut corrections: [CodeCorrection; CHANNELS] =
+        core::array::from_fn(|_| CodeCorrection::None);
+    let mut current_adc_settings = adc_settings;
+    // Channels that asked for another attenuation wait for it before they look again.
+    let mut rangers: [AutoRanger; CHANNELS] =
+        core::array::from_fn(|_| AutoRanger::new(autorange_window_us));
+    let mut range_pending = [false; CHANNELS];
+    // The sampling loops leave the rate points come in at here, for the low-passes. Until they
+    // know it, the low-passes let everything through.
+    let new_point_rate = Cell::new(None);
+    control.take_filter_request();
+    let mut point_rate_hz = 0f64;
+    let mut filters: [FilterChain; CHANNELS] =
+        core::array::from_fn(|_| FilterChain::new(control.filter_settings(), point_rate_hz));
+    // While the probes are calibrated, their codes go to the calibrator instead of the clients.
+    let mut probes = calibrator.channels().map(|channel| channel.probe);
+    let mut measure = |channel: u8, raw_code: u16, microsecond: u64| {
+        if let Some((settings, new_corrections)) = new_adc_settings.take() {
+            corrections = new_corrections;
+            current_adc_settings = settings;
+            rangers.iter_mut().for_each(AutoRanger::restart);
+            range_pending = [false; CHANNELS];
+            calibrator.set_adc(settings);
+            let channels = calibrator.channels();
+            shared_calibrations.store(&channels, &calibrator.curves());
+            shared_calibrations.set_measuring(calibrator.measuring());
+            probes = channels.map(|channel| channel.probe);
+            // Codes with other settings are not comparable to the ones before.
+            filters.iter_mut().for_each(FilterChain::reset);
+        }
+        if let Some(rate_hz) = new_point_rate.take() {
+            point_rate_hz = rate_hz;
+            filters
+                .iter_mut()
+                .for_each(|filter| filter.set_rate(point_rate_hz));
+        }
+        if let Some(settings) = control.take_filter_request() {
+            filters = core::array::from_fn(|_| FilterChain::new(settings, point_rate_hz));
+        }
+        if let Some(mode) = control.take_decimation_request() {
+            decimators
+                .iter_mut()
+                .for_each(|decimator| decimator.set_mode(mode));
+        }
+        if let Some(picked) = control.take_thresholds_request() {
+            picked_thresholds = picked;
+            let noise = noise.borrow();
+            for (channel, decimator) in decimators.iter_mut().enumerate() {
+                let noise = &noise[channel];
+                let volts_per_code = volts_per_raw_code(&probes[channel], &corrections[channel]);
+                let used = thresholds_for(picked, noise, volts_per_code, thresholds);
+                decimator.set_thresholds(used);
+                control.report_thresholds(channel as u8, noise.volts(volts_per_code), used);
+            }
+        }
+        let code = corrections[channel as usize].correct(raw_code);
+        let changes = match control.take_calibration_request() {
+            Some(step) => {
+                let changes = calibrator.start(step);
+                shared_calibrations.set_measuring(calibrator.measuring());
+                changes
+            }
+            None if calibrator.measuring().is_some() => {
+                let changes = calibrator.push(channel, code, &enabled);
+                if changes.is_some() {
+                    shared_calibrations.set_measuring(None);
+                }
+                changes
+            }
+            None => None,
         };
+        if let Some(changes) = changes {
+            store_calibrations(calibrator, changes, flash_requests);
+            let channels = calibrator.channels();
+            shared_calibrations.store(&channels, &calibrator.curves());
+            probes = channels.map(|channel| channel.probe);
+        }
+        if calibrator.measuring().is_some() {
+            return;
+        }
 
-        let time_difference = last.second - before_last.second;
+        // The ranger goes by the ADC's own codes, which clip the same with any correction.
+        let settings = current_adc_settings[channel as usize];
+        let ranger = &mut rangers[channel as usize];
+        if !control.autorange()[channel as usize] {
+            ranger.restart();
+        } else if !range_pending[channel as usize] {
+            if let Some(attenuation) = ranger.push(raw_code, microsecond, settings.attenuation) {
+                control.apply(Command::Adc(
+                    channel,
+                    AdcSettings {
+                        attenuation,
+                        ..settings
+                    },
+                ));
+                range_pending[channel as usize] = true;
+            }
+        }
+
+        let Some((filtered, microsecond)) =
+            filters[channel as usize].push(code as f64, microsecond)
+        else {
+            return;
+        };
+        let mut new_point = OscilliscopePoint {
+            voltage: probes[channel as usize].volts_at(filtered),
+            microsecond,
+            code: (filtered * (1 << CODE_FRACTION_BITS) as f64 + 0.5) as u16,
+            index: 0,
+            channel,
+            trigger: false,
+            heartbeat: false,
+            adc: settings,
+        };
 
-        if time_difference > 1f64
-            || !is_middle_point_removable_complicated(
-                &before_last,
-                &last,
-                &new_point,
-                tolerance_factor,
-                min_voltage_difference,
-            )
         {
-            match point_buffer_writer.append(last.clone()) {
-                Ok(_) => (),
-                Err(_) => (),
+            let noise = noise.borrow();
+            let channel_noise = &noise[channel as usize];
+            if channel_noise.codes() != followed_noise[channel as usize] {
+                followed_noise[channel as usize] = channel_noise.codes();
+                let volts_per_code =
+                    volts_per_raw_code(&probes[channel as usize], &corrections[channel as usize]);
+                let used =
+                    thresholds_for(picked_thresholds, channel_noise, volts_per_code, thresholds);
+                decimators[channel as usize].set_thresholds(used);
+                control.report_thresholds(channel, channel_noise.volts(volts_per_code), used);
+            }
+        }
+
+        if control.take_stop_request() {
+            trigger.stop();
+        }
+        if control.take_run_request() {
+            trigger.run();
+        }
+        trigger.set_mode(control.mode());
+        trigger.detect(&mut new_point);
+        control.set_state(trigger.state());
+
+        if let Some(level) = new_coarseness.take() {
+            decimators
+                .iter_mut()
+                .for_each(|decimator| decimator.set_coarseness(level));
+            control.report_coarseness(level);
+        }
+        decimators[channel as usize].push(new_point, |kept| trigger.frame(kept, &mut send));
+    };
+
+    // Samples are averaged as they come in, never across samples that were lost.
+    let mut averagers: [Averager; CHANNELS] =
+        core::array::from_fn(|_| Averager::new(samples_per_point));
+    let reset =
+        |averagers: &mut [Averager; CHANNELS]| averagers.iter_mut().for_each(Averager::reset);
+    let mut rate_meter = RateMeter::new(now().ticks());
+    let mut overruns = 0u32;
+    control.take_rate_request();
+    let rate = control.sample_rate();
+
+    match backend {
+        SamplingBackend::Oneshot => {
+            // The enabled channels are sampled right after one another on every tick.
+            // At the maximum rate, the points come in as fast as the achieved rate says.
+            let point_rate = |hz: u32| hz as f64 / samples_per_point as f64;
+            let mut pacer = rate.hz().map(|hz| Pacer::new(now().ticks(), hz));
+            new_point_rate.set(rate.hz().map(point_rate));
+            loop {
+                if let Some(rate) = control.take_rate_request() {
+                    pacer = rate.hz().map(|hz| Pacer::new(now().ticks(), hz));
+                    new_point_rate.set(Some(rate.hz().map_or(0f64, point_rate)));
+                    reset(&mut averagers);
+                }
+                if let Some(settings) = control.take_adc_request() {
+                    let pins = oneshot.into_pins();
+                    oneshot = OneshotAdc::new(&mut adc_peripheral, pins, settings);
+                    new_adc_settings.set(Some((settings, settings.map(CodeCorrection::new))));
+                    reset(&mut averagers);
+                    reset_noise();
+                }
+
+                let current_microsecond = now().ticks();
+                let samples = match &mut pacer {
+                    None => {
+                        for &channel in &channels {
+                            let microsecond = now().ticks();
+                            let raw_adc_output = oneshot.read(channel, samples_per_point, |code| {
+                                sample_noise(channel, code)
+                            });
+                            measure(channel, raw_adc_output, microsecond);
+                        }
+                        samples_per_point
+                    }
+                    Some(pacer) => match pacer.poll(current_microsecond) {
+                        None => 0,
+                        Some(tick) => {
+                            if tick.missed > 0 {
+                                overruns = overruns.saturating_add(tick.missed as u32);
+                                reset(&mut averagers);
+                            }
+                            // The channels are converted one after another, so every one is
+                            // stamped as much later than its tick as its conversion started.
+                            let clock = pacer.clock();
+                            let due = clock.microsecond(tick.index);
+                            for &channel in &channels {
+                                let delay = now().ticks().saturating_sub(due);
+                                let code =
+                                    oneshot.read(channel, 1, |code| sample_noise(channel, code));
+                                let averager = &mut averagers[channel as usize];
+                                if let Some((code, first)) = averager.push(code, tick.index) {
+                                    measure(channel, code, clock.microsecond(first) + delay);
+                                }
+                            }
+                            1
+                        }
+                    },
+                };
+
+                if let Some(achieved) = rate_meter.count(samples, current_microsecond) {
+                    control.report_sampling(achieved as u32, overruns);
+                    if pacer.is_none() {
+                        new_point_rate.set(Some(point_rate(achieved as u32)));
+                    }
+                }
             }
+        }
+        SamplingBackend::Dma => {
+            let sampled: heapless::Vec<u8, CHANNELS> = channels
+                .iter()
+                .map(|&channel| adc_channels[channel as usize])
+                .collect();
+            let attenuations = |settings: [AdcSettings; CHANNELS]| {
+                channels
+                    .iter()
+                    .map(|&channel| hal_attenuation(settings[channel as usize].attenuation))
+                    .collect::<heapless::Vec<Attenuation, CHANNELS>>()
+            };
+            let mut sampler = DmaSampler::new(
+                &mut oneshot.adc,
+                dma_peripheral,
+                &sampled,
+                &attenuations(adc_settings),
+                rate,
+            );
+            let mut next_index = 0;
+            let mut block_rate_hz = 0f64;
+            loop {
+                if let Some(rate) = control.take_rate_request() {
+                    sampler.set_rate(rate);
+                    reset(&mut averagers);
+                    next_index = 0;
+                }
+                if let Some(settings) = control.take_adc_request() {
+                    // Reading the calibration converts, which has to wait for the DMA to stop.
+                    sampler.stop();
+                    new_adc_settings.set(Some((settings, settings.map(CodeCorrection::new))));
+                    sampler.set_attenuations(&attenuations(settings));
+                    reset(&mut averagers);
+                    reset_noise();
+                    next_index = 0;
+                }
 
-            before_last = last;
+                let block = sampler.read_block();
+                // The controller's rate is shared by the channels, and known exactly.
+                if block.clock.rate_hz() != block_rate_hz {
+                    block_rate_hz = block.clock.rate_hz();
+                    let per_point = (channels.len() as u32 * samples_per_point).max(1);
+                    new_point_rate.set(Some(block_rate_hz / per_point as f64));
+                }
+                if block.first_index != next_index {
+                    overruns = overruns.saturating_add((block.first_index - next_index) as u32);
+                    reset(&mut averagers);
+                }
+                let mut samples = 0;
+                for (offset, sample) in DmaSample::decode_block(block.data).enumerate() {
+                    // Every conversion counts, whichever channel it was on.
+                    let index = block.first_index + offset as u64;
+                    next_index = index + 1;
+                    let Some(channel) = adc_channels
+                        .iter()
+                        .position(|&adc_channel| adc_channel == sample.channel)
+                    else {
+                        continue;
+                    };
+                    sample_noise(channel as u8, sample.code);
+                    if let Some((code, first)) = averagers[channel].push(sample.code, index) {
+                        measure(channel as u8, code, block.clock.microsecond(first));
+                    }
+                    samples += 1;
+                }
+
+                let per_channel = samples / channels.len().max(1) as u32;
+                if let Some(achieved) = rate_meter.count(per_channel, now().ticks()) {
+                    control.report_sampling(achieved as u32, overruns);
+                }
+            }
         }
-        last = new_point;
     }
 }
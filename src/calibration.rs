@@ -0,0 +1,511 @@
+//! Turning ADC codes into volts at the probes, either along a straight line from the front end's
+//! voltages in Settings.toml, or along a curve fitted to reference voltages measured on the
+//! scope.
+//!
+//! What is measured on the scope is kept in flash as two records, one for the zero offsets and
+//! one for the fitted curves. Both are little endian and start with the same eight bytes:
+//!
+//! | offset | type  | field                                            |
+//! |--------|-------|--------------------------------------------------|
+//! | 0      | `u32` | magic, [`ZERO_OFFSETS_MAGIC`] or [`FITS_MAGIC`]  |
+//! | 4      | `u8`  | record version, always 1                         |
+//! | 5      | `u8`  | reserved                                         |
+//! | 6      | `u16` | reserved                                         |
+//!
+//! Then comes an entry for every channel, which starts with a `u8` that is 1 when the channel
+//! has anything stored and 0 otherwise, and three reserved bytes. Zero offset entries go on with:
+//!
+//! | offset | type  | field                                            |
+//! |--------|-------|--------------------------------------------------|
+//! | 4      | `f32` | probes shorted voltage                           |
+//! | 8      | `u32` | unix time the channel was zeroed at, 0 if unknown|
+//!
+//! Fitted curve entries go on with:
+//!
+//! | offset | type     | field                                         |
+//! |--------|----------|-----------------------------------------------|
+//! | 4      | `u32`    | unix time the curve was fitted or zeroed at   |
+//! | 8      | `f32` x4 | the curve's coefficients, see [`ProbeCalibration`] |
+//! | 24     | `u8`     | the ADC settings it was fitted with, see [`AdcSettings::to_bits`] |
+//! | 25     | `u8`     | reserved                                      |
+//! | 26     | `u16`    | reserved                                      |
+//!
+//! Every record ends with the FNV-1a hash of everything before it, as a `u32`.
+
+use crate::{adc::AdcSettings, websocket_logistics::CHANNELS};
+
+pub const ZERO_OFFSETS_MAGIC: u32 = u32::from_le_bytes(*b"JASZ");
+pub const FITS_MAGIC: u32 = u32::from_le_bytes(*b"JASF");
+pub const ZERO_OFFSETS_LENGTH: usize = record_length(ZERO_OFFSET_ENTRY_LENGTH);
+pub const FITS_LENGTH: usize = record_length(FIT_ENTRY_LENGTH);
+const RECORD_VERSION: u8 = 1;
+const RECORD_HEADER_LENGTH: usize = 8;
+const ZERO_OFFSET_ENTRY_LENGTH: usize = 12;
+const FIT_ENTRY_LENGTH: usize = 28;
+/// The highest code of the 12 bit ADC.
+const FULL_SCALE: f64 = 4095f64;
+
+/// Linear mapping from ADC codes to volts at the probes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    pub volts_per_code: f32,
+    pub offset_volts: f32,
+}
+
+impl Calibration {
+    /// Derives the mapping from the front end's voltages in Settings.toml.
+    ///
+    /// The ADC sees `probes_shorted_voltage` when the probes are shorted, and the front end scales
+    /// `max_voltage` down to the ADC's reference voltage.
+    pub fn from_voltages(
+        reference_voltage: f64,
+        probes_shorted_voltage: f64,
+        max_voltage: f64,
+    ) -> Calibration {
+        let volts_per_code = max_voltage * 2f64 / FULL_SCALE;
+        let offset_volts = -probes_shorted_voltage * max_voltage * 2f64 / reference_voltage
+            - probes_shorted_voltage;
+        Calibration {
+            volts_per_code: volts_per_code as f32,
+            offset_volts: offset_volts as f32,
+        }
+    }
+
+    /// The `probes_shorted_voltage` that makes `from_voltages` read `shorted_code` as 0 V.
+    pub fn probes_shorted_voltage(
+        reference_voltage: f64,
+        max_voltage: f64,
+        shorted_code: f64,
+    ) -> f64 {
+        let volts_per_code = max_voltage * 2f64 / FULL_SCALE;
+        shorted_code * volts_per_code / (max_voltage * 2f64 / reference_voltage + 1f64)
+    }
+
+    /// The same mapping for codes that are each worth `scale` of the codes it was made for, when
+    /// the ADC's range is not the one the front end scales the probes to.
+    pub fn scaled(&self, scale: f64) -> Calibration {
+        Calibration {
+            volts_per_code: (self.volts_per_code as f64 * scale) as f32,
+            offset_volts: self.offset_volts,
+        }
+    }
+
+    pub fn volts(&self, code: u16) -> f64 {
+        code as f64 * self.volts_per_code as f64 + self.offset_volts as f64
+    }
+}
+
+/// Maps ADC codes to volts at the probes along a polynomial of up to the third degree, which can
+/// follow the ADC's bends near the rails where a straight line can't.
+///
+/// The polynomial is in the code divided by 4095, which keeps the coefficients in a sensible
+/// range. The first coefficient is the constant one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProbeCalibration {
+    pub coefficients: [f32; 4],
+}
+
+impl ProbeCalibration {
+    /// The most reference voltages a fit goes by.
+    pub const MAX_REFERENCES: usize = 8;
+
+    pub fn linear(calibration: Calibration) -> ProbeCalibration {
+        ProbeCalibration {
+            coefficients: [
+                calibration.offset_volts,
+                (calibration.volts_per_code as f64 * FULL_SCALE) as f32,
+                0f32,
+                0f32,
+            ],
+        }
+    }
+
+    /// Fits a polynomial through `(code, volts)` references by least squares: a straight line
+    /// through references at two different codes, up to a cubic through four or more.
+    ///
+    /// Returns `None` when the references are all at the same code, or there are none.
+    pub fn fit(references: &[(f64, f64)]) -> Option<ProbeCalibration> {
+        let codes = references
+            .iter()
+            .enumerate()
+            .filter(|(i, (code, _))| references[..*i].iter().all(|(other, _)| other != code))
+            .count();
+        if codes < 2 {
+            return None;
+        }
+        let terms = codes.min(4);
+
+        // The normal equations, with the right hand side as the last column.
+        let mut equations = [[0f64; 5]; 4];
+        for &(code, volts) in references {
+            let x = code / FULL_SCALE;
+            let mut powers = [1f64; 7];
+            for power in 1..powers.len() {
+                powers[power] = powers[power - 1] * x;
+            }
+            for row in 0..terms {
+                for column in 0..terms {
+                    equations[row][column] += powers[row + column];
+                }
+                equations[row][4] += powers[row] * volts;
+            }
+        }
+
+        // Gaussian elimination with partial pivoting.
+        for pivot in 0..terms {
+            let best = (pivot..terms).max_by(|&a, &b| {
+                equations[a][pivot]
+                    .abs()
+                    .total_cmp(&equations[b][pivot].abs())
+            })?;
+            equations.swap(pivot, best);
+            if equations[pivot][pivot].abs() < 1e-12 {
+                return None;
+            }
+            let pivot_row = equations[pivot];
+            for row in &mut equations[pivot + 1..terms] {
+                let factor = row[pivot] / pivot_row[pivot];
+                for (value, pivot_value) in row[pivot..].iter_mut().zip(&pivot_row[pivot..]) {
+                    *value -= factor * pivot_value;
+                }
+            }
+        }
+        let mut coefficients = [0f64; 4];
+        for row in (0..terms).rev() {
+            let known: f64 = (row + 1..terms)
+                .map(|column| equations[row][column] * coefficients[column])
+                .sum();
+            coefficients[row] = (equations[row][4] - known) / equations[row][row];
+        }
+
+        Some(ProbeCalibration {
+            coefficients: coefficients.map(|coefficient| coefficient as f32),
+        })
+    }
+
+    pub fn volts(&self, code: u16) -> f64 {
+        self.volts_at(code as f64)
+    }
+
+    /// Also takes averaged codes, which are not whole.
+    pub fn volts_at(&self, code: f64) -> f64 {
+        let x = code / FULL_SCALE;
+        self.coefficients
+            .iter()
+            .rev()
+            .fold(0f64, |volts, &coefficient| volts * x + coefficient as f64)
+    }
+
+    /// The same curve, moved so `shorted_code` reads as 0 V.
+    pub fn zeroed_at(&self, shorted_code: f64) -> ProbeCalibration {
+        let mut coefficients = self.coefficients;
+        coefficients[0] = (coefficients[0] as f64 - self.volts_at(shorted_code)) as f32;
+        ProbeCalibration { coefficients }
+    }
+}
+
+/// A channel's zero offset, as the ADC sees the shorted probes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZeroOffset {
+    pub probes_shorted_voltage: f32,
+    /// Unix time in seconds, 0 when the scope did not know the time.
+    pub calibrated_at: u32,
+}
+
+/// A curve fitted to reference voltages on the scope.
+///
+/// The curve goes from codes to volts, so it only holds while the ADC is set up the same way as
+/// when it was fitted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FittedCurve {
+    pub curve: ProbeCalibration,
+    /// Unix time in seconds it was fitted or last zeroed at, 0 when the scope did not know the
+    /// time.
+    pub calibrated_at: u32,
+    pub adc: AdcSettings,
+}
+
+/// Writes the zero offsets of the channels that were zeroed, see the module documentation.
+pub fn encode_zero_offsets(offsets: &[Option<ZeroOffset>; CHANNELS]) -> [u8; ZERO_OFFSETS_LENGTH] {
+    let mut record = [0u8; ZERO_OFFSETS_LENGTH];
+    encode_record(&mut record, ZERO_OFFSETS_MAGIC, offsets, |offset, entry| {
+        entry[0..4].copy_from_slice(&offset.probes_shorted_voltage.to_le_bytes());
+        entry[4..8].copy_from_slice(&offset.calibrated_at.to_le_bytes());
+    });
+    record
+}
+
+/// Reads back what `encode_zero_offsets` wrote, `None` for erased flash or a damaged record.
+pub fn decode_zero_offsets(record: &[u8]) -> Option<[Option<ZeroOffset>; CHANNELS]> {
+    decode_record(record, ZERO_OFFSETS_MAGIC, ZERO_OFFSETS_LENGTH, |entry| {
+        ZeroOffset {
+            probes_shorted_voltage: f32::from_bits(word(entry, 0)),
+            calibrated_at: word(entry, 4),
+        }
+    })
+}
+
+/// Writes the curves of the channels that have one, see the module documentation.
+pub fn encode_fits(fits: &[Option<FittedCurve>; CHANNELS]) -> [u8; FITS_LENGTH] {
+    let mut record = [0u8; FITS_LENGTH];
+    encode_record(&mut record, FITS_MAGIC, fits, |fit, entry| {
+        entry[0..4].copy_from_slice(&fit.calibrated_at.to_le_bytes());
+        for (bytes, coefficient) in entry[4..20].chunks_exact_mut(4).zip(fit.curve.coefficients) {
+            bytes.copy_from_slice(&coefficient.to_le_bytes());
+        }
+        entry[20] = fit.adc.to_bits();
+    });
+    record
+}
+
+/// Reads back what `encode_fits` wrote, `None` for erased flash or a damaged record.
+pub fn decode_fits(record: &[u8]) -> Option<[Option<FittedCurve>; CHANNELS]> {
+    let fits = decode_record(record, FITS_MAGIC, FITS_LENGTH, |entry| {
+        AdcSettings::from_bits(entry[20]).map(|adc| FittedCurve {
+            curve: ProbeCalibration {
+                coefficients: core::array::from_fn(|i| f32::from_bits(word(entry, 4 + 4 * i))),
+            },
+            calibrated_at: word(entry, 0),
+            adc,
+        })
+    })?;
+    Some(fits.map(Option::flatten))
+}
+
+const fn record_length(entry_length: usize) -> usize {
+    RECORD_HEADER_LENGTH + entry_length * CHANNELS + 4
+}
+
+/// Writes the header, every channel's entry and the hash. `write_entry` gets the bytes after
+/// the entry's flag and reserved bytes.
+fn encode_record<T>(
+    record: &mut [u8],
+    magic: u32,
+    entries: &[Option<T>; CHANNELS],
+    mut write_entry: impl FnMut(&T, &mut [u8]),
+) {
+    let entry_length = (record.len() - RECORD_HEADER_LENGTH - 4) / CHANNELS;
+    record[0..4].copy_from_slice(&magic.to_le_bytes());
+    record[4] = RECORD_VERSION;
+    for (channel, entry) in entries.iter().enumerate() {
+        let Some(entry) = entry else {
+            continue;
+        };
+        let start = RECORD_HEADER_LENGTH + entry_length * channel;
+        record[start] = 1;
+        write_entry(entry, &mut record[start + 4..start + entry_length]);
+    }
+    let end = record.len() - 4;
+    let hash = fnv1a(&record[..end]);
+    record[end..].copy_from_slice(&hash.to_le_bytes());
+}
+
+fn decode_record<T>(
+    record: &[u8],
+    magic: u32,
+    length: usize,
+    read_entry: impl Fn(&[u8]) -> T,
+) -> Option<[Option<T>; CHANNELS]> {
+    let record = record.get(..length)?;
+    let end = length - 4;
+    if word(record, 0) != magic
+        || record[4] != RECORD_VERSION
+        || word(record, end) != fnv1a(&record[..end])
+    {
+        return None;
+    }
+
+    let entry_length = (end - RECORD_HEADER_LENGTH) / CHANNELS;
+    Some(core::array::from_fn(|channel| {
+        let start = RECORD_HEADER_LENGTH + entry_length * channel;
+        (record[start] == 1).then(|| read_entry(&record[start + 4..start + entry_length]))
+    }))
+}
+
+fn word(bytes: &[u8], start: usize) -> u32 {
+    u32::from_le_bytes(bytes[start..start + 4].try_into().unwrap())
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Averages the codes of every channel, while the probes are shorted or across a reference.
+pub struct CodeAverager {
+    samples: u32,
+    sums: [u64; CHANNELS],
+    counts: [u32; CHANNELS],
+}
+
+impl CodeAverager {
+    /// Averages `samples` codes of every channel.
+    pub fn new(samples: u32) -> CodeAverager {
+        CodeAverager {
+            samples: samples.max(1),
+            sums: [0; CHANNELS],
+            counts: [0; CHANNELS],
+        }
+    }
+
+    pub fn push(&mut self, channel: u8, code: u16) {
+        let channel = channel as usize;
+        if self.counts[channel] < self.samples {
+            self.sums[channel] += code as u64;
+            self.counts[channel] += 1;
+        }
+    }
+
+    /// Whether every enabled channel has had enough codes.
+    pub fn is_done(&self, enabled: &[bool; CHANNELS]) -> bool {
+        enabled
+            .iter()
+            .zip(&self.counts)
+            .all(|(&enabled, &count)| !enabled || count >= self.samples)
+    }
+
+    /// The average code of every channel that got any.
+    pub fn average_codes(&self) -> [Option<f64>; CHANNELS] {
+        core::array::from_fn(|channel| {
+            (self.counts[channel] > 0)
+                .then(|| self.sums[channel] as f64 / self.counts[channel] as f64)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adc::{Attenuation, CalibrationScheme};
+
+    #[test]
+    fn full_scale_spans_twice_the_max_voltage() {
+        let calibration = Calibration::from_voltages(3.3, 0f64, 20f64);
+        assert!(calibration.volts(0).abs() < 1e-6);
+        assert!((calibration.volts(4095) - 40f64).abs() < 1e-4);
+    }
+
+    #[test]
+    fn matches_converting_through_the_adc_voltage() {
+        let (reference_voltage, probes_shorted_voltage, max_voltage) = (3.1, 1.55, 20f64);
+        let calibration =
+            Calibration::from_voltages(reference_voltage, probes_shorted_voltage, max_voltage);
+        for code in [0, 1, 2048, 4095] {
+            let adc_voltage = code as f64 * reference_voltage / 4095f64;
+            let expected = (adc_voltage - probes_shorted_voltage) * max_voltage * 2f64
+                / reference_voltage
+                - probes_shorted_voltage;
+            assert!((calibration.volts(code) - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn zeroing_makes_the_shorted_code_read_zero() {
+        let mut averager = CodeAverager::new(4);
+        for code in [1480, 1490, 1484, 1486, 9999] {
+            averager.push(0, code);
+        }
+        assert!(!averager.is_done(&[true, true]));
+        assert!(averager.is_done(&[true, false]));
+        assert_eq!(averager.average_codes(), [Some(1485f64), None]);
+
+        let probes_shorted_voltage = Calibration::probes_shorted_voltage(3.1, 31f64, 1485f64);
+        let calibration = Calibration::from_voltages(3.1, probes_shorted_voltage, 31f64);
+        assert!(calibration.volts(1485).abs() < 1e-4);
+
+        let curve = ProbeCalibration::linear(calibration).zeroed_at(1000f64);
+        assert!(curve.volts(1000).abs() < 1e-4);
+    }
+
+    #[test]
+    fn straight_lines_stay_straight() {
+        let calibration = Calibration::from_voltages(3.1, 1.1, 31f64);
+        let curve = ProbeCalibration::linear(calibration);
+        for code in [0, 100, 2048, 4095] {
+            assert!((curve.volts(code) - calibration.volts(code)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn curves_are_fitted_through_references() {
+        // An ADC that flattens out near both rails, like the ESP32's does.
+        let volts = |code: f64| {
+            let x = code / 4095f64;
+            -30f64 + 61f64 * x + 4f64 * x * x - 6f64 * x * x * x
+        };
+        let references: [(f64, f64); 6] =
+            [150f64, 800f64, 1600f64, 2400f64, 3300f64, 3950f64].map(|code| (code, volts(code)));
+        let curve = ProbeCalibration::fit(&references).unwrap();
+        for code in (0..=4095).step_by(5) {
+            assert!((curve.volts(code) - volts(code as f64)).abs() < 1e-3);
+        }
+
+        // Two references make a straight line through both.
+        let line = ProbeCalibration::fit(&references[1..3]).unwrap();
+        assert_eq!(line.coefficients[2..], [0f32, 0f32]);
+        for (code, volts) in &references[1..3] {
+            assert!((line.volts_at(*code) - volts).abs() < 1e-3);
+        }
+
+        // Least squares goes between references that don't agree.
+        let noisy = [(0f64, 0.1), (0f64, -0.1), (4095f64, 10.1), (4095f64, 9.9)];
+        let line = ProbeCalibration::fit(&noisy).unwrap();
+        assert!(line.volts(0).abs() < 1e-3);
+        assert!((line.volts(4095) - 10f64).abs() < 1e-3);
+    }
+
+    #[test]
+    fn degenerate_references_are_not_fitted() {
+        assert_eq!(ProbeCalibration::fit(&[]), None);
+        assert_eq!(ProbeCalibration::fit(&[(100f64, 1f64)]), None);
+        assert_eq!(
+            ProbeCalibration::fit(&[(100f64, 1f64), (100f64, 1.5f64)]),
+            None
+        );
+    }
+
+    #[test]
+    fn records_survive_being_stored() {
+        let offsets = [
+            Some(ZeroOffset {
+                probes_shorted_voltage: 1.125,
+                calibrated_at: 1_760_000_000,
+            }),
+            None,
+        ];
+        let mut record = encode_zero_offsets(&offsets);
+        assert_eq!(decode_zero_offsets(&record), Some(offsets));
+
+        assert_eq!(decode_zero_offsets(&[0xff; ZERO_OFFSETS_LENGTH]), None);
+        assert_eq!(decode_zero_offsets(&record[..10]), None);
+        record[12] ^= 1;
+        assert_eq!(decode_zero_offsets(&record), None);
+
+        let fits = [
+            None,
+            Some(FittedCurve {
+                curve: ProbeCalibration {
+                    coefficients: [-30f32, 61f32, 4f32, -6f32],
+                },
+                calibrated_at: 0,
+                adc: AdcSettings {
+                    attenuation: Attenuation::Db6,
+                    scheme: CalibrationScheme::Curve,
+                },
+            }),
+        ];
+        let record = encode_fits(&fits);
+        assert_eq!(decode_fits(&record), Some(fits));
+        assert_eq!(decode_zero_offsets(&record), None);
+    }
+
+    #[test]
+    fn lines_follow_the_adc_range() {
+        // The same voltage at the ADC is a higher code at 6 dB than at 11 dB.
+        let calibration = Calibration::from_voltages(3.1, 1.1, 31f64);
+        let scaled = calibration.scaled(1.75 / 3.1);
+        let code_at_6_db = (2000f64 * 3.1 / 1.75) as u16;
+        assert!((scaled.volts(code_at_6_db) - calibration.volts(2000)).abs() < 0.02);
+    }
+}
@@ -0,0 +1,684 @@
+//! Calibrating the probes on the scope: zero offsets with the probes shorted, and curves fitted
+//! to reference voltages across them.
+//!
+//! A curve is fitted in a few steps. Clients put every reference voltage across the probes in
+//! turn and ask the scope to measure it, then ask for the fit once they have enough.
+//!
+//! Curves only hold for the ADC settings they were fitted with. They are kept when a channel
+//! switches to other settings, and apply again when it switches back. Zero offsets are voltages
+//! at the ADC, so they hold for any settings.
+
+use crate::{
+    adc::AdcSettings,
+    calibration::{Calibration, CodeAverager, FittedCurve, ProbeCalibration, ZeroOffset},
+    websocket_logistics::CHANNELS,
+};
+use core::sync::atomic::{fence, AtomicU32, AtomicU8, Ordering};
+use heapless::Vec;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CalibrationStep {
+    /// Measures the zero offsets of the probes, which have to be shorted. Carries the client's
+    /// unix time in seconds to record with them, 0 when unknown.
+    Zero(u32),
+    /// Measures the given voltage, which has to be across the probes, for the next fit.
+    Reference(f32),
+    /// Fits curves to the references measured so far, with the time like `Zero`.
+    Fit(u32),
+    /// Forgets the fitted curves and the references measured so far.
+    ClearFits,
+}
+
+impl CalibrationStep {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CalibrationStep::Zero(_) => "zero",
+            CalibrationStep::Reference(_) => "reference",
+            CalibrationStep::Fit(_) => "fit",
+            CalibrationStep::ClearFits => "clear",
+        }
+    }
+
+    /// Packs the step into two numbers that fit in atomics, the first one never 0.
+    pub fn to_parts(self) -> (u8, u32) {
+        match self {
+            CalibrationStep::Zero(time) => (1, time),
+            CalibrationStep::Reference(volts) => (2, volts.to_bits()),
+            CalibrationStep::Fit(time) => (3, time),
+            CalibrationStep::ClearFits => (4, 0),
+        }
+    }
+
+    pub fn from_parts(kind: u8, argument: u32) -> Option<CalibrationStep> {
+        match kind {
+            1 => Some(CalibrationStep::Zero(argument)),
+            2 => Some(CalibrationStep::Reference(f32::from_bits(argument))),
+            3 => Some(CalibrationStep::Fit(argument)),
+            4 => Some(CalibrationStep::ClearFits),
+            _ => None,
+        }
+    }
+}
+
+/// Where a channel's calibration comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CalibrationSource {
+    /// The straight line from the front end's voltages in Settings.toml.
+    Settings,
+    /// The same straight line, with a zero offset measured on the scope.
+    Zeroed,
+    /// A curve fitted to reference voltages on the scope.
+    Fitted,
+}
+
+impl CalibrationSource {
+    const ALL: [CalibrationSource; 3] = [
+        CalibrationSource::Settings,
+        CalibrationSource::Zeroed,
+        CalibrationSource::Fitted,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CalibrationSource::Settings => "settings",
+            CalibrationSource::Zeroed => "zeroed",
+            CalibrationSource::Fitted => "fitted",
+        }
+    }
+}
+
+/// A channel's calibration as the scope uses it right now.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelCalibration {
+    pub probe: ProbeCalibration,
+    pub source: CalibrationSource,
+    /// Unix time in seconds the zero offset or curve was measured at, 0 when unknown or from
+    /// Settings.toml.
+    pub calibrated_at: u32,
+    /// Reference voltages measured for the next fit.
+    pub references: u8,
+    /// The ADC settings the calibration holds for.
+    pub adc: AdcSettings,
+}
+
+/// A channel's front end as Settings.toml describes it, see `Calibration::from_voltages`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrontEnd {
+    /// The voltage at the ADC the front end scales `max_voltage` to, the ADC's range at the
+    /// attenuation it was built for.
+    pub reference_voltage: f64,
+    pub probes_shorted_voltage: f64,
+    pub max_voltage: f64,
+}
+
+/// Which of the stored records changed, so they get written again.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Changes {
+    pub zero_offsets: bool,
+    pub fits: bool,
+}
+
+/// Keeps track of everything the channels were calibrated with, and goes through the steps
+/// clients ask for.
+///
+/// A fitted curve wins over a zero offset, which wins over Settings.toml. Zeroing a channel that
+/// has a curve for its ADC settings moves the curve instead.
+pub struct ProbeCalibrator {
+    front_ends: [FrontEnd; CHANNELS],
+    adc: [AdcSettings; CHANNELS],
+    zero_offsets: [Option<ZeroOffset>; CHANNELS],
+    fits: [Option<FittedCurve>; CHANNELS],
+    references: [Vec<(f64, f64), { ProbeCalibration::MAX_REFERENCES }>; CHANNELS],
+    measuring: Option<(CalibrationStep, CodeAverager)>,
+    samples: u32,
+}
+
+impl ProbeCalibrator {
+    /// Starts out with what was stored, and averages `samples` codes for every measurement.
+    pub fn new(
+        front_ends: [FrontEnd; CHANNELS],
+        adc: [AdcSettings; CHANNELS],
+        zero_offsets: [Option<ZeroOffset>; CHANNELS],
+        fits: [Option<FittedCurve>; CHANNELS],
+        samples: u32,
+    ) -> ProbeCalibrator {
+        ProbeCalibrator {
+            front_ends,
+            adc,
+            zero_offsets,
+            fits,
+            references: core::array::from_fn(|_| Vec::new()),
+            measuring: None,
+            samples,
+        }
+    }
+
+    pub fn channels(&self) -> [ChannelCalibration; CHANNELS] {
+        core::array::from_fn(|channel| self.channel_for(channel, self.adc[channel]))
+    }
+
+    /// The curves of every channel for all ADC settings, see `AdcSettings::index`, so codes
+    /// taken before the settings changed still turn into the right volts.
+    pub fn curves(&self) -> [[ProbeCalibration; AdcSettings::COUNT]; CHANNELS] {
+        core::array::from_fn(|channel| {
+            core::array::from_fn(|index| {
+                self.channel_for(channel, AdcSettings::from_index(index))
+                    .probe
+            })
+        })
+    }
+
+    /// The channel's calibration as it would be with `adc`.
+    fn channel_for(&self, channel: usize, adc: AdcSettings) -> ChannelCalibration {
+        let front_end = self.front_ends[channel];
+        let references = self.references[channel].len() as u8;
+        if let Some(fit) = self.fits[channel].filter(|fit| fit.adc == adc) {
+            return ChannelCalibration {
+                probe: fit.curve,
+                source: CalibrationSource::Fitted,
+                calibrated_at: fit.calibrated_at,
+                references,
+                adc,
+            };
+        }
+        let (probes_shorted_voltage, source, calibrated_at) = match self.zero_offsets[channel] {
+            Some(zero) => (
+                zero.probes_shorted_voltage as f64,
+                CalibrationSource::Zeroed,
+                zero.calibrated_at,
+            ),
+            None => (
+                front_end.probes_shorted_voltage,
+                CalibrationSource::Settings,
+                0,
+            ),
+        };
+        ChannelCalibration {
+            probe: ProbeCalibration::linear(
+                Calibration::from_voltages(
+                    front_end.reference_voltage,
+                    probes_shorted_voltage,
+                    front_end.max_voltage,
+                )
+                .scaled(self.code_scale(channel, adc)),
+            ),
+            source,
+            calibrated_at,
+            references,
+            adc,
+        }
+    }
+
+    pub fn adc(&self) -> [AdcSettings; CHANNELS] {
+        self.adc
+    }
+
+    /// Switches channels to other ADC settings. References measured with the old ones are
+    /// forgotten, and a measurement that was going on starts over.
+    pub fn set_adc(&mut self, adc: [AdcSettings; CHANNELS]) {
+        let mut changed = false;
+        for ((old, new), references) in self.adc.iter_mut().zip(adc).zip(&mut self.references) {
+            if *old != new {
+                *old = new;
+                references.clear();
+                changed = true;
+            }
+        }
+        if let (true, Some((_, averager))) = (changed, &mut self.measuring) {
+            *averager = CodeAverager::new(self.samples);
+        }
+    }
+
+    /// The channel's curve, if it has one for its ADC settings.
+    fn fit(&self, channel: usize) -> Option<FittedCurve> {
+        self.fits[channel].filter(|fit| fit.adc == self.adc[channel])
+    }
+
+    /// What one of the channel's codes with `adc` is worth in codes at the front end's reference
+    /// voltage.
+    fn code_scale(&self, channel: usize, adc: AdcSettings) -> f64 {
+        adc.reference_voltage() / self.front_ends[channel].reference_voltage
+    }
+
+    pub fn zero_offsets(&self) -> &[Option<ZeroOffset>; CHANNELS] {
+        &self.zero_offsets
+    }
+
+    pub fn fits(&self) -> &[Option<FittedCurve>; CHANNELS] {
+        &self.fits
+    }
+
+    /// The step whose codes are being averaged, if any.
+    pub fn measuring(&self) -> Option<CalibrationStep> {
+        self.measuring.as_ref().map(|(step, _)| *step)
+    }
+
+    /// Starts a step. Zeroing and references need codes first, see `push`, the others are done
+    /// right away.
+    ///
+    /// Returns `Some` when the calibrations changed.
+    pub fn start(&mut self, step: CalibrationStep) -> Option<Changes> {
+        match step {
+            CalibrationStep::Zero(_) | CalibrationStep::Reference(_) => {
+                self.measuring = Some((step, CodeAverager::new(self.samples)));
+                None
+            }
+            CalibrationStep::Fit(time) => {
+                let mut changes = Changes::default();
+                for ((fit, references), adc) in
+                    self.fits.iter_mut().zip(&mut self.references).zip(self.adc)
+                {
+                    if let Some(curve) = ProbeCalibration::fit(references) {
+                        *fit = Some(FittedCurve {
+                            curve,
+                            calibrated_at: time,
+                            adc,
+                        });
+                        changes.fits = true;
+                    }
+                    references.clear();
+                }
+                Some(changes)
+            }
+            CalibrationStep::ClearFits => {
+                self.fits = [None; CHANNELS];
+                self.references.iter_mut().for_each(Vec::clear);
+                Some(Changes {
+                    zero_offsets: false,
+                    fits: true,
+                })
+            }
+        }
+    }
+
+    /// Takes the next code of a channel while measuring, and finishes the step once every enabled
+    /// channel had enough.
+    ///
+    /// Returns `Some` when the calibrations changed.
+    pub fn push(&mut self, channel: u8, code: u16, enabled: &[bool; CHANNELS]) -> Option<Changes> {
+        let (step, averager) = self.measuring.as_mut()?;
+        averager.push(channel, code);
+        if !averager.is_done(enabled) {
+            return None;
+        }
+
+        let step = *step;
+        let average_codes = averager.average_codes();
+        self.measuring = None;
+        let mut changes = Changes::default();
+        for (channel, average_code) in average_codes.into_iter().enumerate() {
+            let Some(average_code) = average_code else {
+                continue;
+            };
+            match step {
+                CalibrationStep::Zero(time) => match self.fit(channel) {
+                    Some(fit) => {
+                        self.fits[channel] = Some(FittedCurve {
+                            curve: fit.curve.zeroed_at(average_code),
+                            calibrated_at: time,
+                            adc: fit.adc,
+                        });
+                        changes.fits = true;
+                    }
+                    None => {
+                        let front_end = self.front_ends[channel];
+                        self.zero_offsets[channel] = Some(ZeroOffset {
+                            probes_shorted_voltage: Calibration::probes_shorted_voltage(
+                                front_end.reference_voltage,
+                                front_end.max_voltage,
+                                average_code * self.code_scale(channel, self.adc[channel]),
+                            ) as f32,
+                            calibrated_at: time,
+                        });
+                        changes.zero_offsets = true;
+                    }
+                },
+                CalibrationStep::Reference(volts) => {
+                    // Past the limit, references are ignored until the next fit.
+                    let _ = self.references[channel].push((average_code, volts as f64));
+                }
+                CalibrationStep::Fit(_) | CalibrationStep::ClearFits => (),
+            }
+        }
+        Some(changes)
+    }
+}
+
+/// The calibrations the measuring core uses, for the WebSocket servers on the other core.
+///
+/// Only the measuring core changes them. Lives in a static, so the atomics are in internal RAM
+/// where they work across both cores.
+pub struct SharedCalibrations {
+    /// Odd while the channels are being changed, so readers know to try again.
+    generation: AtomicU32,
+    /// The kind of the step being measured, see `CalibrationStep::to_parts`, 0 for none.
+    measuring: AtomicU8,
+    channels: [SharedChannel; CHANNELS],
+}
+
+struct SharedChannel {
+    coefficients: [AtomicU32; 4],
+    source: AtomicU8,
+    calibrated_at: AtomicU32,
+    references: AtomicU8,
+    adc: AtomicU8,
+    /// Every curve's coefficients, see `ProbeCalibrator::curves`.
+    curves: [[AtomicU32; 4]; AdcSettings::COUNT],
+}
+
+impl SharedCalibrations {
+    pub const fn new() -> SharedCalibrations {
+        SharedCalibrations {
+            generation: AtomicU32::new(0),
+            measuring: AtomicU8::new(0),
+            channels: [const {
+                SharedChannel {
+                    coefficients: [const { AtomicU32::new(0) }; 4],
+                    source: AtomicU8::new(CalibrationSource::Settings as u8),
+                    calibrated_at: AtomicU32::new(0),
+                    references: AtomicU8::new(0),
+                    adc: AtomicU8::new(AdcSettings::DEFAULT.to_bits()),
+                    curves: [const { [const { AtomicU32::new(0) }; 4] }; AdcSettings::COUNT],
+                }
+            }; CHANNELS],
+        }
+    }
+
+    /// Changes with every `store` and `set_measuring`, so readers can tell when to look again.
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn store(
+        &self,
+        channels: &[ChannelCalibration; CHANNELS],
+        curves: &[[ProbeCalibration; AdcSettings::COUNT]; CHANNELS],
+    ) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        for ((shared, channel), curves) in self.channels.iter().zip(channels).zip(curves) {
+            for (shared, coefficient) in shared.coefficients.iter().zip(channel.probe.coefficients)
+            {
+                shared.store(coefficient.to_bits(), Ordering::Relaxed);
+            }
+            shared.source.store(channel.source as u8, Ordering::Relaxed);
+            shared
+                .calibrated_at
+                .store(channel.calibrated_at, Ordering::Relaxed);
+            shared
+                .references
+                .store(channel.references, Ordering::Relaxed);
+            shared.adc.store(channel.adc.to_bits(), Ordering::Relaxed);
+            for (shared, curve) in shared.curves.iter().zip(curves) {
+                for (shared, coefficient) in shared.iter().zip(curve.coefficients) {
+                    shared.store(coefficient.to_bits(), Ordering::Relaxed);
+                }
+            }
+        }
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn load(&self) -> [ChannelCalibration; CHANNELS] {
+        self.read(|| {
+            self.channels.each_ref().map(|shared| ChannelCalibration {
+                probe: ProbeCalibration {
+                    coefficients: shared
+                        .coefficients
+                        .each_ref()
+                        .map(|coefficient| f32::from_bits(coefficient.load(Ordering::Relaxed))),
+                },
+                source: CalibrationSource::ALL[shared.source.load(Ordering::Relaxed) as usize],
+                calibrated_at: shared.calibrated_at.load(Ordering::Relaxed),
+                references: shared.references.load(Ordering::Relaxed),
+                adc: AdcSettings::from_bits(shared.adc.load(Ordering::Relaxed))
+                    .unwrap_or(AdcSettings::DEFAULT),
+            })
+        })
+    }
+
+    /// The curves that were stored with the channels.
+    pub fn load_curves(&self) -> [[ProbeCalibration; AdcSettings::COUNT]; CHANNELS] {
+        self.read(|| {
+            self.channels.each_ref().map(|shared| {
+                shared.curves.each_ref().map(|curve| ProbeCalibration {
+                    coefficients: curve
+                        .each_ref()
+                        .map(|coefficient| f32::from_bits(coefficient.load(Ordering::Relaxed))),
+                })
+            })
+        })
+    }
+
+    /// Reads until nothing was stored in the meantime.
+    fn read<T>(&self, read: impl Fn() -> T) -> T {
+        loop {
+            let before = self.generation.load(Ordering::Acquire);
+            let value = read();
+            fence(Ordering::Acquire);
+            if before & 1 == 0 && self.generation.load(Ordering::Relaxed) == before {
+                return value;
+            }
+        }
+    }
+
+    /// The name of the step being measured right now, if any.
+    pub fn measuring(&self) -> Option<&'static str> {
+        CalibrationStep::from_parts(self.measuring.load(Ordering::Relaxed), 0)
+            .map(|step| step.name())
+    }
+
+    pub fn set_measuring(&self, step: Option<CalibrationStep>) {
+        let kind = step.map_or(0, |step| step.to_parts().0);
+        self.measuring.store(kind, Ordering::Relaxed);
+        self.generation.fetch_add(2, Ordering::Release);
+    }
+}
+
+impl Default for SharedCalibrations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adc::{Attenuation, CalibrationScheme};
+
+    const FRONT_END: FrontEnd = FrontEnd {
+        reference_voltage: 3.1,
+        probes_shorted_voltage: 1.1,
+        max_voltage: 31f64,
+    };
+
+    fn calibrator() -> ProbeCalibrator {
+        ProbeCalibrator::new(
+            [FRONT_END; CHANNELS],
+            [AdcSettings::DEFAULT; CHANNELS],
+            [None; CHANNELS],
+            [None; CHANNELS],
+            2,
+        )
+    }
+
+    /// Feeds the same code to both channels until the step is done.
+    fn measure(calibrator: &mut ProbeCalibrator, codes: [u16; CHANNELS]) -> Option<Changes> {
+        for _ in 0..2 {
+            for (channel, code) in codes.into_iter().enumerate() {
+                if let Some(changes) = calibrator.push(channel as u8, code, &[true; CHANNELS]) {
+                    return Some(changes);
+                }
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn zeroing_replaces_the_settings() {
+        let mut calibrator = calibrator();
+        assert_eq!(calibrator.channels()[0].source, CalibrationSource::Settings);
+
+        assert_eq!(calibrator.start(CalibrationStep::Zero(42)), None);
+        assert_eq!(calibrator.measuring(), Some(CalibrationStep::Zero(42)));
+        assert_eq!(
+            measure(&mut calibrator, [1500, 1600]),
+            Some(Changes {
+                zero_offsets: true,
+                fits: false
+            })
+        );
+        assert_eq!(calibrator.measuring(), None);
+
+        let channels = calibrator.channels();
+        assert_eq!(channels[0].source, CalibrationSource::Zeroed);
+        assert_eq!(channels[0].calibrated_at, 42);
+        assert!(channels[0].probe.volts(1500).abs() < 1e-3);
+        assert!(channels[1].probe.volts(1600).abs() < 1e-3);
+        assert!(calibrator.zero_offsets()[1].is_some());
+    }
+
+    #[test]
+    fn curves_are_fitted_to_the_references_and_zeroed_after() {
+        let mut calibrator = calibrator();
+        for (volts, codes) in [
+            (-10f32, [1000, 1100]),
+            (0f32, [2000, 2100]),
+            (10f32, [3000, 3100]),
+        ] {
+            calibrator.start(CalibrationStep::Reference(volts));
+            assert_eq!(measure(&mut calibrator, codes), Some(Changes::default()));
+        }
+        assert_eq!(calibrator.channels()[0].references, 3);
+
+        assert_eq!(
+            calibrator.start(CalibrationStep::Fit(7)),
+            Some(Changes {
+                zero_offsets: false,
+                fits: true
+            })
+        );
+        let channels = calibrator.channels();
+        assert_eq!(channels[0].source, CalibrationSource::Fitted);
+        assert_eq!(channels[0].references, 0);
+        assert!((channels[0].probe.volts(3000) - 10f64).abs() < 1e-3);
+        assert!((channels[1].probe.volts(1100) + 10f64).abs() < 1e-3);
+
+        // Zeroing moves the curve, the zero offsets stay as they were.
+        calibrator.start(CalibrationStep::Zero(8));
+        assert_eq!(
+            measure(&mut calibrator, [2050, 2100]),
+            Some(Changes {
+                zero_offsets: false,
+                fits: true
+            })
+        );
+        let channels = calibrator.channels();
+        assert!(channels[0].probe.volts(2050).abs() < 1e-3);
+        assert_eq!(channels[0].calibrated_at, 8);
+
+        calibrator.start(CalibrationStep::ClearFits);
+        assert_eq!(calibrator.channels()[0].source, CalibrationSource::Settings);
+    }
+
+    #[test]
+    fn curves_only_hold_for_their_adc_settings() {
+        let mut calibrator = calibrator();
+        for (volts, code) in [(-10f32, 1000), (10f32, 3000)] {
+            calibrator.start(CalibrationStep::Reference(volts));
+            measure(&mut calibrator, [code, code]);
+        }
+        calibrator.start(CalibrationStep::Fit(7));
+        let six_db = AdcSettings {
+            attenuation: Attenuation::Db6,
+            scheme: CalibrationScheme::Basic,
+        };
+        let fitted = calibrator.channels()[0].probe;
+        calibrator.set_adc([six_db, AdcSettings::DEFAULT]);
+        let channels = calibrator.channels();
+        assert_eq!(channels[0].source, CalibrationSource::Settings);
+        assert_eq!(channels[0].adc, six_db);
+        assert_eq!(channels[1].source, CalibrationSource::Fitted);
+        // Codes taken before the switch still go by the curve.
+        let curves = calibrator.curves();
+        assert_eq!(curves[0][AdcSettings::DEFAULT.index()], fitted);
+        assert_eq!(curves[0][six_db.index()], channels[0].probe);
+
+        // The same voltage is a higher code at 6 dB, zeroing takes that into account.
+        calibrator.start(CalibrationStep::Zero(8));
+        let shorted = (1500f64 * 3.1 / 1.75) as u16;
+        assert_eq!(
+            measure(&mut calibrator, [shorted, 2000]),
+            Some(Changes {
+                zero_offsets: true,
+                fits: true
+            })
+        );
+        calibrator.set_adc([AdcSettings::DEFAULT; CHANNELS]);
+        let channels = calibrator.channels();
+        assert_eq!(channels[0].source, CalibrationSource::Fitted);
+        assert_eq!(channels[0].calibrated_at, 7);
+        calibrator.start(CalibrationStep::ClearFits);
+        assert!(calibrator.channels()[0].probe.volts(1500).abs() < 1e-2);
+    }
+
+    #[test]
+    fn changing_the_adc_settings_forgets_references() {
+        let mut calibrator = calibrator();
+        calibrator.start(CalibrationStep::Reference(5f32));
+        measure(&mut calibrator, [2500, 2500]);
+        calibrator.start(CalibrationStep::Reference(-5f32));
+        calibrator.push(0, 1000, &[true; CHANNELS]);
+        let mut adc = [AdcSettings::DEFAULT; CHANNELS];
+        adc[1].scheme = CalibrationScheme::Curve;
+        calibrator.set_adc(adc);
+        assert_eq!(calibrator.channels()[0].references, 1);
+        assert_eq!(calibrator.channels()[1].references, 0);
+
+        // The measurement started over, so it takes two codes again.
+        assert_eq!(calibrator.push(0, 1000, &[true, false]), None);
+        assert!(calibrator.push(0, 1000, &[true, false]).is_some());
+    }
+
+    #[test]
+    fn too_few_references_fit_nothing() {
+        let mut calibrator = calibrator();
+        calibrator.start(CalibrationStep::Reference(5f32));
+        measure(&mut calibrator, [2500, 2500]);
+        assert_eq!(
+            calibrator.start(CalibrationStep::Fit(0)),
+            Some(Changes::default())
+        );
+        assert_eq!(calibrator.fits(), &[None; CHANNELS]);
+    }
+
+    #[test]
+    fn steps_survive_the_atomics() {
+        for step in [
+            CalibrationStep::Zero(1_760_000_000),
+            CalibrationStep::Reference(-2.5),
+            CalibrationStep::Fit(3),
+            CalibrationStep::ClearFits,
+        ] {
+            let (kind, argument) = step.to_parts();
+            assert_eq!(CalibrationStep::from_parts(kind, argument), Some(step));
+        }
+        assert_eq!(CalibrationStep::from_parts(0, 0), None);
+    }
+
+    #[test]
+    fn shared_calibrations_are_read_whole() {
+        let shared = SharedCalibrations::new();
+        let calibrator = calibrator();
+        let channels = calibrator.channels();
+        let generation = shared.generation();
+        shared.store(&channels, &calibrator.curves());
+        assert_eq!(shared.load(), channels);
+        assert_eq!(shared.load_curves(), calibrator.curves());
+        assert_ne!(shared.generation(), generation);
+
+        let generation = shared.generation();
+        shared.set_measuring(Some(CalibrationStep::Reference(1f32)));
+        assert_eq!(shared.measuring(), Some("reference"));
+        assert_ne!(shared.generation(), generation);
+        assert_eq!(shared.load(), channels);
+    }
+}
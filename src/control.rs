@@ -0,0 +1,726 @@
+//! Settings clients can change while the scope runs, shared between the cores.
+//!
+//! Clients send plain text commands, one per WebSocket text message, like `mode single`, `run`,
+//! `rate 10000`, `adc 1 6db curve`, `autorange 1 on`, `filter median 5 lowpass 2000`,
+//! `decimation swingdoor 0.05` or `thresholds 0.1 0.3`. Calibrating the probes takes `zero`,
+//! `reference 5.0`, `fit` and `fit clear`.
+
+use crate::{
+    adc::{AdcSettings, Attenuation, CalibrationScheme},
+    calibrator::CalibrationStep,
+    decimation::{DecimationMode, Thresholds},
+    filter::{FilterSettings, FilterStage, MAX_STAGES},
+    websocket_logistics::CHANNELS,
+};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AcquisitionMode {
+    /// Runs freely when no trigger comes along for a while.
+    Auto,
+    /// Only sends triggered frames.
+    Normal,
+    /// Sends one triggered frame, then waits to be run again.
+    Single,
+}
+
+impl AcquisitionMode {
+    const ALL: [AcquisitionMode; 3] = [
+        AcquisitionMode::Auto,
+        AcquisitionMode::Normal,
+        AcquisitionMode::Single,
+    ];
+
+    pub fn from_name(name: &str) -> Option<AcquisitionMode> {
+        Self::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AcquisitionMode::Auto => "auto",
+            AcquisitionMode::Normal => "normal",
+            AcquisitionMode::Single => "single",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AcquisitionState {
+    /// Stopped by a client, nothing is sent until it runs the scope again.
+    Stopped,
+    /// Waiting for a trigger.
+    Armed,
+    /// Sending a triggered frame.
+    Triggered,
+    /// Sending everything, because no trigger came in auto mode.
+    FreeRunning,
+    /// The single frame was sent, nothing more is until the scope is run again.
+    Done,
+}
+
+impl AcquisitionState {
+    const ALL: [AcquisitionState; 5] = [
+        AcquisitionState::Stopped,
+        AcquisitionState::Armed,
+        AcquisitionState::Triggered,
+        AcquisitionState::FreeRunning,
+        AcquisitionState::Done,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AcquisitionState::Stopped => "stopped",
+            AcquisitionState::Armed => "armed",
+            AcquisitionState::Triggered => "triggered",
+            AcquisitionState::FreeRunning => "free_running",
+            AcquisitionState::Done => "done",
+        }
+    }
+}
+
+/// How often the ADC is sampled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleRate {
+    Hz(u32),
+    /// As fast as the sampling backend goes.
+    Max,
+}
+
+impl SampleRate {
+    /// Reads `max` or a whole number of samples per second, 0 also meaning `max`.
+    pub fn parse(text: &str) -> Option<SampleRate> {
+        match text {
+            "max" => Some(SampleRate::Max),
+            _ => text.parse().ok().map(SampleRate::from_hz),
+        }
+    }
+
+    fn from_hz(hz: u32) -> SampleRate {
+        match hz {
+            0 => SampleRate::Max,
+            hz => SampleRate::Hz(hz),
+        }
+    }
+
+    /// The rate in samples per second, `None` for `max`.
+    pub fn hz(&self) -> Option<u32> {
+        match self {
+            SampleRate::Hz(hz) => Some(*hz),
+            SampleRate::Max => None,
+        }
+    }
+}
+
+/// How the sampling is going, as the measuring core reports it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SamplingReport {
+    pub requested: SampleRate,
+    /// Samples per second actually taken, measured over about a second.
+    pub achieved_hz: u32,
+    /// Samples that were not taken or read in time since the scope started.
+    pub overruns: u32,
+    pub adc: [AdcSettings; CHANNELS],
+    pub autorange: [bool; CHANNELS],
+    pub filter: FilterSettings,
+    pub decimation: DecimationMode,
+    /// The thresholds a client picked, `None` while they follow the noise.
+    pub thresholds: Option<Thresholds>,
+    /// The standard deviation of every channel's noise in volts, once it was measured.
+    pub noise_volts: [Option<f32>; CHANNELS],
+    /// The thresholds every channel decimates with.
+    pub used_thresholds: [Thresholds; CHANNELS],
+    /// How many times the decimation's tolerances are doubled because a client falls behind,
+    /// see `Backpressure`.
+    pub coarseness: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Mode(AcquisitionMode),
+    Rate(SampleRate),
+    /// Arms the trigger, also after a single frame or a stop.
+    Run,
+    Stop,
+    /// Sets up the ADC for a channel, counting from 0.
+    Adc(u8, AdcSettings),
+    /// Lets the measuring core pick the attenuation of a channel, counting from 0.
+    AutoRange(u8, bool),
+    /// Filters the codes of every channel before they are decimated.
+    Filter(FilterSettings),
+    /// Picks how every channel's points are thinned out before they are sent.
+    Decimation(DecimationMode),
+    /// Sets the thresholds of the slope mode, `None` to derive them from the noise again.
+    Thresholds(Option<Thresholds>),
+    Calibrate(CalibrationStep),
+}
+
+impl Command {
+    pub fn parse(text: &str) -> Option<Command> {
+        let mut words = text.split_whitespace();
+        let command = match (words.next()?, words.next()) {
+            ("mode", Some(mode)) => Command::Mode(AcquisitionMode::from_name(mode)?),
+            ("rate", Some(rate)) => Command::Rate(SampleRate::parse(rate)?),
+            ("run", None) => Command::Run,
+            ("stop", None) => Command::Stop,
+            ("adc", Some(channel)) => {
+                let channel = channel.parse::<u8>().ok()?.checked_sub(1)?;
+                let settings = AdcSettings {
+                    attenuation: Attenuation::from_name(words.next()?)?,
+                    scheme: CalibrationScheme::from_name(words.next()?)?,
+                };
+                Command::Adc((channel < CHANNELS as u8).then_some(channel)?, settings)
+            }
+            ("autorange", Some(channel)) => {
+                let channel = channel.parse::<u8>().ok()?.checked_sub(1)?;
+                let on = match words.next()? {
+                    "on" => true,
+                    "off" => false,
+                    _ => return None,
+                };
+                Command::AutoRange((channel < CHANNELS as u8).then_some(channel)?, on)
+            }
+            ("filter", Some(_)) => {
+                let stages = text.trim_start().strip_prefix("filter")?;
+                return FilterSettings::parse(stages).map(Command::Filter);
+            }
+            ("decimation", Some(_)) => {
+                let mode = text.trim_start().strip_prefix("decimation")?;
+                return DecimationMode::parse(mode).map(Command::Decimation);
+            }
+            ("thresholds", Some("auto")) => Command::Thresholds(None),
+            ("thresholds", Some(_)) => {
+                let thresholds = text.trim_start().strip_prefix("thresholds")?;
+                return Thresholds::parse(thresholds)
+                    .map(|thresholds| Command::Thresholds(Some(thresholds)));
+            }
+            ("zero", None) => Command::Calibrate(CalibrationStep::Zero(0)),
+            ("zero", Some(time)) => Command::Calibrate(CalibrationStep::Zero(time.parse().ok()?)),
+            ("reference", Some(volts)) => {
+                let volts: f32 = volts.parse().ok()?;
+                Command::Calibrate(CalibrationStep::Reference(
+                    volts.is_finite().then_some(volts)?,
+                ))
+            }
+            ("fit", None) => Command::Calibrate(CalibrationStep::Fit(0)),
+            ("fit", Some("clear")) => Command::Calibrate(CalibrationStep::ClearFits),
+            ("fit", Some(time)) => Command::Calibrate(CalibrationStep::Fit(time.parse().ok()?)),
+            _ => return None,
+        };
+        words.next().is_none().then_some(command)
+    }
+}
+
+/// Where clients leave their commands for the measuring core, and where it reports back.
+///
+/// Lives in a static, so the atomics are in internal RAM where they work across both cores.
+pub struct AcquisitionControl {
+    mode: AtomicU8,
+    state: AtomicU8,
+    run_requested: AtomicBool,
+    stop_requested: AtomicBool,
+    sample_rate_hz: AtomicU32,
+    rate_requested: AtomicBool,
+    achieved_rate_hz: AtomicU32,
+    overruns: AtomicU32,
+    /// Every channel's settings, see `AdcSettings::to_bits`.
+    adc: [AtomicU8; CHANNELS],
+    adc_requested: AtomicBool,
+    autorange: [AtomicBool; CHANNELS],
+    /// Every stage of the filter chain, see `FilterStage::to_bits`, 0 for none.
+    filter: [AtomicU32; MAX_STAGES],
+    filter_requested: AtomicBool,
+    /// The decimation mode, see `DecimationMode::to_parts`.
+    decimation: AtomicU8,
+    decimation_argument: AtomicU32,
+    decimation_requested: AtomicBool,
+    /// The thresholds a client picked as the bits of `f32`s, tolerance factor first, unless
+    /// they follow the noise.
+    thresholds: [AtomicU32; 2],
+    thresholds_follow_noise: AtomicBool,
+    thresholds_requested: AtomicBool,
+    /// What the measuring core found, as the bits of `f32`s, NaN for not yet.
+    noise_volts: [AtomicU32; CHANNELS],
+    used_thresholds: [[AtomicU32; 2]; CHANNELS],
+    coarseness: AtomicU8,
+    /// The kind of calibration step asked for, see `CalibrationStep::to_parts`, 0 for none.
+    calibration_requested: AtomicU8,
+    calibration_argument: AtomicU32,
+}
+
+impl AcquisitionControl {
+    pub const fn new() -> AcquisitionControl {
+        AcquisitionControl {
+            mode: AtomicU8::new(AcquisitionMode::Auto as u8),
+            state: AtomicU8::new(AcquisitionState::Armed as u8),
+            run_requested: AtomicBool::new(false),
+            stop_requested: AtomicBool::new(false),
+            sample_rate_hz: AtomicU32::new(0),
+            rate_requested: AtomicBool::new(false),
+            achieved_rate_hz: AtomicU32::new(0),
+            overruns: AtomicU32::new(0),
+            adc: [const { AtomicU8::new(AdcSettings::DEFAULT.to_bits()) }; CHANNELS],
+            adc_requested: AtomicBool::new(false),
+            autorange: [const { AtomicBool::new(false) }; CHANNELS],
+            filter: [const { AtomicU32::new(0) }; MAX_STAGES],
+            filter_requested: AtomicBool::new(false),
+            decimation: AtomicU8::new(0),
+            decimation_argument: AtomicU32::new(0),
+            decimation_requested: AtomicBool::new(false),
+            thresholds: [const { AtomicU32::new(0) }; 2],
+            thresholds_follow_noise: AtomicBool::new(true),
+            thresholds_requested: AtomicBool::new(false),
+            noise_volts: [const { AtomicU32::new(f32::NAN.to_bits()) }; CHANNELS],
+            used_thresholds: [const { [const { AtomicU32::new(0) }; 2] }; CHANNELS],
+            coarseness: AtomicU8::new(0),
+            calibration_requested: AtomicU8::new(0),
+            calibration_argument: AtomicU32::new(0),
+        }
+    }
+
+    pub fn apply(&self, command: Command) {
+        match command {
+            Command::Mode(mode) => self.mode.store(mode as u8, Ordering::Relaxed),
+            Command::Run => self.run_requested.store(true, Ordering::Relaxed),
+            Command::Stop => self.stop_requested.store(true, Ordering::Relaxed),
+            Command::Rate(rate) => {
+                self.sample_rate_hz
+                    .store(rate.hz().unwrap_or(0), Ordering::Relaxed);
+                self.rate_requested.store(true, Ordering::Release);
+            }
+            Command::Adc(channel, settings) => {
+                self.adc[channel as usize].store(settings.to_bits(), Ordering::Relaxed);
+                self.adc_requested.store(true, Ordering::Release);
+            }
+            Command::AutoRange(channel, on) => {
+                self.autorange[channel as usize].store(on, Ordering::Relaxed)
+            }
+            Command::Filter(settings) => {
+                for (shared, stage) in self.filter.iter().zip(settings.stages) {
+                    shared.store(stage.map_or(0, FilterStage::to_bits), Ordering::Relaxed);
+                }
+                self.filter_requested.store(true, Ordering::Release);
+            }
+            Command::Decimation(mode) => {
+                let (kind, argument) = mode.to_parts();
+                self.decimation_argument.store(argument, Ordering::Relaxed);
+                self.decimation.store(kind, Ordering::Relaxed);
+                self.decimation_requested.store(true, Ordering::Release);
+            }
+            Command::Thresholds(thresholds) => {
+                if let Some(thresholds) = thresholds {
+                    store_thresholds(&self.thresholds, thresholds);
+                }
+                self.thresholds_follow_noise
+                    .store(thresholds.is_none(), Ordering::Relaxed);
+                self.thresholds_requested.store(true, Ordering::Release);
+            }
+            Command::Calibrate(step) => {
+                let (kind, argument) = step.to_parts();
+                self.calibration_argument.store(argument, Ordering::Relaxed);
+                self.calibration_requested.store(kind, Ordering::Release);
+            }
+        }
+    }
+
+    pub fn mode(&self) -> AcquisitionMode {
+        AcquisitionMode::ALL[self.mode.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn state(&self) -> AcquisitionState {
+        AcquisitionState::ALL[self.state.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn set_state(&self, state: AcquisitionState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    /// Whether a client asked to run the scope since the last call.
+    pub fn take_run_request(&self) -> bool {
+        self.run_requested.swap(false, Ordering::Relaxed)
+    }
+
+    /// Whether a client asked to stop the scope since the last call.
+    pub fn take_stop_request(&self) -> bool {
+        self.stop_requested.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn sample_rate(&self) -> SampleRate {
+        SampleRate::from_hz(self.sample_rate_hz.load(Ordering::Relaxed))
+    }
+
+    /// The sample rate a client asked for since the last call, if any.
+    pub fn take_rate_request(&self) -> Option<SampleRate> {
+        self.rate_requested
+            .swap(false, Ordering::Acquire)
+            .then(|| self.sample_rate())
+    }
+
+    pub fn adc_settings(&self) -> [AdcSettings; CHANNELS] {
+        self.adc.each_ref().map(|bits| {
+            AdcSettings::from_bits(bits.load(Ordering::Relaxed)).unwrap_or(AdcSettings::DEFAULT)
+        })
+    }
+
+    /// The ADC settings of every channel, if a client changed any since the last call.
+    pub fn take_adc_request(&self) -> Option<[AdcSettings; CHANNELS]> {
+        self.adc_requested
+            .swap(false, Ordering::Acquire)
+            .then(|| self.adc_settings())
+    }
+
+    /// Which channels the measuring core picks the attenuation for.
+    pub fn autorange(&self) -> [bool; CHANNELS] {
+        self.autorange
+            .each_ref()
+            .map(|on| on.load(Ordering::Relaxed))
+    }
+
+    pub fn filter_settings(&self) -> FilterSettings {
+        FilterSettings {
+            stages: self
+                .filter
+                .each_ref()
+                .map(|bits| FilterStage::from_bits(bits.load(Ordering::Relaxed))),
+        }
+    }
+
+    /// The filter chain a client asked for since the last call, if any.
+    pub fn take_filter_request(&self) -> Option<FilterSettings> {
+        self.filter_requested
+            .swap(false, Ordering::Acquire)
+            .then(|| self.filter_settings())
+    }
+
+    pub fn decimation_mode(&self) -> DecimationMode {
+        DecimationMode::from_parts(
+            self.decimation.load(Ordering::Relaxed),
+            self.decimation_argument.load(Ordering::Relaxed),
+        )
+        .unwrap_or(DecimationMode::Slope)
+    }
+
+    /// The decimation mode a client asked for since the last call, if any.
+    pub fn take_decimation_request(&self) -> Option<DecimationMode> {
+        self.decimation_requested
+            .swap(false, Ordering::Acquire)
+            .then(|| self.decimation_mode())
+    }
+
+    /// The thresholds a client picked, `None` while they follow the noise.
+    pub fn thresholds(&self) -> Option<Thresholds> {
+        (!self.thresholds_follow_noise.load(Ordering::Relaxed))
+            .then(|| load_thresholds(&self.thresholds))
+    }
+
+    /// The thresholds a client asked for since the last call, if any.
+    pub fn take_thresholds_request(&self) -> Option<Option<Thresholds>> {
+        self.thresholds_requested
+            .swap(false, Ordering::Acquire)
+            .then(|| self.thresholds())
+    }
+
+    /// Tells clients how noisy a channel is and what it decimates with.
+    pub fn report_thresholds(&self, channel: u8, noise_volts: Option<f64>, used: Thresholds) {
+        let noise_volts = noise_volts.map_or(f32::NAN, |volts| volts as f32);
+        self.noise_volts[channel as usize].store(noise_volts.to_bits(), Ordering::Relaxed);
+        store_thresholds(&self.used_thresholds[channel as usize], used);
+    }
+
+    /// Tells clients how much coarser the points are decimated to keep up with them.
+    pub fn report_coarseness(&self, level: u8) {
+        self.coarseness.store(level, Ordering::Relaxed);
+    }
+
+    /// The calibration step a client asked for since the last call, if any. Only the last one
+    /// counts when several come in at once.
+    pub fn take_calibration_request(&self) -> Option<CalibrationStep> {
+        let kind = self.calibration_requested.swap(0, Ordering::Acquire);
+        CalibrationStep::from_parts(kind, self.calibration_argument.load(Ordering::Relaxed))
+    }
+
+    pub fn report_sampling(&self, achieved_hz: u32, overruns: u32) {
+        self.achieved_rate_hz.store(achieved_hz, Ordering::Relaxed);
+        self.overruns.store(overruns, Ordering::Relaxed);
+    }
+
+    pub fn sampling(&self) -> SamplingReport {
+        SamplingReport {
+            requested: self.sample_rate(),
+            achieved_hz: self.achieved_rate_hz.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+            adc: self.adc_settings(),
+            autorange: self.autorange(),
+            filter: self.filter_settings(),
+            decimation: self.decimation_mode(),
+            thresholds: self.thresholds(),
+            noise_volts: self.noise_volts.each_ref().map(|bits| {
+                let volts = f32::from_bits(bits.load(Ordering::Relaxed));
+                (!volts.is_nan()).then_some(volts)
+            }),
+            used_thresholds: self.used_thresholds.each_ref().map(load_thresholds),
+            coarseness: self.coarseness.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn store_thresholds(shared: &[AtomicU32; 2], thresholds: Thresholds) {
+    let [tolerance_factor, min_voltage_difference] = shared;
+    tolerance_factor.store(
+        (thresholds.tolerance_factor as f32).to_bits(),
+        Ordering::Relaxed,
+    );
+    min_voltage_difference.store(
+        (thresholds.min_voltage_difference as f32).to_bits(),
+        Ordering::Relaxed,
+    );
+}
+
+fn load_thresholds(shared: &[AtomicU32; 2]) -> Thresholds {
+    let [tolerance_factor, min_voltage_difference] = shared
+        .each_ref()
+        .map(|bits| f32::from_bits(bits.load(Ordering::Relaxed)) as f64);
+    Thresholds {
+        tolerance_factor,
+        min_voltage_difference,
+    }
+}
+
+impl Default for AcquisitionControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            Command::parse("mode single"),
+            Some(Command::Mode(AcquisitionMode::Single))
+        );
+        assert_eq!(Command::parse(" run "), Some(Command::Run));
+        assert_eq!(Command::parse("stop"), Some(Command::Stop));
+        assert_eq!(
+            Command::parse("rate 10000"),
+            Some(Command::Rate(SampleRate::Hz(10_000)))
+        );
+        assert_eq!(
+            Command::parse("rate max"),
+            Some(Command::Rate(SampleRate::Max))
+        );
+        assert_eq!(
+            Command::parse("zero"),
+            Some(Command::Calibrate(CalibrationStep::Zero(0)))
+        );
+        assert_eq!(
+            Command::parse("zero 1760000000"),
+            Some(Command::Calibrate(CalibrationStep::Zero(1_760_000_000)))
+        );
+        assert_eq!(Command::parse("zero yesterday"), None);
+        assert_eq!(
+            Command::parse("reference -2.5"),
+            Some(Command::Calibrate(CalibrationStep::Reference(-2.5)))
+        );
+        assert_eq!(Command::parse("reference NaN"), None);
+        assert_eq!(
+            Command::parse("fit clear"),
+            Some(Command::Calibrate(CalibrationStep::ClearFits))
+        );
+        assert_eq!(
+            Command::parse("fit 5"),
+            Some(Command::Calibrate(CalibrationStep::Fit(5)))
+        );
+        assert_eq!(
+            Command::parse("adc 2 2.5db line"),
+            Some(Command::Adc(
+                1,
+                AdcSettings {
+                    attenuation: Attenuation::Db2_5,
+                    scheme: CalibrationScheme::Line
+                }
+            ))
+        );
+        assert_eq!(Command::parse("adc 0 6db basic"), None);
+        assert_eq!(Command::parse("adc 3 6db basic"), None);
+        assert_eq!(Command::parse("adc 1 6db"), None);
+        assert_eq!(Command::parse("adc 1 6db basic now"), None);
+        assert_eq!(
+            Command::parse("autorange 2 on"),
+            Some(Command::AutoRange(1, true))
+        );
+        assert_eq!(
+            Command::parse("autorange 1 off"),
+            Some(Command::AutoRange(0, false))
+        );
+        assert_eq!(Command::parse("autorange 3 on"), None);
+        assert_eq!(Command::parse("autorange 1 maybe"), None);
+        assert_eq!(
+            Command::parse("filter median 5 lowpass 2000"),
+            Some(Command::Filter(
+                FilterSettings::parse("median 5 lowpass 2000").unwrap()
+            ))
+        );
+        assert_eq!(
+            Command::parse("filter off"),
+            Some(Command::Filter(FilterSettings::OFF))
+        );
+        assert_eq!(Command::parse("filter median"), None);
+        assert_eq!(Command::parse("filter"), None);
+        assert_eq!(
+            Command::parse("decimation peak 1000"),
+            Some(Command::Decimation(DecimationMode::PeakDetect(1000)))
+        );
+        assert_eq!(
+            Command::parse("decimation slope"),
+            Some(Command::Decimation(DecimationMode::Slope))
+        );
+        assert_eq!(
+            Command::parse("decimation swingdoor 0.05"),
+            Some(Command::Decimation(DecimationMode::SwingDoor(0.05)))
+        );
+        assert_eq!(Command::parse("decimation peak"), None);
+        assert_eq!(
+            Command::parse("thresholds auto"),
+            Some(Command::Thresholds(None))
+        );
+        assert_eq!(
+            Command::parse("thresholds 0.5 0.25"),
+            Some(Command::Thresholds(Some(Thresholds {
+                tolerance_factor: 0.5,
+                min_voltage_difference: 0.25
+            })))
+        );
+        assert_eq!(Command::parse("thresholds 0.5"), None);
+        assert_eq!(Command::parse("rate fast"), None);
+        assert_eq!(Command::parse("mode"), None);
+        assert_eq!(Command::parse("mode sometimes"), None);
+        assert_eq!(Command::parse("stop now"), None);
+        assert_eq!(Command::parse(""), None);
+    }
+
+    #[test]
+    fn requests_are_taken_once() {
+        let control = AcquisitionControl::new();
+        control.apply(Command::Run);
+        control.apply(Command::Mode(AcquisitionMode::Normal));
+        assert_eq!(control.mode(), AcquisitionMode::Normal);
+        assert!(control.take_run_request());
+        assert!(!control.take_run_request());
+        assert!(!control.take_stop_request());
+
+        assert_eq!(control.take_calibration_request(), None);
+        control.apply(Command::Calibrate(CalibrationStep::Zero(1_760_000_000)));
+        assert_eq!(
+            control.take_calibration_request(),
+            Some(CalibrationStep::Zero(1_760_000_000))
+        );
+        assert_eq!(control.take_calibration_request(), None);
+
+        control.set_state(AcquisitionState::Done);
+        assert_eq!(control.state(), AcquisitionState::Done);
+    }
+
+    #[test]
+    fn rate_requests_are_taken_once() {
+        let control = AcquisitionControl::new();
+        assert_eq!(control.take_rate_request(), None);
+        control.apply(Command::Rate(SampleRate::Hz(1000)));
+        assert_eq!(control.take_rate_request(), Some(SampleRate::Hz(1000)));
+        assert_eq!(control.take_rate_request(), None);
+        assert_eq!(control.sample_rate(), SampleRate::Hz(1000));
+
+        control.report_sampling(998, 3);
+        control.report_coarseness(2);
+        assert_eq!(
+            control.sampling(),
+            SamplingReport {
+                requested: SampleRate::Hz(1000),
+                achieved_hz: 998,
+                overruns: 3,
+                adc: [AdcSettings::DEFAULT; CHANNELS],
+                autorange: [false; CHANNELS],
+                filter: FilterSettings::OFF,
+                decimation: DecimationMode::Slope,
+                thresholds: None,
+                noise_volts: [None; CHANNELS],
+                used_thresholds: [Thresholds {
+                    tolerance_factor: 0f64,
+                    min_voltage_difference: 0f64
+                }; CHANNELS],
+                coarseness: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn adc_requests_are_taken_once() {
+        let control = AcquisitionControl::new();
+        assert_eq!(control.take_adc_request(), None);
+        let six_db = AdcSettings {
+            attenuation: Attenuation::Db6,
+            scheme: CalibrationScheme::Curve,
+        };
+        control.apply(Command::Adc(1, six_db));
+        assert_eq!(
+            control.take_adc_request(),
+            Some([AdcSettings::DEFAULT, six_db])
+        );
+        assert_eq!(control.take_adc_request(), None);
+
+        control.apply(Command::AutoRange(0, true));
+        assert_eq!(control.autorange(), [true, false]);
+        assert_eq!(control.take_adc_request(), None);
+    }
+
+    #[test]
+    fn filter_requests_are_taken_once() {
+        let control = AcquisitionControl::new();
+        assert_eq!(control.take_filter_request(), None);
+        let settings = FilterSettings::parse("oversample 2 biquad 500").unwrap();
+        control.apply(Command::Filter(settings));
+        assert_eq!(control.take_filter_request(), Some(settings));
+        assert_eq!(control.take_filter_request(), None);
+        control.apply(Command::Filter(FilterSettings::OFF));
+        assert_eq!(control.take_filter_request(), Some(FilterSettings::OFF));
+    }
+
+    #[test]
+    fn decimation_requests_are_taken_once() {
+        let control = AcquisitionControl::new();
+        assert_eq!(control.take_decimation_request(), None);
+        control.apply(Command::Decimation(DecimationMode::PeakDetect(500)));
+        assert_eq!(
+            control.take_decimation_request(),
+            Some(DecimationMode::PeakDetect(500))
+        );
+        assert_eq!(control.take_decimation_request(), None);
+        assert_eq!(
+            control.sampling().decimation,
+            DecimationMode::PeakDetect(500)
+        );
+    }
+
+    #[test]
+    fn thresholds_follow_the_noise_until_a_client_picks_them() {
+        let control = AcquisitionControl::new();
+        assert_eq!(control.take_thresholds_request(), None);
+        assert_eq!(control.thresholds(), None);
+        let picked = Thresholds {
+            tolerance_factor: 0.5,
+            min_voltage_difference: 0.25,
+        };
+        control.apply(Command::Thresholds(Some(picked)));
+        assert_eq!(control.take_thresholds_request(), Some(Some(picked)));
+        assert_eq!(control.take_thresholds_request(), None);
+        control.apply(Command::Thresholds(None));
+        assert_eq!(control.take_thresholds_request(), Some(None));
+
+        control.report_thresholds(1, Some(0.5), picked);
+        let sampling = control.sampling();
+        assert_eq!(sampling.noise_volts, [None, Some(0.5)]);
+        assert_eq!(sampling.used_thresholds[1], picked);
+    }
+}
@@ -0,0 +1,893 @@
+//! Deciding which measured points are worth sending.
+
+use crate::websocket_logistics::OscilliscopePoint;
+use core::fmt;
+use libm::fabs;
+
+/// How a `Decimator` picks the points it keeps. Clients pick it while the scope runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecimationMode {
+    /// Drops points that lie on the line between their neighbours, see
+    /// `is_middle_point_removable_complicated`. Follows the shape of the signal with few points,
+    /// but can take a narrow spike for noise.
+    Slope,
+    /// Keeps the lowest and the highest point of every bucket of that many microseconds, so even
+    /// the shortest spike survives. Buckets are no longer than half the longest gap, so points
+    /// of two buckets in a row are never further apart than that.
+    PeakDetect(u32),
+    /// Keeps as few points as it can while the lines between them stay within that many volts
+    /// of every point left out, like a swing door.
+    SwingDoor(f32),
+}
+
+impl DecimationMode {
+    /// Reads `slope`, `peak` with the bucket length in microseconds, or `swingdoor` with the
+    /// largest error in volts.
+    pub fn parse(text: &str) -> Option<DecimationMode> {
+        let mut words = text.split_whitespace();
+        let mode = match (words.next()?, words.next()) {
+            ("slope", None) => DecimationMode::Slope,
+            ("peak", Some(bucket_us)) => {
+                let bucket_us = bucket_us.parse().ok()?;
+                DecimationMode::PeakDetect((bucket_us > 0).then_some(bucket_us)?)
+            }
+            ("swingdoor", Some(volts)) => {
+                let volts: f32 = volts.parse().ok()?;
+                DecimationMode::SwingDoor((volts.is_finite() && volts > 0f32).then_some(volts)?)
+            }
+            _ => return None,
+        };
+        words.next().is_none().then_some(mode)
+    }
+
+    /// Packs the mode into a kind, 0 for slope, and its argument, for atomics. The swing door's
+    /// volts go in as the bits of the `f32`.
+    pub fn to_parts(self) -> (u8, u32) {
+        match self {
+            DecimationMode::Slope => (0, 0),
+            DecimationMode::PeakDetect(bucket_us) => (1, bucket_us),
+            DecimationMode::SwingDoor(volts) => (2, volts.to_bits()),
+        }
+    }
+
+    pub fn from_parts(kind: u8, argument: u32) -> Option<DecimationMode> {
+        match kind {
+            0 => Some(DecimationMode::Slope),
+            1 => Some(DecimationMode::PeakDetect(argument.max(1))),
+            2 => Some(DecimationMode::SwingDoor(f32::from_bits(argument))),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DecimationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecimationMode::Slope => f.write_str("slope"),
+            DecimationMode::PeakDetect(bucket_us) => write!(f, "peak {bucket_us}"),
+            DecimationMode::SwingDoor(volts) => write!(f, "swingdoor {volts}"),
+        }
+    }
+}
+
+/// What the slope mode goes by, see `is_middle_point_removable_complicated`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    /// Filters points from straight lines. Higher value <=> less points.
+    pub tolerance_factor: f64,
+    /// Needed between two points, for the one between them to be kept.
+    pub min_voltage_difference: f64,
+}
+
+impl Thresholds {
+    /// Reads the tolerance factor, from 0 to 1, and the minimum voltage difference.
+    pub fn parse(text: &str) -> Option<Thresholds> {
+        let mut words = text.split_whitespace();
+        let tolerance_factor: f64 = words.next()?.parse().ok()?;
+        let min_voltage_difference: f64 = words.next()?.parse().ok()?;
+        (words.next().is_none()
+            && (0f64..=1f64).contains(&tolerance_factor)
+            && min_voltage_difference.is_finite()
+            && min_voltage_difference >= 0f64)
+            .then_some(Thresholds {
+                tolerance_factor,
+                min_voltage_difference,
+            })
+    }
+
+    /// The thresholds for keeping about half as many points with every `level`, see
+    /// `Decimator::set_coarseness`.
+    pub fn coarsened(self, level: u8) -> Thresholds {
+        let scale = (1u32 << level) as f64;
+        Thresholds {
+            tolerance_factor: (self.tolerance_factor * scale).min(1f64),
+            min_voltage_difference: self.min_voltage_difference * scale,
+        }
+    }
+}
+
+/// Written as `f32`s, which is how precisely the cores share them.
+impl fmt::Display for Thresholds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.tolerance_factor as f32, self.min_voltage_difference as f32
+        )
+    }
+}
+
+/// Keeps the points worth sending of one channel, the way its `DecimationMode` says.
+///
+/// Trigger points are always kept, and so are the points on either side of an ADC range change,
+/// so the trace shows where the range changed. Kept points are numbered, so readers further down
+/// can tell when some went missing.
+///
+/// While points are held back, a heartbeat goes out every so often, so readers can follow the
+/// signal up to the newest point without every point being sent. The points kept after it are
+/// what the signal really did: peak detection keeps a bucket's points when the bucket is full,
+/// and those can be older than the heartbeat.
+pub struct Decimator {
+    state: ModeState,
+    next_index: u32,
+    thresholds: Thresholds,
+    /// How many times the tolerances are doubled.
+    coarseness: u8,
+    /// Longest stretch between two kept points, even when the signal is perfectly flat.
+    max_gap_us: u64,
+    /// Longest stretch without a kept point or a heartbeat, 0 for no heartbeats.
+    heartbeat_us: u64,
+    last_sent_us: u64,
+}
+
+enum ModeState {
+    /// Every point is held back until the next one arrives, since that one decides whether it
+    /// was needed. The first point is kept right away, the line starts from it.
+    Slope {
+        before_last: Option<OscilliscopePoint>,
+        last: Option<OscilliscopePoint>,
+    },
+    PeakDetect {
+        bucket_us: u32,
+        bucket: Option<Bucket>,
+    },
+    SwingDoor(SwingDoor),
+}
+
+/// The slopes a line from the last kept point may have, to pass within `max_error` of every
+/// point since.
+///
+/// A new point that the line from the last kept point to it would leave a point since too far
+/// from closes the door: the point before it is kept, and the door opens again from there. Kept
+/// points are points that were measured, so the bound holds for their voltages. The codes sent
+/// for them are rounded, which adds up to half a code.
+struct SwingDoor {
+    max_error: f64,
+    anchor: Option<OscilliscopePoint>,
+    /// The newest point, kept if the one after it does not fit.
+    held: Option<OscilliscopePoint>,
+    lowest_slope: f64,
+    highest_slope: f64,
+}
+
+/// The points of a bucket worth keeping so far.
+struct Bucket {
+    number: u64,
+    lowest: OscilliscopePoint,
+    highest: OscilliscopePoint,
+    trigger: Option<OscilliscopePoint>,
+}
+
+impl ModeState {
+    fn new(mode: DecimationMode) -> ModeState {
+        match mode {
+            DecimationMode::Slope => ModeState::Slope {
+                before_last: None,
+                last: None,
+            },
+            DecimationMode::PeakDetect(bucket_us) => ModeState::PeakDetect {
+                bucket_us,
+                bucket: None,
+            },
+            DecimationMode::SwingDoor(volts) => ModeState::SwingDoor(SwingDoor {
+                max_error: volts as f64,
+                anchor: None,
+                held: None,
+                lowest_slope: f64::NEG_INFINITY,
+                highest_slope: f64::INFINITY,
+            }),
+        }
+    }
+}
+
+impl Decimator {
+    /// Decimates the points of one channel.
+    pub fn new(
+        mode: DecimationMode,
+        thresholds: Thresholds,
+        max_gap_us: u64,
+        heartbeat_us: u64,
+    ) -> Decimator {
+        Decimator {
+            state: ModeState::new(mode),
+            next_index: 0,
+            thresholds,
+            coarseness: 0,
+            max_gap_us,
+            heartbeat_us,
+            last_sent_us: 0,
+        }
+    }
+
+    /// Switches to another mode. Points held back for the old one are dropped, the numbering
+    /// goes on.
+    pub fn set_mode(&mut self, mode: DecimationMode) {
+        self.state = ModeState::new(mode);
+    }
+
+    /// Changes what the slope mode goes by, from the next point on.
+    pub fn set_thresholds(&mut self, thresholds: Thresholds) {
+        self.thresholds = thresholds;
+    }
+
+    /// Doubles every mode's tolerances `level` times, for when the points can't all be sent:
+    /// the thresholds of the slope mode, the peak detection's buckets and the swing door's
+    /// error.
+    pub fn set_coarseness(&mut self, level: u8) {
+        self.coarseness = level;
+    }
+
+    /// Takes the newest point, and hands the points that turned out to be worth keeping to
+    /// `keep`, oldest first, followed by a heartbeat if one is due.
+    pub fn push(&mut self, new_point: OscilliscopePoint, mut keep: impl FnMut(OscilliscopePoint)) {
+        let Decimator {
+            state,
+            next_index,
+            thresholds,
+            coarseness,
+            max_gap_us,
+            heartbeat_us,
+            last_sent_us,
+            ..
+        } = self;
+        let mut number = |mut point: OscilliscopePoint| {
+            point.index = *next_index;
+            *next_index = next_index.wrapping_add(1);
+            *last_sent_us = point.microsecond;
+            point
+        };
+
+        let holding = match state {
+            ModeState::Slope { before_last, last } => match (*before_last, last.replace(new_point))
+            {
+                (Some(before), Some(held)) => {
+                    let thresholds = thresholds.coarsened(*coarseness);
+                    let time_difference = held.microsecond - before.microsecond;
+                    if time_difference > *max_gap_us
+                        || held.trigger
+                        || held.adc != before.adc
+                        || new_point.adc != held.adc
+                        || !is_middle_point_removable_complicated(
+                            &before,
+                            &held,
+                            &new_point,
+                            thresholds.tolerance_factor,
+                            thresholds.min_voltage_difference,
+                        )
+                    {
+                        let held = number(held);
+                        *before_last = Some(held);
+                        keep(held);
+                    }
+                    true
+                }
+                (Some(_), None) => true,
+                (None, _) => {
+                    let first = number(new_point);
+                    *before_last = Some(first);
+                    *last = None;
+                    keep(first);
+                    false
+                }
+            },
+            ModeState::PeakDetect { bucket_us, bucket } => {
+                let bucket_length =
+                    ((*bucket_us as u64) << *coarseness).min((*max_gap_us / 2).max(1));
+                let number_of_bucket = new_point.microsecond / bucket_length;
+                if let Some(full) = bucket.take_if(|bucket| {
+                    bucket.number != number_of_bucket || bucket.lowest.adc != new_point.adc
+                }) {
+                    full.empty(|point| keep(number(point)));
+                }
+                let bucket = bucket.get_or_insert(Bucket {
+                    number: number_of_bucket,
+                    lowest: new_point,
+                    highest: new_point,
+                    trigger: None,
+                });
+                if new_point.voltage < bucket.lowest.voltage {
+                    bucket.lowest = new_point;
+                }
+                if new_point.voltage > bucket.highest.voltage {
+                    bucket.highest = new_point;
+                }
+                if new_point.trigger && bucket.trigger.is_none() {
+                    bucket.trigger = Some(new_point);
+                }
+                true
+            }
+            ModeState::SwingDoor(door) => {
+                let max_error = door.max_error * (1u32 << *coarseness) as f64;
+                door.push(new_point, max_error, *max_gap_us, |point| {
+                    keep(number(point))
+                });
+                door.held.is_some()
+            }
+        };
+
+        if holding
+            && *heartbeat_us > 0
+            && new_point.microsecond.saturating_sub(*last_sent_us) >= *heartbeat_us
+        {
+            *last_sent_us = new_point.microsecond;
+            keep(OscilliscopePoint {
+                index: *next_index,
+                trigger: false,
+                heartbeat: true,
+                ..new_point
+            });
+        }
+    }
+}
+
+impl SwingDoor {
+    fn push(
+        &mut self,
+        new_point: OscilliscopePoint,
+        max_error: f64,
+        max_gap_us: u64,
+        mut keep: impl FnMut(OscilliscopePoint),
+    ) {
+        let Some(anchor) = self.anchor else {
+            keep(new_point);
+            self.open(new_point);
+            return;
+        };
+        let fits = new_point.microsecond - anchor.microsecond <= max_gap_us
+            && (self.lowest_slope..=self.highest_slope).contains(&slope(&anchor, &new_point));
+        let kept_anyway = new_point.trigger || new_point.adc != self.held.unwrap_or(anchor).adc;
+        if !fits || kept_anyway {
+            if let Some(held) = self.held {
+                keep(held);
+                self.open(held);
+            }
+        }
+        if kept_anyway {
+            keep(new_point);
+            self.open(new_point);
+            return;
+        }
+
+        // Narrows the door, so it only lets through lines that pass near the new point too.
+        let anchor = self.anchor.unwrap_or(anchor);
+        let microseconds = new_point
+            .microsecond
+            .saturating_sub(anchor.microsecond)
+            .max(1) as f64;
+        let difference = new_point.voltage - anchor.voltage;
+        self.lowest_slope = self
+            .lowest_slope
+            .max((difference - max_error) / microseconds);
+        self.highest_slope = self
+            .highest_slope
+            .min((difference + max_error) / microseconds);
+        self.held = Some(new_point);
+    }
+
+    /// Opens the door wide from a point that was just kept.
+    fn open(&mut self, anchor: OscilliscopePoint) {
+        self.anchor = Some(anchor);
+        self.held = None;
+        self.lowest_slope = f64::NEG_INFINITY;
+        self.highest_slope = f64::INFINITY;
+    }
+}
+
+/// Volts per microsecond from `left` to `right`, which comes at least a microsecond later.
+fn slope(left: &OscilliscopePoint, right: &OscilliscopePoint) -> f64 {
+    let microseconds = right.microsecond.saturating_sub(left.microsecond).max(1);
+    (right.voltage - left.voltage) / microseconds as f64
+}
+
+impl Bucket {
+    /// Hands over the lowest, the highest and the trigger point in the order they were measured,
+    /// each once.
+    fn empty(self, mut keep: impl FnMut(OscilliscopePoint)) {
+        let mut points = [Some(self.lowest), Some(self.highest), self.trigger];
+        points.sort_by_key(|point| point.map(|point| point.microsecond));
+        let mut last_kept: Option<u64> = None;
+        for point in points.into_iter().flatten() {
+            if last_kept != Some(point.microsecond) {
+                last_kept = Some(point.microsecond);
+                keep(point);
+            }
+        }
+    }
+}
+
+pub fn is_middle_point_removable_complicated(
+    left: &OscilliscopePoint,
+    middle: &OscilliscopePoint,
+    right: &OscilliscopePoint,
+    tolerance_factor: f64,
+    min_voltage_difference: f64,
+) -> bool {
+    if fabs(right.voltage - left.voltage) < min_voltage_difference {
+        true
+    } else {
+        // Only the differences become floats, so they stay exact however long the uptime.
+        let delta_time_to_right = right.microsecond.saturating_sub(left.microsecond) as f64;
+        if delta_time_to_right == 0f64 {
+            return true;
+        }
+        let delta_time_to_middle = middle.microsecond.saturating_sub(left.microsecond) as f64;
+
+        let voltage_difference = {
+            let voltage_on_slope = {
+                let slope_to_right = {
+                    let delta_voltage_to_right = right.voltage - left.voltage;
+                    delta_voltage_to_right / delta_time_to_right
+                };
+                slope_to_right * delta_time_to_middle + left.voltage
+            };
+            voltage_on_slope - middle.voltage
+        };
+        let tolerance = {
+            let middle_proximity =
+                1f64 - fabs(1f64 - 2f64 * delta_time_to_middle / delta_time_to_right);
+            (tolerance_factor / 2f64) * middle_proximity * fabs(right.voltage - left.voltage)
+        };
+
+        fabs(voltage_difference) < tolerance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adc::AdcSettings;
+
+    const MAX_GAP_US: u64 = 1_000_000;
+    const THRESHOLDS: Thresholds = Thresholds {
+        tolerance_factor: 0.1,
+        min_voltage_difference: 0.05,
+    };
+
+    fn point(microsecond: u64, voltage: f64) -> OscilliscopePoint {
+        OscilliscopePoint {
+            voltage,
+            microsecond,
+            code: 0,
+            index: 0,
+            channel: 0,
+            trigger: false,
+            heartbeat: false,
+            adc: AdcSettings::DEFAULT,
+        }
+    }
+
+    fn decimate(points: impl IntoIterator<Item = OscilliscopePoint>) -> Vec<OscilliscopePoint> {
+        decimate_with(DecimationMode::Slope, points)
+    }
+
+    fn decimate_with(
+        mode: DecimationMode,
+        points: impl IntoIterator<Item = OscilliscopePoint>,
+    ) -> Vec<OscilliscopePoint> {
+        decimate_with_gaps(mode, MAX_GAP_US, 0, points)
+    }
+
+    fn decimate_with_gaps(
+        mode: DecimationMode,
+        max_gap_us: u64,
+        heartbeat_us: u64,
+        points: impl IntoIterator<Item = OscilliscopePoint>,
+    ) -> Vec<OscilliscopePoint> {
+        let mut decimator = Decimator::new(mode, THRESHOLDS, max_gap_us, heartbeat_us);
+        let mut kept = Vec::new();
+        for point in points {
+            decimator.push(point, |point| kept.push(point));
+        }
+        kept
+    }
+
+    #[test]
+    fn points_on_a_straight_line_are_removable() {
+        assert!(is_middle_point_removable_complicated(
+            &point(0, 0f64),
+            &point(500, 0.5),
+            &point(1000, 1f64),
+            0.1,
+            0.05,
+        ));
+    }
+
+    #[test]
+    fn a_spike_is_kept() {
+        assert!(!is_middle_point_removable_complicated(
+            &point(0, 0f64),
+            &point(500, 3f64),
+            &point(1000, 1f64),
+            0.1,
+            0.05,
+        ));
+    }
+
+    #[test]
+    fn differences_stay_exact_after_a_long_uptime() {
+        let year = 365 * 24 * 3600 * 1_000_000u64;
+        assert!(!is_middle_point_removable_complicated(
+            &point(year, 0f64),
+            &point(year + 1, 3f64),
+            &point(year + 2, 1f64),
+            0.1,
+            0.05,
+        ));
+    }
+
+    #[test]
+    fn a_ramp_is_thinned_out() {
+        let kept = decimate((0..1000).map(|i| point(100_000 + i * 100, i as f64 * 0.01)));
+        assert!(kept.len() < 10, "kept {} points", kept.len());
+    }
+
+    #[test]
+    fn a_flat_signal_is_still_sent_every_second() {
+        let kept = decimate((0..50_000).map(|i| point(100_000 + i * 100, 1f64)));
+        assert!(kept.len() >= 4, "kept {} points", kept.len());
+        for pair in kept.windows(2) {
+            assert!(pair[1].microsecond - pair[0].microsecond <= MAX_GAP_US + 100);
+        }
+    }
+
+    #[test]
+    fn trigger_points_are_kept() {
+        let kept = decimate((0..1000).map(|i| OscilliscopePoint {
+            trigger: i == 500,
+            ..point(100_000 + i * 100, 1f64)
+        }));
+        assert!(kept.iter().any(|point| point.trigger));
+    }
+
+    #[test]
+    fn range_changes_keep_the_points_around_them() {
+        let db6 = AdcSettings {
+            attenuation: crate::adc::Attenuation::Db6,
+            ..AdcSettings::DEFAULT
+        };
+        let kept = decimate((0..1000).map(|i| OscilliscopePoint {
+            adc: if i < 500 { AdcSettings::DEFAULT } else { db6 },
+            ..point(100_000 + i * 100, 1f64)
+        }));
+        let microseconds: Vec<_> = kept.iter().map(|point| point.microsecond).collect();
+        assert!(microseconds.contains(&(100_000 + 499 * 100)));
+        assert!(microseconds.contains(&(100_000 + 500 * 100)));
+    }
+
+    #[test]
+    fn kept_points_are_numbered_consecutively() {
+        let kept = decimate((0..1000).map(|i| point(100_000 + i * 100, (i % 7) as f64)));
+        assert!(kept.len() > 1);
+        for (expected, point) in kept.iter().enumerate() {
+            assert_eq!(point.index, expected as u32);
+        }
+    }
+
+    #[test]
+    fn modes_are_read_back() {
+        for mode in [
+            DecimationMode::Slope,
+            DecimationMode::PeakDetect(1000),
+            DecimationMode::SwingDoor(0.05),
+        ] {
+            assert_eq!(DecimationMode::parse(&mode.to_string()), Some(mode));
+            let (kind, argument) = mode.to_parts();
+            assert_eq!(DecimationMode::from_parts(kind, argument), Some(mode));
+        }
+        assert_eq!(DecimationMode::parse("peak"), None);
+        assert_eq!(DecimationMode::parse("peak 0"), None);
+        assert_eq!(DecimationMode::parse("slope 5"), None);
+        assert_eq!(DecimationMode::parse("swingdoor -1"), None);
+    }
+
+    #[test]
+    fn switching_modes_only_keeps_real_points() {
+        let mut decimator =
+            Decimator::new(DecimationMode::PeakDetect(1000), THRESHOLDS, MAX_GAP_US, 0);
+        let mut kept = Vec::new();
+        let start = 3_600_000_000;
+        for i in 0..100 {
+            decimator.push(point(start + i * 100, (i % 5) as f64), |point| {
+                kept.push(point)
+            });
+        }
+        let before = kept.len();
+        decimator.set_mode(DecimationMode::Slope);
+        for i in 100..200 {
+            decimator.push(point(start + i * 100, (i % 5) as f64), |point| {
+                kept.push(point)
+            });
+        }
+
+        assert_eq!(kept[before].microsecond, start + 100 * 100);
+        assert!(kept[before..]
+            .iter()
+            .all(|point| point.voltage == ((point.microsecond - start) / 100 % 5) as f64));
+        for (expected, point) in kept.iter().enumerate() {
+            assert_eq!(point.index, expected as u32);
+        }
+    }
+
+    #[test]
+    fn a_spike_survives_peak_detection() {
+        let kept = decimate_with(
+            DecimationMode::PeakDetect(1000),
+            (0..1000).map(|i| {
+                point(
+                    i * 10,
+                    if i == 437 {
+                        5f64
+                    } else {
+                        (i % 3) as f64 * 0.01
+                    },
+                )
+            }),
+        );
+        assert!(kept.iter().any(|point| point.voltage == 5f64));
+    }
+
+    #[test]
+    fn peak_detection_keeps_the_lowest_and_highest_point_of_every_bucket() {
+        let kept = decimate_with(
+            DecimationMode::PeakDetect(1000),
+            (0..1000).map(|i| point(i * 10, (i % 13) as f64)),
+        );
+        // The last bucket is still open.
+        assert_eq!(kept.len(), 2 * 9);
+        for bucket in kept.chunks(2) {
+            assert_eq!(bucket[0].microsecond / 1000, bucket[1].microsecond / 1000);
+            assert!(bucket[0].microsecond < bucket[1].microsecond);
+            let mut voltages = [bucket[0].voltage, bucket[1].voltage];
+            voltages.sort_by(f64::total_cmp);
+            assert_eq!(voltages, [0f64, 12f64]);
+        }
+        for (expected, point) in kept.iter().enumerate() {
+            assert_eq!(point.index, expected as u32);
+        }
+    }
+
+    #[test]
+    fn peak_detection_keeps_trigger_points_and_range_changes() {
+        let db6 = AdcSettings {
+            attenuation: crate::adc::Attenuation::Db6,
+            ..AdcSettings::DEFAULT
+        };
+        let kept = decimate_with(
+            DecimationMode::PeakDetect(1000),
+            (0..1000).map(|i| OscilliscopePoint {
+                trigger: i == 250,
+                adc: if i < 505 { AdcSettings::DEFAULT } else { db6 },
+                ..point(i * 10, 1f64)
+            }),
+        );
+        assert!(kept
+            .iter()
+            .any(|point| point.trigger && point.microsecond == 2500));
+        // The bucket from 5000 to 5999 us is split where the range changed.
+        let microseconds: Vec<_> = kept.iter().map(|point| point.microsecond).collect();
+        assert!(microseconds.contains(&5000));
+        assert!(microseconds.contains(&5050));
+    }
+
+    /// Checks that the lines between the kept points pass within `max_error` of every point,
+    /// up to the last kept one.
+    fn assert_within(points: &[OscilliscopePoint], kept: &[OscilliscopePoint], max_error: f64) {
+        assert!(kept.len() > 1);
+        let mut right = 0;
+        for point in points
+            .iter()
+            .filter(|point| point.microsecond <= kept[kept.len() - 1].microsecond)
+        {
+            while kept[right].microsecond < point.microsecond {
+                right += 1;
+            }
+            let line = if right == 0 {
+                kept[0].voltage
+            } else {
+                let (left, right) = (&kept[right - 1], &kept[right]);
+                left.voltage + slope(left, right) * (point.microsecond - left.microsecond) as f64
+            };
+            assert!(
+                fabs(line - point.voltage) <= max_error + 1e-9,
+                "{} V at {} us is {} V off",
+                point.voltage,
+                point.microsecond,
+                fabs(line - point.voltage)
+            );
+        }
+    }
+
+    /// Deterministic noise from -1 to 1.
+    fn noise(seed: &mut u32) -> f64 {
+        *seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (*seed >> 8) as f64 / (1u32 << 23) as f64 - 1f64
+    }
+
+    #[test]
+    fn the_swing_door_stays_within_its_error() {
+        let mut seed = 1;
+        let waveforms: [Vec<OscilliscopePoint>; 4] = [
+            // A noisy sine.
+            (0..20_000)
+                .map(|i| {
+                    let volts = 3f64 * libm::sin(i as f64 * 0.002) + 0.05 * noise(&mut seed);
+                    point(i * 50, volts)
+                })
+                .collect(),
+            // A square wave.
+            (0..20_000)
+                .map(|i| point(i * 50, if (i / 500) & 1 == 0 { -1f64 } else { 4f64 }))
+                .collect(),
+            // A chirp, with uneven spacing.
+            (0..20_000)
+                .map(|i| {
+                    let t = i as f64 * 1e-4;
+                    point(i * 50 + i % 7, libm::sin(t * t * 40f64))
+                })
+                .collect(),
+            // Noise only.
+            (0..20_000)
+                .map(|i| point(i * 50, noise(&mut seed)))
+                .collect(),
+        ];
+        for max_error in [0.01, 0.1, 0.5] {
+            for points in &waveforms {
+                let kept = decimate_with(
+                    DecimationMode::SwingDoor(max_error as f32),
+                    points.iter().copied(),
+                );
+                assert_within(points, &kept, max_error as f32 as f64);
+            }
+        }
+    }
+
+    #[test]
+    fn the_swing_door_thins_out_smooth_signals() {
+        let points: Vec<_> = (0..20_000)
+            .map(|i| point(i * 50, 3f64 * libm::sin(i as f64 * 0.002)))
+            .collect();
+        let kept = decimate_with(DecimationMode::SwingDoor(0.05), points.iter().copied());
+        assert!(kept.len() < 200, "kept {} points", kept.len());
+    }
+
+    #[test]
+    fn the_swing_door_keeps_trigger_points_and_range_changes() {
+        let db6 = AdcSettings {
+            attenuation: crate::adc::Attenuation::Db6,
+            ..AdcSettings::DEFAULT
+        };
+        let kept = decimate_with(
+            DecimationMode::SwingDoor(0.1),
+            (0..1000).map(|i| OscilliscopePoint {
+                trigger: i == 250,
+                adc: if i < 500 { AdcSettings::DEFAULT } else { db6 },
+                ..point(i * 10, 1f64)
+            }),
+        );
+        let microseconds: Vec<_> = kept.iter().map(|point| point.microsecond).collect();
+        assert!(microseconds.contains(&2500));
+        assert!(microseconds.contains(&4990));
+        assert!(microseconds.contains(&5000));
+        for (expected, point) in kept.iter().enumerate() {
+            assert_eq!(point.index, expected as u32);
+        }
+    }
+
+    #[test]
+    fn the_longest_gap_can_be_shorter() {
+        for mode in [
+            DecimationMode::Slope,
+            DecimationMode::PeakDetect(1_000_000),
+            DecimationMode::SwingDoor(0.1),
+        ] {
+            let kept = decimate_with_gaps(
+                mode,
+                50_000,
+                0,
+                (0..50_000).map(|i| point(100_000 + i * 100, 1f64)),
+            );
+            assert!(kept.len() >= 90, "{mode} kept {} points", kept.len());
+            for pair in kept.windows(2) {
+                assert!(pair[1].microsecond - pair[0].microsecond <= 50_000 + 100);
+            }
+        }
+    }
+
+    #[test]
+    fn heartbeats_follow_a_flat_signal() {
+        for mode in [
+            DecimationMode::Slope,
+            DecimationMode::PeakDetect(100_000),
+            DecimationMode::SwingDoor(0.1),
+        ] {
+            let sent = decimate_with_gaps(
+                mode,
+                MAX_GAP_US,
+                10_000,
+                (0..50_000).map(|i| point(100_000 + i * 100, 1f64)),
+            );
+            let heartbeats = sent.iter().filter(|point| point.heartbeat).count();
+            assert!(heartbeats > 400, "{mode} sent {heartbeats} heartbeats");
+            // Points kept for a bucket can be older than the heartbeat before them, but the newest
+            // time sent moves on every 10 ms.
+            let mut newest = sent[0].microsecond;
+            for point in &sent[1..] {
+                assert!(point.microsecond <= newest + 10_000);
+                newest = newest.max(point.microsecond);
+            }
+            let kept: Vec<_> = sent.iter().filter(|point| !point.heartbeat).collect();
+            for pair in kept.windows(2) {
+                assert!(pair[0].microsecond < pair[1].microsecond);
+            }
+            // Heartbeats take the index the next kept point gets, and do not use it up.
+            let mut kept = 0;
+            for point in &sent {
+                assert_eq!(point.index, kept);
+                kept += !point.heartbeat as u32;
+            }
+        }
+    }
+
+    #[test]
+    fn coarser_levels_keep_fewer_points() {
+        let mut seed = 3;
+        let points: Vec<_> = (0..20_000)
+            .map(|i| {
+                let volts = 3f64 * libm::sin(i as f64 * 0.002) + 0.02 * noise(&mut seed);
+                point(i * 50, volts)
+            })
+            .collect();
+        for mode in [
+            DecimationMode::Slope,
+            DecimationMode::PeakDetect(1000),
+            DecimationMode::SwingDoor(0.05),
+        ] {
+            let counts: Vec<_> = (0..=4)
+                .map(|level| {
+                    let mut decimator = Decimator::new(mode, THRESHOLDS, MAX_GAP_US, 0);
+                    decimator.set_coarseness(level);
+                    let mut kept = Vec::new();
+                    for point in points.iter().copied() {
+                        decimator.push(point, |point| kept.push(point));
+                    }
+                    if let DecimationMode::SwingDoor(volts) = mode {
+                        assert_within(&points, &kept, volts as f64 * (1 << level) as f64);
+                    }
+                    kept.len()
+                })
+                .collect();
+            assert!(
+                counts.windows(2).all(|pair| pair[1] < pair[0]),
+                "{mode}: kept {counts:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn thresholds_are_read_back() {
+        assert_eq!(Thresholds::parse(&THRESHOLDS.to_string()), Some(THRESHOLDS));
+        assert_eq!(Thresholds::parse("1.5 0.3"), None);
+        assert_eq!(Thresholds::parse("0.1 -0.3"), None);
+        assert_eq!(Thresholds::parse("0.1"), None);
+    }
+}
@@ -0,0 +1,498 @@
+//! Filtering a channel's codes before they are decimated, in a chain of up to `MAX_STAGES`
+//! stages that clients pick while the scope runs.
+//!
+//! Codes go through the chain as floats, so averages keep the bits below a code. The points keep
+//! up to `CODE_FRACTION_BITS` of them, all the way to the clients.
+
+use core::fmt;
+use heapless::Deque;
+use libm::{cos, exp, sin};
+
+/// The most stages in a chain.
+pub const MAX_STAGES: usize = 4;
+/// The most codes a moving average or median goes over.
+pub const MAX_WINDOW: usize = 32;
+/// The most bits oversampling adds, which takes 4^4 = 256 codes per point.
+pub const MAX_OVERSAMPLE_BITS: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterStage {
+    /// Averages the last that many codes.
+    MovingAverage(u8),
+    /// Takes the middle one of the last that many codes, which throws out short spikes.
+    Median(u8),
+    /// A single-pole low-pass with its cutoff in Hz.
+    LowPass(u32),
+    /// A second-order Butterworth low-pass with its cutoff in Hz, falling off twice as steep.
+    Biquad(u32),
+    /// Averages 4^bits codes into one, which resolves that many more bits as long as noise
+    /// dithers the codes, at a quarter of the rate for every bit.
+    Oversample(u8),
+}
+
+impl FilterStage {
+    pub fn parse(name: &str, argument: &str) -> Option<FilterStage> {
+        let stage = match name {
+            "average" => FilterStage::MovingAverage(argument.parse().ok()?),
+            "median" => FilterStage::Median(argument.parse().ok()?),
+            "lowpass" => FilterStage::LowPass(argument.parse().ok()?),
+            "biquad" => FilterStage::Biquad(argument.parse().ok()?),
+            "oversample" => FilterStage::Oversample(argument.parse().ok()?),
+            _ => return None,
+        };
+        stage.is_valid().then_some(stage)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterStage::MovingAverage(_) => "average",
+            FilterStage::Median(_) => "median",
+            FilterStage::LowPass(_) => "lowpass",
+            FilterStage::Biquad(_) => "biquad",
+            FilterStage::Oversample(_) => "oversample",
+        }
+    }
+
+    pub fn argument(&self) -> u32 {
+        match *self {
+            FilterStage::MovingAverage(length) | FilterStage::Median(length) => length as u32,
+            FilterStage::LowPass(cutoff_hz) | FilterStage::Biquad(cutoff_hz) => cutoff_hz,
+            FilterStage::Oversample(bits) => bits as u32,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        match *self {
+            FilterStage::MovingAverage(length) | FilterStage::Median(length) => {
+                (1..=MAX_WINDOW as u8).contains(&length)
+            }
+            FilterStage::LowPass(cutoff_hz) | FilterStage::Biquad(cutoff_hz) => {
+                (1..1 << 24).contains(&cutoff_hz)
+            }
+            FilterStage::Oversample(bits) => (1..=MAX_OVERSAMPLE_BITS).contains(&bits),
+        }
+    }
+
+    /// Packs the stage into a word for atomics, the kind in the highest byte. Never 0, which
+    /// stands for no stage.
+    pub fn to_bits(self) -> u32 {
+        let kind = match self {
+            FilterStage::MovingAverage(_) => 1,
+            FilterStage::Median(_) => 2,
+            FilterStage::LowPass(_) => 3,
+            FilterStage::Biquad(_) => 4,
+            FilterStage::Oversample(_) => 5,
+        };
+        kind << 24 | self.argument()
+    }
+
+    pub fn from_bits(bits: u32) -> Option<FilterStage> {
+        let argument = bits & 0x00ff_ffff;
+        let small = argument.try_into().ok();
+        let stage = match bits >> 24 {
+            1 => FilterStage::MovingAverage(small?),
+            2 => FilterStage::Median(small?),
+            3 => FilterStage::LowPass(argument),
+            4 => FilterStage::Biquad(argument),
+            5 => FilterStage::Oversample(small?),
+            _ => return None,
+        };
+        stage.is_valid().then_some(stage)
+    }
+}
+
+/// The stages of a chain, in the order codes go through them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterSettings {
+    pub stages: [Option<FilterStage>; MAX_STAGES],
+}
+
+impl FilterSettings {
+    pub const OFF: FilterSettings = FilterSettings {
+        stages: [None; MAX_STAGES],
+    };
+
+    /// Reads `off`, or stages with their argument one after another, like `median 5 lowpass
+    /// 2000`.
+    pub fn parse(text: &str) -> Option<FilterSettings> {
+        let mut words = text.split_whitespace();
+        let mut settings = FilterSettings::OFF;
+        if text.trim() == "off" {
+            return Some(settings);
+        }
+        let mut slots = settings.stages.iter_mut();
+        while let Some(name) = words.next() {
+            *slots.next()? = Some(FilterStage::parse(name, words.next()?)?);
+        }
+        settings.stages[0].is_some().then_some(settings)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = FilterStage> + '_ {
+        self.stages.iter().flatten().copied()
+    }
+}
+
+impl fmt::Display for FilterSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.stages[0].is_none() {
+            return f.write_str("off");
+        }
+        for (number, stage) in self.iter().enumerate() {
+            let separator = if number == 0 { "" } else { " " };
+            write!(f, "{separator}{} {}", stage.name(), stage.argument())?;
+        }
+        Ok(())
+    }
+}
+
+/// One stage of a chain, with what it remembers of the codes before.
+enum StageState {
+    MovingAverage(Deque<f64, MAX_WINDOW>, usize),
+    Median(Deque<f64, MAX_WINDOW>, usize),
+    LowPass {
+        cutoff_hz: u32,
+        alpha: f64,
+        output: Option<f64>,
+    },
+    Biquad {
+        cutoff_hz: u32,
+        /// b0, b1, b2, a1 and a2, with a0 divided out.
+        coefficients: [f64; 5],
+        /// The transposed direct form II's two delays.
+        state: Option<[f64; 2]>,
+    },
+    Oversample {
+        bits: u8,
+        sum: f64,
+        count: u32,
+        first_microsecond: u64,
+    },
+}
+
+impl StageState {
+    fn new(stage: FilterStage) -> StageState {
+        match stage {
+            FilterStage::MovingAverage(length) => {
+                StageState::MovingAverage(Deque::new(), length as usize)
+            }
+            FilterStage::Median(length) => StageState::Median(Deque::new(), length as usize),
+            FilterStage::LowPass(cutoff_hz) => StageState::LowPass {
+                cutoff_hz,
+                alpha: 1f64,
+                output: None,
+            },
+            FilterStage::Biquad(cutoff_hz) => StageState::Biquad {
+                cutoff_hz,
+                coefficients: [1f64, 0f64, 0f64, 0f64, 0f64],
+                state: None,
+            },
+            FilterStage::Oversample(bits) => StageState::Oversample {
+                bits,
+                sum: 0f64,
+                count: 0,
+                first_microsecond: 0,
+            },
+        }
+    }
+
+    /// Designs the low-passes for codes coming in at `rate_hz`, and returns the rate codes go
+    /// out at. Without a rate the low-passes let everything through.
+    fn set_rate(&mut self, rate_hz: f64) -> f64 {
+        match self {
+            StageState::LowPass {
+                cutoff_hz, alpha, ..
+            } => {
+                *alpha = if rate_hz > 0f64 {
+                    1f64 - exp(-2f64 * core::f64::consts::PI * *cutoff_hz as f64 / rate_hz)
+                } else {
+                    1f64
+                };
+                rate_hz
+            }
+            StageState::Biquad {
+                cutoff_hz,
+                coefficients,
+                ..
+            } => {
+                // The cookbook low-pass with a Q of 1/sqrt(2), its cutoff kept below Nyquist.
+                *coefficients = if rate_hz > 0f64 {
+                    let cutoff_hz = (*cutoff_hz as f64).min(0.45 * rate_hz);
+                    let omega = 2f64 * core::f64::consts::PI * cutoff_hz / rate_hz;
+                    let alpha = sin(omega) / core::f64::consts::SQRT_2;
+                    let a0 = 1f64 + alpha;
+                    let b1 = (1f64 - cos(omega)) / a0;
+                    [
+                        b1 / 2f64,
+                        b1,
+                        b1 / 2f64,
+                        -2f64 * cos(omega) / a0,
+                        (1f64 - alpha) / a0,
+                    ]
+                } else {
+                    [1f64, 0f64, 0f64, 0f64, 0f64]
+                };
+                rate_hz
+            }
+            StageState::Oversample { bits, .. } => rate_hz / (1u32 << (2 * *bits)) as f64,
+            StageState::MovingAverage(..) | StageState::Median(..) => rate_hz,
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            StageState::MovingAverage(codes, _) | StageState::Median(codes, _) => codes.clear(),
+            StageState::LowPass { output, .. } => *output = None,
+            StageState::Biquad { state, .. } => *state = None,
+            StageState::Oversample { sum, count, .. } => {
+                *sum = 0f64;
+                *count = 0;
+            }
+        }
+    }
+
+    fn push(&mut self, code: f64, microsecond: u64) -> Option<(f64, u64)> {
+        match self {
+            StageState::MovingAverage(codes, length) => {
+                remember(codes, *length, code);
+                Some((codes.iter().sum::<f64>() / codes.len() as f64, microsecond))
+            }
+            StageState::Median(codes, length) => {
+                remember(codes, *length, code);
+                let mut sorted = [0f64; MAX_WINDOW];
+                let sorted = &mut sorted[..codes.len()];
+                for (sorted, code) in sorted.iter_mut().zip(codes.iter()) {
+                    *sorted = *code;
+                }
+                sorted.sort_unstable_by(f64::total_cmp);
+                let middle = sorted.len() / 2;
+                let median = if sorted.len() & 1 == 1 {
+                    sorted[middle]
+                } else {
+                    (sorted[middle - 1] + sorted[middle]) / 2f64
+                };
+                Some((median, microsecond))
+            }
+            StageState::LowPass { alpha, output, .. } => {
+                let output = output.get_or_insert(code);
+                *output += *alpha * (code - *output);
+                Some((*output, microsecond))
+            }
+            StageState::Biquad {
+                coefficients: [b0, b1, b2, a1, a2],
+                state,
+                ..
+            } => {
+                // Starting out settled on the first code saves waiting for the filter to ring in.
+                let [z1, z2] =
+                    state.get_or_insert([(*b1 - *a1 + *b2 - *a2) * code, (*b2 - *a2) * code]);
+                let output = *b0 * code + *z1;
+                *z1 = *b1 * code - *a1 * output + *z2;
+                *z2 = *b2 * code - *a2 * output;
+                Some((output, microsecond))
+            }
+            StageState::Oversample {
+                bits,
+                sum,
+                count,
+                first_microsecond,
+            } => {
+                if *count == 0 {
+                    *first_microsecond = microsecond;
+                }
+                *sum += code;
+                *count += 1;
+                let codes = 1u32 << (2 * *bits);
+                if *count < codes {
+                    return None;
+                }
+                let average = *sum / codes as f64;
+                *sum = 0f64;
+                *count = 0;
+                Some((average, *first_microsecond))
+            }
+        }
+    }
+}
+
+/// Keeps the last `length` codes.
+fn remember(codes: &mut Deque<f64, MAX_WINDOW>, length: usize, code: f64) {
+    while codes.len() >= length {
+        codes.pop_front();
+    }
+    let _ = codes.push_back(code);
+}
+
+/// Runs one channel's codes through the stages of its `FilterSettings`.
+pub struct FilterChain {
+    stages: heapless::Vec<StageState, MAX_STAGES>,
+}
+
+impl FilterChain {
+    /// Filters codes that come in at `rate_hz`, 0 when the rate is not known yet.
+    pub fn new(settings: FilterSettings, rate_hz: f64) -> FilterChain {
+        let mut chain = FilterChain {
+            stages: settings.iter().map(StageState::new).collect(),
+        };
+        chain.set_rate(rate_hz);
+        chain
+    }
+
+    /// Designs the low-passes again for another rate. What the stages remember stays.
+    pub fn set_rate(&mut self, rate_hz: f64) {
+        self.stages
+            .iter_mut()
+            .fold(rate_hz, |rate_hz, stage| stage.set_rate(rate_hz));
+    }
+
+    /// Forgets the codes before, for when they jumped or were lost.
+    pub fn reset(&mut self) {
+        self.stages.iter_mut().for_each(StageState::reset);
+    }
+
+    /// Takes the next code, and returns the filtered one with its timestamp, unless a stage
+    /// waits for more codes.
+    pub fn push(&mut self, code: f64, microsecond: u64) -> Option<(f64, u64)> {
+        self.stages
+            .iter_mut()
+            .try_fold((code, microsecond), |(code, microsecond), stage| {
+                stage.push(code, microsecond)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{string::ToString, vec::Vec};
+    use core::f64::consts::PI;
+
+    fn chain(text: &str, rate_hz: f64) -> FilterChain {
+        FilterChain::new(FilterSettings::parse(text).unwrap(), rate_hz)
+    }
+
+    fn filter(chain: &mut FilterChain, codes: impl IntoIterator<Item = f64>) -> Vec<f64> {
+        codes
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, code)| chain.push(code, i as u64 * 100))
+            .map(|(code, _)| code)
+            .collect()
+    }
+
+    /// The amplitude of a sine at `frequency_hz` after the chain settled.
+    fn gain(text: &str, frequency_hz: f64) -> f64 {
+        let rate_hz = 10_000f64;
+        let mut chain = chain(text, rate_hz);
+        let sine = (0..20_000)
+            .map(|i| 2000f64 + 1000f64 * sin(2f64 * PI * frequency_hz * i as f64 / rate_hz));
+        let filtered = filter(&mut chain, sine);
+        let settled = &filtered[filtered.len() / 2..];
+        let highest = settled.iter().copied().fold(f64::MIN, f64::max);
+        let lowest = settled.iter().copied().fold(f64::MAX, f64::min);
+        (highest - lowest) / 2000f64
+    }
+
+    #[test]
+    fn settings_are_read_and_written_back() {
+        let settings = FilterSettings::parse("median 5 lowpass 2000").unwrap();
+        assert_eq!(
+            settings.iter().collect::<Vec<_>>(),
+            [FilterStage::Median(5), FilterStage::LowPass(2000)]
+        );
+        assert_eq!(settings.to_string(), "median 5 lowpass 2000");
+        assert_eq!(FilterSettings::parse("off"), Some(FilterSettings::OFF));
+        assert_eq!(FilterSettings::OFF.to_string(), "off");
+
+        assert_eq!(FilterSettings::parse(""), None);
+        assert_eq!(FilterSettings::parse("median"), None);
+        assert_eq!(FilterSettings::parse("median 0"), None);
+        assert_eq!(FilterSettings::parse("average 33"), None);
+        assert_eq!(FilterSettings::parse("oversample 5"), None);
+        assert_eq!(FilterSettings::parse("notch 50"), None);
+        assert_eq!(
+            FilterSettings::parse("average 2 average 2 average 2 average 2 average 2"),
+            None
+        );
+    }
+
+    #[test]
+    fn stages_survive_being_packed() {
+        for stage in [
+            FilterStage::MovingAverage(8),
+            FilterStage::Median(31),
+            FilterStage::LowPass(83_333),
+            FilterStage::Biquad(1),
+            FilterStage::Oversample(4),
+        ] {
+            assert_eq!(FilterStage::from_bits(stage.to_bits()), Some(stage));
+        }
+        assert_eq!(FilterStage::from_bits(0), None);
+        assert_eq!(FilterStage::from_bits(1 << 24 | 300), None);
+    }
+
+    #[test]
+    fn moving_averages_smooth_a_step() {
+        let mut chain = chain("average 4", 0f64);
+        let filtered = filter(&mut chain, [0f64, 0f64, 0f64, 0f64, 4f64, 4f64, 4f64, 4f64]);
+        assert_eq!(filtered, [0f64, 0f64, 0f64, 0f64, 1f64, 2f64, 3f64, 4f64]);
+    }
+
+    #[test]
+    fn medians_throw_out_spikes() {
+        let mut chain = chain("median 3", 0f64);
+        let filtered = filter(
+            &mut chain,
+            [100f64, 100f64, 4095f64, 100f64, 100f64, 0f64, 100f64],
+        );
+        assert!(filtered.iter().all(|&code| code == 100f64), "{filtered:?}");
+    }
+
+    #[test]
+    fn low_passes_keep_slow_signals_and_damp_fast_ones() {
+        assert!((gain("lowpass 500", 10f64) - 1f64).abs() < 0.01);
+        assert!((gain("lowpass 500", 500f64) - 0.707).abs() < 0.05);
+        assert!(gain("lowpass 500", 4000f64) < 0.2);
+
+        // The biquad falls off twice as fast past the cutoff.
+        assert!((gain("biquad 500", 10f64) - 1f64).abs() < 0.01);
+        assert!((gain("biquad 500", 500f64) - 0.707).abs() < 0.05);
+        assert!(gain("biquad 500", 4000f64) < 0.03);
+    }
+
+    #[test]
+    fn low_passes_start_out_settled() {
+        let mut chain = chain("lowpass 100 biquad 100", 10_000f64);
+        let filtered = filter(&mut chain, [1234f64; 10]);
+        assert!(filtered.iter().all(|code| (code - 1234f64).abs() < 1e-6));
+    }
+
+    #[test]
+    fn oversampling_resolves_below_a_code() {
+        // Codes dithering between 100 and 101, a quarter of the time at 101.
+        let mut chain = chain("oversample 2", 0f64);
+        let codes = (0..64).map(|i| if i % 4 == 0 { 101f64 } else { 100f64 });
+        let filtered: Vec<_> = codes
+            .enumerate()
+            .filter_map(|(i, code)| chain.push(code, i as u64 * 10))
+            .collect();
+        assert_eq!(
+            filtered,
+            [(100.25, 0), (100.25, 160), (100.25, 320), (100.25, 480)]
+        );
+    }
+
+    #[test]
+    fn stages_after_oversampling_run_at_the_lower_rate() {
+        // At 10 kHz in, the low-pass gets a code 625 times a second.
+        let mut chain = chain("oversample 2 lowpass 100", 10_000f64);
+        let codes = [0f64; 16].into_iter().chain([1000f64; 16]);
+        let filtered = filter(&mut chain, codes);
+        let expected = 1000f64 * (1f64 - exp(-2f64 * PI * 100f64 / 625f64));
+        assert_eq!(filtered.len(), 2);
+        assert!((filtered[1] - expected).abs() < 1e-6, "{filtered:?}");
+
+        chain.push(5f64, 0);
+        chain.reset();
+        assert_eq!(filter(&mut chain, [7f64; 16]), [7f64]);
+    }
+}
@@ -0,0 +1,159 @@
+//! Reading the HTTP request a browser sends to open a WebSocket.
+
+use crate::protocol::WireFormat;
+use heapless::{String, Vec};
+
+/// The longest key or subprotocol name that is accepted.
+pub const MAX_TOKEN_LENGTH: usize = 24;
+const MAX_OFFERED_PROTOCOLS: usize = 3;
+const MAX_HEADERS: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError {
+    /// The request does not end in an empty line yet.
+    Incomplete,
+    /// Not valid HTTP.
+    Garbled(httparse::Error),
+    /// Valid HTTP, but it does not ask for a WebSocket.
+    NotAnUpgrade,
+    /// A key or subprotocol longer than [`MAX_TOKEN_LENGTH`].
+    TooLong,
+}
+
+/// What the server needs to know to answer a WebSocket handshake.
+#[derive(Debug, PartialEq)]
+pub struct HandshakeRequest {
+    /// The `Sec-WebSocket-Key`, which the response has to be derived from.
+    pub key: String<MAX_TOKEN_LENGTH>,
+    pub format: WireFormat,
+    /// The subprotocol the response should name, if the client offered any.
+    pub subprotocol: Option<String<MAX_TOKEN_LENGTH>>,
+    /// The longest message the client wants to get, from `?max_frame=` in the path.
+    pub max_frame: Option<usize>,
+}
+
+/// Whether `request` holds a whole HTTP header, which ends in an empty line.
+pub fn is_complete(request: &[u8]) -> bool {
+    request.ends_with(b"\r\n\r\n")
+}
+
+pub fn parse(request: &[u8]) -> Result<HandshakeRequest, HandshakeError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    match parsed.parse(request) {
+        Ok(httparse::Status::Complete(_)) => (),
+        Ok(httparse::Status::Partial) => return Err(HandshakeError::Incomplete),
+        Err(e) => return Err(HandshakeError::Garbled(e)),
+    }
+
+    let mut upgrade = false;
+    let mut key = None;
+    let mut offered: Vec<&str, MAX_OFFERED_PROTOCOLS> = Vec::new();
+    for header in parsed.headers.iter() {
+        let Ok(value) = core::str::from_utf8(header.value) else {
+            continue;
+        };
+        if header.name.eq_ignore_ascii_case("Upgrade") {
+            upgrade = value.trim().eq_ignore_ascii_case("websocket");
+        } else if header.name.eq_ignore_ascii_case("Sec-WebSocket-Key") {
+            key = Some(value.trim());
+        } else if header.name.eq_ignore_ascii_case("Sec-WebSocket-Protocol") {
+            for protocol in value.split(',').map(str::trim) {
+                // Anything past what fits is not one we know anyway.
+                let _ = offered.push(protocol);
+            }
+        }
+    }
+
+    let key = match key {
+        Some(key) if upgrade => key,
+        _ => return Err(HandshakeError::NotAnUpgrade),
+    };
+
+    let max_frame = parsed
+        .path
+        .and_then(|path| path.split_once('?'))
+        .and_then(|(_, query)| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("max_frame="))
+        })
+        .and_then(|length| length.parse().ok());
+
+    let format = WireFormat::negotiate(offered.iter().copied());
+    // Browsers close the connection if none of the offered protocols is named in the response.
+    let subprotocol = match format.subprotocol() {
+        Some(name) => Some(name),
+        None => offered.first().copied(),
+    };
+
+    Ok(HandshakeRequest {
+        key: String::try_from(key).map_err(|_| HandshakeError::TooLong)?,
+        format,
+        subprotocol: subprotocol
+            .map(String::try_from)
+            .transpose()
+            .map_err(|_| HandshakeError::TooLong)?,
+        max_frame,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    fn request(protocols: Option<&str>) -> std::string::String {
+        let mut request = std::format!(
+            "GET / HTTP/1.1\r\nHost: 192.168.1.1:43822\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {KEY}\r\nSec-WebSocket-Version: 13\r\n"
+        );
+        if let Some(protocols) = protocols {
+            request += &std::format!("Sec-WebSocket-Protocol: {protocols}\r\n");
+        }
+        request + "\r\n"
+    }
+
+    #[test]
+    fn picks_the_compact_format_when_offered() {
+        let handshake = parse(request(Some("chat, just-a-scope.v2")).as_bytes()).unwrap();
+        assert_eq!(handshake.key, KEY);
+        assert_eq!(handshake.format, WireFormat::V2);
+        assert_eq!(handshake.subprotocol.as_deref(), Some("just-a-scope.v2"));
+    }
+
+    #[test]
+    fn falls_back_to_the_legacy_format() {
+        let handshake = parse(request(None).as_bytes()).unwrap();
+        assert_eq!(handshake.format, WireFormat::Legacy);
+        assert_eq!(handshake.subprotocol, None);
+
+        let handshake = parse(request(Some("chat")).as_bytes()).unwrap();
+        assert_eq!(handshake.format, WireFormat::Legacy);
+        assert_eq!(handshake.subprotocol.as_deref(), Some("chat"));
+    }
+
+    #[test]
+    fn reads_the_longest_message_the_client_wants() {
+        assert_eq!(parse(request(None).as_bytes()).unwrap().max_frame, None);
+        let asking = request(None).replacen("GET / ", "GET /?v=3&max_frame=1400 ", 1);
+        assert_eq!(parse(asking.as_bytes()).unwrap().max_frame, Some(1400));
+        let garbled = request(None).replacen("GET / ", "GET /?max_frame=lots ", 1);
+        assert_eq!(parse(garbled.as_bytes()).unwrap().max_frame, None);
+    }
+
+    #[test]
+    fn rejects_plain_http() {
+        let request = b"GET / HTTP/1.1\r\nHost: 192.168.1.1\r\n\r\n";
+        assert!(is_complete(request));
+        assert_eq!(parse(request), Err(HandshakeError::NotAnUpgrade));
+    }
+
+    #[test]
+    fn waits_for_the_whole_header() {
+        let full = request(None);
+        let partial = &full.as_bytes()[..full.len() - 2];
+        assert!(!is_complete(partial));
+        assert_eq!(parse(partial), Err(HandshakeError::Incomplete));
+    }
+}
@@ -0,0 +1,199 @@
+use core::{
+    mem::MaybeUninit,
+    ptr::NonNull,
+    sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+};
+
+/// Long ring of everything that was sent, so clients that connect later can catch up.
+///
+/// There is one writer and any number of readers. Positions count every entry ever appended
+/// and wrap around at `usize::MAX`, which only 32 bits are on the scope. The capacity is a power
+/// of two, so a position lands on the same slot before and after that wrap. Readers copy entries out and then check that the writer did
+/// not lap them while copying, the same way a seqlock works, so the writer never waits.
+///
+/// Like every seqlock, that lets a reader copy an entry while the writer overwrites it, which
+/// is a data race as far as Rust is concerned. Copying entries word by word through atomics
+/// instead would read their padding, which is just as undefined. So entries are copied with
+/// volatile accesses, which the compiler can neither split up nor leave out, and a copy stays a
+/// `MaybeUninit` until the reader has checked that the writer kept off its slot. A torn copy is
+/// thrown away without ever being taken for a `T`.
+pub struct History<T: 'static> {
+    entries: NonNull<T>,
+    capacity: usize,
+    written: AtomicUsize,
+    wrapped: AtomicBool,
+    writer_available: AtomicBool,
+}
+
+pub struct HistoryWriter<'a, T: 'static> {
+    history: &'a History<T>,
+}
+
+unsafe impl<T: Send> Send for History<T> {}
+unsafe impl<T: Send> Sync for History<T> {}
+unsafe impl<'a, T: Send> Send for HistoryWriter<'a, T> {}
+
+impl<T: Copy> History<T> {
+    /// The storage can live anywhere, PSRAM included. The counters stay in the struct itself,
+    /// which should be kept in internal RAM where atomics work across both cores.
+    ///
+    /// Only the largest power of two entries that fits in `storage` is used.
+    pub fn new(storage: &'static mut [T]) -> History<T> {
+        assert!(
+            storage.len() > 1,
+            "History needs room for at least two entries."
+        );
+        History {
+            capacity: 1 << storage.len().ilog2(),
+            entries: NonNull::from(storage).cast(),
+            written: AtomicUsize::new(0),
+            wrapped: AtomicBool::new(false),
+            writer_available: AtomicBool::new(true),
+        }
+    }
+
+    pub fn take_writer(&self) -> Option<HistoryWriter<'_, T>> {
+        if self.writer_available.swap(false, Ordering::Acquire) {
+            Some(HistoryWriter { history: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Position right after the newest entry.
+    pub fn end(&self) -> usize {
+        self.written.load(Ordering::Acquire)
+    }
+
+    /// Position of the oldest entry that is still kept. One slot of slack is left, since the
+    /// writer may be busy overwriting the entry right before it.
+    pub fn start(&self) -> usize {
+        let end = self.end();
+        if self.wrapped.load(Ordering::Acquire) {
+            end.wrapping_sub(self.capacity - 1)
+        } else {
+            0
+        }
+    }
+
+    /// Copies entries starting at `position` into `into`, returning how many were copied.
+    ///
+    /// Returns `None` if the writer has already overwritten, or was overwriting, some of them.
+    /// Starting over from [`History::start`] is the way to go then.
+    pub fn read(&self, position: usize, into: &mut [T]) -> Option<usize> {
+        let available = self.end().wrapping_sub(position);
+        if available >= self.capacity {
+            return None;
+        }
+
+        let count = available.min(into.len());
+        for (offset, entry) in into[..count].iter_mut().enumerate() {
+            let index = position.wrapping_add(offset) % self.capacity;
+            let copy = unsafe {
+                self.entries
+                    .as_ptr()
+                    .add(index)
+                    .cast::<MaybeUninit<T>>()
+                    .read_volatile()
+            };
+
+            // Pairs with the fence in `append`. If the writer touched the copied entry, the
+            // position it was writing to is visible now as well.
+            fence(Ordering::Acquire);
+            if self.written.load(Ordering::Relaxed).wrapping_sub(position) >= self.capacity {
+                return None;
+            }
+            *entry = unsafe { copy.assume_init() };
+        }
+
+        Some(count)
+    }
+}
+
+impl<'a, T: Copy> HistoryWriter<'a, T> {
+    pub fn append(&mut self, value: T) {
+        let history = self.history;
+        let position = history.written.load(Ordering::Relaxed);
+
+        // Readers must be able to see that this slot is being overwritten before they can see
+        // any part of the new value.
+        fence(Ordering::Release);
+        unsafe {
+            history
+                .entries
+                .as_ptr()
+                .add(position % history.capacity)
+                .write_volatile(value);
+        }
+
+        let next = position.wrapping_add(1);
+        // `is_multiple_of` is newer than the toolchain the firmware is built with.
+        #[allow(clippy::manual_is_multiple_of)]
+        if next % history.capacity == 0 {
+            history.wrapped.store(true, Ordering::Release);
+        }
+        history.written.store(next, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{boxed::Box, vec};
+
+    fn history(capacity: usize) -> History<u32> {
+        History::new(Box::leak(vec![0; capacity].into_boxed_slice()))
+    }
+
+    #[test]
+    fn reads_back_what_was_appended() {
+        let history = history(8);
+        let mut writer = history.take_writer().unwrap();
+        for value in 0..5 {
+            writer.append(value);
+        }
+
+        let mut into = [0; 8];
+        assert_eq!(history.start(), 0);
+        assert_eq!(history.read(1, &mut into), Some(4));
+        assert_eq!(into[..4], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn lapped_readers_start_over() {
+        let history = history(4);
+        let mut writer = history.take_writer().unwrap();
+        for value in 0..10 {
+            writer.append(value);
+        }
+
+        let mut into = [0; 4];
+        assert_eq!(history.read(2, &mut into), None);
+        let start = history.start();
+        assert_eq!(history.read(start, &mut into), Some(3));
+        assert_eq!(into[..3], [7, 8, 9]);
+    }
+
+    #[test]
+    fn positions_keep_their_slots_when_they_wrap_around() {
+        let history = history(6);
+        assert_eq!(history.capacity(), 4);
+        // As if the writer had been at it for a long while.
+        history.written.store(usize::MAX - 3, Ordering::Relaxed);
+        history.wrapped.store(true, Ordering::Relaxed);
+        let mut writer = history.take_writer().unwrap();
+        for value in 0..5 {
+            writer.append(value);
+        }
+
+        let mut into = [0; 4];
+        let start = history.start();
+        assert_eq!(start, usize::MAX - 1);
+        assert_eq!(history.read(start, &mut into), Some(3));
+        assert_eq!(into[..3], [2, 3, 4]);
+    }
+}
@@ -1 +1,21 @@
-#![no_std]
\ No newline at end of file
+//! Everything that does not need the scope's hardware, so it can be tested on a computer.
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+
+pub mod adc;
+pub mod autorange;
+pub mod backpressure;
+pub mod calibration;
+pub mod calibrator;
+pub mod control;
+pub mod decimation;
+pub mod filter;
+pub mod handshake;
+pub mod history;
+pub mod noise;
+pub mod protocol;
+pub mod sampling;
+pub mod trigger;
+pub mod wakeup;
+pub mod websocket_logistics;
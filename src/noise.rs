@@ -0,0 +1,169 @@
+//! Estimating how much noise a channel's samples carry, so the decimator can tell it from the
+//! signal.
+//!
+//! The estimate goes by the raw codes of the samples, before averaging and filtering smooth the
+//! noise away. Every window of samples gets an estimate from the differences between
+//! neighbouring samples, which leave out slow changes of the signal. Edges and fast signals
+//! still add to it, so the noise floor is the estimate of the quietest of the last few windows.
+
+use crate::decimation::Thresholds;
+use libm::sqrt;
+
+/// Samples in every window.
+const WINDOW_SAMPLES: u32 = 256;
+/// Windows the quietest one is picked from.
+const WINDOWS: usize = 8;
+/// How many standard deviations of the noise a change has to be, to be kept.
+const NOISE_MULTIPLE: f64 = 4.0;
+/// The smallest change to keep, in codes, for signals so clean that the codes' steps are all
+/// there is.
+const MIN_CODES: f64 = 2.0;
+
+/// Follows the noise floor of one channel.
+pub struct NoiseFloor {
+    previous: Option<f64>,
+    sum_of_squares: f64,
+    count: u32,
+    /// Standard deviation of the noise in codes in every window, `None` until there is one.
+    windows: [Option<f64>; WINDOWS],
+    next_window: usize,
+}
+
+impl NoiseFloor {
+    pub fn new() -> NoiseFloor {
+        NoiseFloor {
+            previous: None,
+            sum_of_squares: 0f64,
+            count: 0,
+            windows: [None; WINDOWS],
+            next_window: 0,
+        }
+    }
+
+    /// Forgets the windows so far, for when the noise changed, like with the ADC's range.
+    pub fn reset(&mut self) {
+        *self = NoiseFloor::new();
+    }
+
+    /// Takes the raw code of the next sample, and returns the standard deviation of the noise
+    /// in codes whenever a window is done.
+    pub fn push(&mut self, code: u16) -> Option<f64> {
+        let code = code as f64;
+        if let Some(previous) = self.previous.replace(code) {
+            let difference = code - previous;
+            self.sum_of_squares += difference * difference;
+            self.count += 1;
+        }
+        if self.count < WINDOW_SAMPLES {
+            return None;
+        }
+
+        // The difference of two independent samples of the noise has twice its variance.
+        let noise_codes = sqrt(self.sum_of_squares / self.count as f64 / 2f64);
+        self.windows[self.next_window] = Some(noise_codes);
+        self.next_window = (self.next_window + 1) % WINDOWS;
+        self.sum_of_squares = 0f64;
+        self.count = 0;
+        self.codes()
+    }
+
+    /// The standard deviation of the noise in codes, once a window is done.
+    pub fn codes(&self) -> Option<f64> {
+        self.windows.iter().flatten().copied().reduce(f64::min)
+    }
+
+    /// The standard deviation of the noise in volts, for a probe where a code is worth
+    /// `volts_per_code`.
+    pub fn volts(&self, volts_per_code: f64) -> Option<f64> {
+        Some(self.codes()? * volts_per_code)
+    }
+
+    /// The thresholds that keep changes of more than the noise, for points where a code is worth
+    /// `volts_per_code`.
+    pub fn thresholds(&self, volts_per_code: f64, tolerance_factor: f64) -> Option<Thresholds> {
+        Some(Thresholds {
+            tolerance_factor,
+            min_voltage_difference: (self.volts(volts_per_code)? * NOISE_MULTIPLE)
+                .max(volts_per_code * MIN_CODES),
+        })
+    }
+}
+
+impl Default for NoiseFloor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libm::{fabs, sin};
+
+    /// Deterministic noise with a standard deviation of 1.
+    fn gaussian(seed: &mut u32) -> f64 {
+        // The sum of 12 uniform numbers from 0 to 1 is close to normal, with a variance of 1.
+        (0..12)
+            .map(|_| {
+                *seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (*seed >> 8) as f64 / (1u32 << 24) as f64
+            })
+            .sum::<f64>()
+            - 6f64
+    }
+
+    /// Rounds to a code, the way the ADC hands them out.
+    fn code(codes: f64) -> u16 {
+        (codes + 0.5) as u16
+    }
+
+    fn estimate(codes: impl Iterator<Item = f64>) -> f64 {
+        let mut noise = NoiseFloor::new();
+        codes.for_each(|codes| {
+            noise.push(code(codes));
+        });
+        noise.codes().unwrap()
+    }
+
+    #[test]
+    fn finds_the_noise_under_a_slow_signal() {
+        let mut seed = 7;
+        let noise_codes = estimate(
+            (0..10_000)
+                .map(|i| 2048f64 + 1000f64 * sin(i as f64 * 0.001) + 8f64 * gaussian(&mut seed)),
+        );
+        assert!(fabs(noise_codes - 8f64) < 1.6, "{noise_codes}");
+    }
+
+    #[test]
+    fn edges_do_not_count_as_noise() {
+        let mut seed = 7;
+        let noise_codes = estimate((0..10_000).map(|i| {
+            let square = if (i / 700) & 1 == 0 { 1000f64 } else { 3000f64 };
+            square + 4f64 * gaussian(&mut seed)
+        }));
+        assert!(fabs(noise_codes - 4f64) < 0.8, "{noise_codes}");
+    }
+
+    #[test]
+    fn thresholds_follow_the_noise_but_not_below_a_couple_of_codes() {
+        let mut noise = NoiseFloor::new();
+        assert_eq!(noise.thresholds(0.01, 0.1), None);
+        let mut seed = 7;
+        (0..10_000).for_each(|_| {
+            noise.push(code(2048f64 + 10f64 * gaussian(&mut seed)));
+        });
+        let thresholds = noise.thresholds(0.01, 0.1).unwrap();
+        assert_eq!(thresholds.tolerance_factor, 0.1);
+        assert!(fabs(thresholds.min_voltage_difference - 0.4) < 0.08);
+
+        noise.reset();
+        (0..10_000).for_each(|_| {
+            noise.push(2048);
+        });
+        assert_eq!(
+            noise.thresholds(0.01, 0.1).unwrap().min_voltage_difference,
+            0.02
+        );
+    }
+}
@@ -0,0 +1,912 @@
+//! Encoding of the points sent over the WebSocket.
+//!
+//! Every version 2 frame is little endian and starts with the same eight bytes:
+//!
+//! | offset | type  | field                                           |
+//! |--------|-------|-------------------------------------------------|
+//! | 0      | `u8`  | protocol version, always 2                      |
+//! | 1      | `u8`  | frame type                                      |
+//! | 2      | `u8`  | channel, counting from 0                        |
+//! | 3      | `u8`  | reserved                                        |
+//! | 4      | `u32` | sequence number, counting frames per connection |
+//!
+//! Points frames (type 0) continue with:
+//!
+//! | offset | type  | field                                           |
+//! |--------|-------|-------------------------------------------------|
+//! | 8      | `u16` | point count                                     |
+//! | 10     | `u8`  | bits below a whole ADC code, 0 to 4             |
+//! | 11     | `u8`  | reserved                                        |
+//! | 12     | `u32` | reserved                                        |
+//! | 16     | `u64` | base timestamp in microseconds                  |
+//! | 24     | `f32` x4 | the curve from ADC codes to volts at the probes |
+//!
+//! The curve is a [`ProbeCalibration`]: a polynomial in the code divided by 4095, with the
+//! constant coefficient first.
+//!
+//! After that come the ADC codes, each with 12 bits plus the bits below a whole code, so the
+//! bits the filters resolve get through. They are packed one after the other starting at the
+//! lowest bit, and the last byte is filled up with zeros.
+//!
+//! Then the microseconds since the previous point for every point, where the first point is
+//! relative to the base timestamp. Each takes seven bits per byte, lowest first, with the top
+//! bit set on every byte but its last, so the usual sample periods take one or two bytes.
+//!
+//! Gap frames (type 1) say that points were lost, or skipped between two triggered frames,
+//! between two points that were sent:
+//!
+//! | offset | type  | field                                           |
+//! |--------|-------|-------------------------------------------------|
+//! | 8      | `u32` | number of points lost                           |
+//! | 12     | `u32` | reserved                                        |
+//! | 16     | `u64` | timestamp of the last point before the gap      |
+//! | 24     | `u64` | timestamp of the first point after the gap      |
+//!
+//! Trigger frames (type 2) come right before the point the trigger fired on:
+//!
+//! | offset | type  | field                                           |
+//! |--------|-------|-------------------------------------------------|
+//! | 8      | `u64` | timestamp of the trigger point                  |
+//!
+//! Range frames (type 3) come right before the first point whose code the ADC converted with
+//! other settings than the point before, so a jump in the codes can be told from one in the
+//! signal. Until the first one, a channel's codes are with 11 dB and the basic calibration:
+//!
+//! | offset | type  | field                                           |
+//! |--------|-------|-------------------------------------------------|
+//! | 8      | `u64` | timestamp of the first point with the settings  |
+//! | 16     | `u8`  | attenuation, 0 for 0 dB up to 3 for 11 dB       |
+//! | 17     | `u8`  | calibration scheme, 0 basic, 1 line, 2 curve    |
+//! | 18     | `u16` | reserved                                        |
+//! | 20     | `f32` | highest voltage the ADC measures at its pin     |
+//!
+//! Every points frame only holds points with the same settings, and its curve is the one for them.
+//!
+//! Heartbeat frames (type 4) say how far the signal got while the scope holds points back, with
+//! the newest point measured. The points sent after one can be older than it:
+//!
+//! | offset | type  | field                                           |
+//! |--------|-------|-------------------------------------------------|
+//! | 8      | `u64` | timestamp of the newest point                   |
+//! | 16     | `f32` | volts at the probes of the newest point         |
+//! | 20     | `u32` | reserved                                        |
+//!
+//! Pages that do not ask for [`V2_SUBPROTOCOL`] get the legacy format, which is just the voltage
+//! and the second of every point of the first channel as two `f64`s.
+
+use crate::{
+    adc::AdcSettings,
+    calibration::ProbeCalibration,
+    websocket_logistics::{OscilliscopePoint, CHANNELS, CODE_FRACTION_BITS},
+};
+
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const V2_SUBPROTOCOL: &str = "just-a-scope.v2";
+
+const FRAME_TYPE_POINTS: u8 = 0;
+const FRAME_TYPE_GAP: u8 = 1;
+const FRAME_TYPE_TRIGGER: u8 = 2;
+const FRAME_TYPE_RANGE: u8 = 3;
+const FRAME_TYPE_HEARTBEAT: u8 = 4;
+const POINTS_HEADER_LENGTH: usize = 40;
+const GAP_FRAME_LENGTH: usize = 32;
+const TRIGGER_FRAME_LENGTH: usize = 16;
+const RANGE_FRAME_LENGTH: usize = 24;
+const HEARTBEAT_FRAME_LENGTH: usize = 24;
+const LEGACY_POINT_LENGTH: usize = 16;
+/// The shortest frame the encoder can always make progress with: a points frame of a single
+/// point, which is longer than any other kind of frame.
+pub const MIN_FRAME_LENGTH: usize = POINTS_HEADER_LENGTH + 2 + varint_length(u32::MAX as u64);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WireFormat {
+    Legacy,
+    V2,
+}
+
+impl WireFormat {
+    /// Picks the newest format the client offered in its `Sec-WebSocket-Protocol` header.
+    pub fn negotiate<'a>(offered: impl IntoIterator<Item = &'a str>) -> WireFormat {
+        if offered
+            .into_iter()
+            .any(|protocol| protocol == V2_SUBPROTOCOL)
+        {
+            WireFormat::V2
+        } else {
+            WireFormat::Legacy
+        }
+    }
+
+    pub fn subprotocol(&self) -> Option<&'static str> {
+        match self {
+            WireFormat::Legacy => None,
+            WireFormat::V2 => Some(V2_SUBPROTOCOL),
+        }
+    }
+}
+
+/// Turns the points a client should see into frames, one connection's worth.
+///
+/// Points are recognised by their channel and index, so ones that were sent already are
+/// skipped, and missing ones are reported with a gap frame.
+pub struct StreamEncoder {
+    format: WireFormat,
+    sequence: u32,
+    channels: [ChannelStream; CHANNELS],
+}
+
+/// What the encoder remembers about one channel's points.
+#[derive(Clone, Copy)]
+struct ChannelStream {
+    /// How codes turn into volts for every ADC settings, see `AdcSettings::index`.
+    curves: [ProbeCalibration; AdcSettings::COUNT],
+    last_sent: Option<OscilliscopePoint>,
+    announced_trigger: Option<u32>,
+    announced_adc: AdcSettings,
+}
+
+impl StreamEncoder {
+    pub fn new(
+        format: WireFormat,
+        curves: [[ProbeCalibration; AdcSettings::COUNT]; CHANNELS],
+    ) -> StreamEncoder {
+        StreamEncoder {
+            format,
+            sequence: 0,
+            channels: curves.map(|curves| ChannelStream {
+                curves,
+                last_sent: None,
+                announced_trigger: None,
+                announced_adc: AdcSettings::DEFAULT,
+            }),
+        }
+    }
+
+    /// Changes how `channel`'s codes turn into volts, from the next points frame on.
+    pub fn set_curves(&mut self, channel: u8, curves: [ProbeCalibration; AdcSettings::COUNT]) {
+        self.channels[channel as usize].curves = curves;
+    }
+
+    /// Encodes the next frame for the points of `channel` among `points` into `frame`.
+    ///
+    /// Points of other channels are passed over, so every channel is encoded by going through
+    /// the same points once for each. Returns how many of `points` were dealt with and how many
+    /// bytes of `frame` were written. Either can be zero, but never both as long as there are
+    /// points left.
+    pub fn encode(
+        &mut self,
+        channel: u8,
+        points: &[OscilliscopePoint],
+        frame: &mut [u8],
+    ) -> (usize, usize) {
+        let StreamEncoder {
+            format,
+            sequence,
+            channels,
+        } = self;
+        let stream = &mut channels[channel as usize];
+
+        // The legacy format has no way to tell channels apart, so it only gets the first one.
+        if *format == WireFormat::Legacy && channel != 0 {
+            return (points.len(), 0);
+        }
+
+        let mut positions = (0..points.len()).filter(|&i| points[i].channel == channel);
+        let Some(start) = positions.find(|&i| match stream.last_sent {
+            Some(last) => points[i].index.wrapping_sub(last.index) as i32 > 0,
+            None => true,
+        }) else {
+            return (points.len(), 0);
+        };
+        let first = points[start];
+
+        // The legacy format has no way to tell about gaps.
+        if let (Some(last), WireFormat::V2) = (stream.last_sent, *format) {
+            let lost = first.index.wrapping_sub(last.index) - 1;
+            if lost > 0 {
+                // Pretend the lost points were sent, the gap frame accounts for them.
+                stream.last_sent = Some(OscilliscopePoint {
+                    index: first.index.wrapping_sub(1),
+                    ..last
+                });
+                return (
+                    start,
+                    encode_gap(sequence, channel, lost, &last, &first, frame),
+                );
+            }
+        }
+
+        // Heartbeats are not points of their own, the next one shares their index.
+        if first.heartbeat {
+            return match format {
+                WireFormat::Legacy => (start + 1, 0),
+                WireFormat::V2 => {
+                    let curve = stream.curves[first.adc.index()];
+                    let length = encode_heartbeat(sequence, channel, curve, &first, frame);
+                    (start + 1, length)
+                }
+            };
+        }
+
+        if first.adc != stream.announced_adc && *format == WireFormat::V2 {
+            stream.announced_adc = first.adc;
+            return (start, encode_range(sequence, channel, &first, frame));
+        }
+
+        if first.trigger
+            && *format == WireFormat::V2
+            && stream.announced_trigger != Some(first.index)
+        {
+            stream.announced_trigger = Some(first.index);
+            return (start, encode_trigger(sequence, channel, &first, frame));
+        }
+
+        // Only consecutive points with the same ADC settings go in the same frame, so a gap or a
+        // range change ends it, and the trigger point starts a new one after its trigger frame.
+        let mut previous = first.index;
+        let run = core::iter::once(start).chain(positions.take_while(move |&i| {
+            let consecutive = points[i].index == previous.wrapping_add(1)
+                && !points[i].trigger
+                && !points[i].heartbeat
+                && points[i].adc == first.adc;
+            previous = points[i].index;
+            consecutive
+        }));
+        let run_points = run.clone().map(|i| &points[i]);
+
+        let (count, length) = match format {
+            WireFormat::Legacy => encode_legacy(run_points, frame),
+            WireFormat::V2 => {
+                let curve = stream.curves[first.adc.index()];
+                encode_points(sequence, channel, curve, run_points, frame)
+            }
+        };
+        match run.take(count).last() {
+            Some(last) => {
+                stream.last_sent = Some(points[last]);
+                (last + 1, length)
+            }
+            None => (start, length),
+        }
+    }
+}
+
+fn write_frame_header(frame_type: u8, channel: u8, sequence: &mut u32, frame: &mut [u8]) {
+    frame[0] = PROTOCOL_VERSION;
+    frame[1] = frame_type;
+    frame[2] = channel;
+    frame[3] = 0;
+    frame[4..8].copy_from_slice(&sequence.to_le_bytes());
+    *sequence = sequence.wrapping_add(1);
+}
+
+fn encode_gap(
+    sequence: &mut u32,
+    channel: u8,
+    lost: u32,
+    before: &OscilliscopePoint,
+    after: &OscilliscopePoint,
+    frame: &mut [u8],
+) -> usize {
+    let frame = &mut frame[..GAP_FRAME_LENGTH];
+    frame.fill(0);
+    write_frame_header(FRAME_TYPE_GAP, channel, sequence, frame);
+    frame[8..12].copy_from_slice(&lost.to_le_bytes());
+    frame[16..24].copy_from_slice(&before.microsecond.to_le_bytes());
+    frame[24..32].copy_from_slice(&after.microsecond.to_le_bytes());
+    GAP_FRAME_LENGTH
+}
+
+fn encode_trigger(
+    sequence: &mut u32,
+    channel: u8,
+    point: &OscilliscopePoint,
+    frame: &mut [u8],
+) -> usize {
+    let frame = &mut frame[..TRIGGER_FRAME_LENGTH];
+    frame.fill(0);
+    write_frame_header(FRAME_TYPE_TRIGGER, channel, sequence, frame);
+    frame[8..16].copy_from_slice(&point.microsecond.to_le_bytes());
+    TRIGGER_FRAME_LENGTH
+}
+
+fn encode_range(
+    sequence: &mut u32,
+    channel: u8,
+    point: &OscilliscopePoint,
+    frame: &mut [u8],
+) -> usize {
+    let frame = &mut frame[..RANGE_FRAME_LENGTH];
+    frame.fill(0);
+    write_frame_header(FRAME_TYPE_RANGE, channel, sequence, frame);
+    frame[8..16].copy_from_slice(&point.microsecond.to_le_bytes());
+    frame[16] = point.adc.attenuation as u8;
+    frame[17] = point.adc.scheme as u8;
+    let full_scale_voltage = point.adc.attenuation.full_scale_voltage() as f32;
+    frame[20..24].copy_from_slice(&full_scale_voltage.to_le_bytes());
+    RANGE_FRAME_LENGTH
+}
+
+fn encode_heartbeat(
+    sequence: &mut u32,
+    channel: u8,
+    curve: ProbeCalibration,
+    point: &OscilliscopePoint,
+    frame: &mut [u8],
+) -> usize {
+    let frame = &mut frame[..HEARTBEAT_FRAME_LENGTH];
+    frame.fill(0);
+    write_frame_header(FRAME_TYPE_HEARTBEAT, channel, sequence, frame);
+    frame[8..16].copy_from_slice(&point.microsecond.to_le_bytes());
+    let volts = curve.volts_at(whole_codes(point.code)) as f32;
+    frame[16..20].copy_from_slice(&volts.to_le_bytes());
+    HEARTBEAT_FRAME_LENGTH
+}
+
+fn encode_points<'p>(
+    sequence: &mut u32,
+    channel: u8,
+    curve: ProbeCalibration,
+    points: impl Iterator<Item = &'p OscilliscopePoint> + Clone,
+    frame: &mut [u8],
+) -> (usize, usize) {
+    let Some(base) = points.clone().next().map(|point| point.microsecond) else {
+        return (0, 0);
+    };
+
+    // Take points while they fit, and while the time since the previous one fits in a u32. Every
+    // code gets as many bits below a whole code as the finest one needs.
+    let mut count = 0;
+    let mut fraction_bits = 0;
+    let mut deltas_length = 0;
+    let mut previous = base;
+    for point in points.clone().take(u16::MAX as usize) {
+        let Some(delta) =
+            (point.microsecond.checked_sub(previous)).filter(|&delta| delta <= u32::MAX as u64)
+        else {
+            break;
+        };
+        let bits = fraction_bits
+            .max(CODE_FRACTION_BITS - point.code.trailing_zeros().min(CODE_FRACTION_BITS));
+        let delta_length = varint_length(delta);
+        if POINTS_HEADER_LENGTH
+            + packed_codes_length(count + 1, bits)
+            + deltas_length
+            + delta_length
+            > frame.len()
+        {
+            break;
+        }
+        previous = point.microsecond;
+        fraction_bits = bits;
+        deltas_length += delta_length;
+        count += 1;
+    }
+    if count == 0 {
+        return (0, 0);
+    }
+
+    let length = POINTS_HEADER_LENGTH + packed_codes_length(count, fraction_bits) + deltas_length;
+    let frame = &mut frame[..length];
+    frame.fill(0);
+
+    write_frame_header(FRAME_TYPE_POINTS, channel, sequence, frame);
+    frame[8..10].copy_from_slice(&(count as u16).to_le_bytes());
+    frame[10] = fraction_bits as u8;
+    frame[16..24].copy_from_slice(&base.to_le_bytes());
+    for (bytes, coefficient) in frame[24..40].chunks_exact_mut(4).zip(curve.coefficients) {
+        bytes.copy_from_slice(&coefficient.to_le_bytes());
+    }
+
+    let bits = 12 + fraction_bits as usize;
+    let (codes, deltas) =
+        frame[POINTS_HEADER_LENGTH..].split_at_mut(packed_codes_length(count, fraction_bits));
+    let mut previous = base;
+    let mut written = 0;
+    for (i, point) in points.take(count).enumerate() {
+        let code = (point.code >> (CODE_FRACTION_BITS - fraction_bits)) as u32;
+        let (byte, shift) = (i * bits / 8, i * bits % 8);
+        for (j, packed) in codes[byte..]
+            .iter_mut()
+            .take((shift + bits).div_ceil(8))
+            .enumerate()
+        {
+            *packed |= ((code << shift) >> (8 * j)) as u8;
+        }
+
+        written += write_varint(point.microsecond - previous, &mut deltas[written..]);
+        previous = point.microsecond;
+    }
+
+    (count, length)
+}
+
+fn encode_legacy<'p>(
+    points: impl Iterator<Item = &'p OscilliscopePoint>,
+    frame: &mut [u8],
+) -> (usize, usize) {
+    let mut count = 0;
+    for (point, bytes) in points.zip(frame.chunks_exact_mut(LEGACY_POINT_LENGTH)) {
+        bytes[..8].copy_from_slice(&point.voltage.to_le_bytes());
+        let second = point.microsecond as f64 / 1_000_000f64;
+        bytes[8..].copy_from_slice(&second.to_le_bytes());
+        count += 1;
+    }
+    (count, count * LEGACY_POINT_LENGTH)
+}
+
+/// Turns the code of a point into ADC codes, which need not be whole.
+fn whole_codes(code: u16) -> f64 {
+    code as f64 / (1 << CODE_FRACTION_BITS) as f64
+}
+
+fn packed_codes_length(count: usize, fraction_bits: u32) -> usize {
+    (count * (12 + fraction_bits as usize)).div_ceil(8)
+}
+
+/// How many bytes `write_varint` takes for `value`.
+const fn varint_length(value: u64) -> usize {
+    let bits = (u64::BITS - value.leading_zeros()) as usize;
+    if bits == 0 {
+        1
+    } else {
+        bits.div_ceil(7)
+    }
+}
+
+/// Writes `value` seven bits at a time, lowest first, with the top bit set on all but the last
+/// byte. Returns how many bytes it took.
+fn write_varint(mut value: u64, to: &mut [u8]) -> usize {
+    let mut length = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            to[length] = byte;
+            return length + 1;
+        }
+        to[length] = byte | 0x80;
+        length += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Half a volt per code, from -1 V at code 0.
+    const CURVE: ProbeCalibration = ProbeCalibration {
+        coefficients: [-1f32, 2047.5, 0f32, 0f32],
+    };
+
+    fn point(index: u32) -> OscilliscopePoint {
+        OscilliscopePoint {
+            voltage: 0f64,
+            microsecond: 1_500_000 + index as u64 * 1000,
+            code: (index * 100 + 7) as u16,
+            index,
+            channel: 0,
+            trigger: false,
+            heartbeat: false,
+            adc: AdcSettings::DEFAULT,
+        }
+    }
+
+    fn encoder(format: WireFormat) -> StreamEncoder {
+        StreamEncoder::new(format, [[CURVE; AdcSettings::COUNT]; CHANNELS])
+    }
+
+    /// Encodes every batch like the WebSocket server does, collecting the frames.
+    fn encode_all(encoder: &mut StreamEncoder, batches: &[&[OscilliscopePoint]]) -> Vec<Vec<u8>> {
+        encode_all_into(encoder, batches, &mut [0u8; 240])
+    }
+
+    fn encode_all_into(
+        encoder: &mut StreamEncoder,
+        batches: &[&[OscilliscopePoint]],
+        frame: &mut [u8],
+    ) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        for batch in batches.iter().copied() {
+            for channel in 0..CHANNELS as u8 {
+                let mut points = batch;
+                while !points.is_empty() {
+                    let (used, length) = encoder.encode(channel, points, frame);
+                    assert!(used > 0 || length > 0);
+                    if length > 0 {
+                        frames.push(frame[..length].to_vec());
+                    }
+                    points = &points[used..];
+                }
+            }
+        }
+        frames
+    }
+
+    /// Decodes a points frame into (microsecond, code) pairs, with the codes like the points
+    /// have them.
+    fn decode_points(frame: &[u8]) -> Vec<(u64, u16)> {
+        assert_eq!(frame[1], FRAME_TYPE_POINTS);
+        let count = u16::from_le_bytes([frame[8], frame[9]]) as usize;
+        let fraction_bits = frame[10] as u32;
+        let bits = 12 + fraction_bits as usize;
+        let mut time = u64::from_le_bytes(frame[16..24].try_into().unwrap());
+        let codes = &frame[POINTS_HEADER_LENGTH..];
+        let mut deltas = codes[packed_codes_length(count, fraction_bits)..].iter();
+        let points = (0..count)
+            .map(|i| {
+                let packed = &codes[i * bits / 8..];
+                let word = u32::from_le_bytes([packed[0], packed[1], packed[2], 0]);
+                let code = (word >> (i * bits % 8)) & ((1 << bits) - 1);
+                let mut shift = 0;
+                for byte in deltas.by_ref() {
+                    time += ((byte & 0x7f) as u64) << shift;
+                    shift += 7;
+                    if byte & 0x80 == 0 {
+                        break;
+                    }
+                }
+                (time, (code << (CODE_FRACTION_BITS - fraction_bits)) as u16)
+            })
+            .collect();
+        assert!(deltas.next().is_none());
+        points
+    }
+
+    #[test]
+    fn points_survive_the_round_trip() {
+        let points: Vec<_> = (0..5).map(point).collect();
+        let mut encoder = encoder(WireFormat::V2);
+        let frames = encode_all(&mut encoder, &[&points]);
+
+        assert_eq!(frames.len(), 1);
+        let expected: Vec<_> = points.iter().map(|p| (p.microsecond, p.code)).collect();
+        assert_eq!(decode_points(&frames[0]), expected);
+    }
+
+    /// Turns the codes of a points frame into volts like the page does, with the frame's curve.
+    fn decode_volts(frame: &[u8]) -> Vec<f64> {
+        let coefficients: Vec<_> = frame[24..40]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()) as f64)
+            .collect();
+        decode_points(frame)
+            .into_iter()
+            .map(|(_, code)| {
+                let x = whole_codes(code) / 4095f64;
+                coefficients
+                    .iter()
+                    .rev()
+                    .fold(0f64, |volts, c| volts * x + c)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn points_follow_the_fitted_curve() {
+        // Bends away from the straight line between its ends, by most in the middle.
+        let curve = ProbeCalibration {
+            coefficients: [-30f32, 61f32, 4f32, -6f32],
+        };
+        let points: Vec<_> = (0..12)
+            .map(|i| OscilliscopePoint {
+                code: (1000 + i as u16 * 200) << CODE_FRACTION_BITS,
+                ..point(i)
+            })
+            .collect();
+        let mut encoder =
+            StreamEncoder::new(WireFormat::V2, [[curve; AdcSettings::COUNT]; CHANNELS]);
+        let frames = encode_all(&mut encoder, &[&points]);
+
+        let volts: Vec<_> = frames
+            .iter()
+            .flat_map(|frame| decode_volts(frame))
+            .collect();
+        assert_eq!(volts.len(), points.len());
+        for (volts, point) in volts.iter().zip(&points) {
+            assert!((volts - curve.volts_at(whole_codes(point.code))).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn codes_keep_the_bits_below_a_whole_code() {
+        let whole: Vec<_> = (0..7)
+            .map(|i| OscilliscopePoint {
+                code: (4095 - i as u16) << CODE_FRACTION_BITS,
+                ..point(i)
+            })
+            .collect();
+        let frames = encode_all(&mut encoder(WireFormat::V2), &[&whole]);
+        assert_eq!(frames[0][10], 0);
+        // The first point is at the base timestamp, the others a millisecond apart.
+        assert_eq!(
+            frames[0].len(),
+            POINTS_HEADER_LENGTH + packed_codes_length(7, 0) + 1 + 6 * 2
+        );
+        let expected: Vec<_> = whole.iter().map(|p| (p.microsecond, p.code)).collect();
+        assert_eq!(decode_points(&frames[0]), expected);
+
+        // Oversampled by two bits, one of the points needs both.
+        let mut oversampled = whole.clone();
+        oversampled[3].code += 0b0100;
+        oversampled[5].code -= 0b1000;
+        let frames = encode_all(&mut encoder(WireFormat::V2), &[&oversampled]);
+        assert_eq!(frames[0][10], 2);
+        let expected: Vec<_> = oversampled
+            .iter()
+            .map(|p| (p.microsecond, p.code))
+            .collect();
+        assert_eq!(decode_points(&frames[0]), expected);
+    }
+
+    #[test]
+    fn time_deltas_take_as_few_bytes_as_they_need() {
+        let mut points: Vec<_> = (0..4).map(point).collect();
+        points[1].microsecond = points[0].microsecond + 127;
+        points[2].microsecond = points[1].microsecond + 128;
+        points[3].microsecond = points[2].microsecond + u32::MAX as u64;
+        let frames = encode_all(&mut encoder(WireFormat::V2), &[&points]);
+
+        assert_eq!(frames.len(), 1);
+        let codes = POINTS_HEADER_LENGTH + packed_codes_length(4, 4);
+        assert_eq!(
+            frames[0][codes..],
+            [0, 127, 0x80, 1, 0xff, 0xff, 0xff, 0xff, 0x0f]
+        );
+        let expected: Vec<_> = points.iter().map(|p| (p.microsecond, p.code)).collect();
+        assert_eq!(decode_points(&frames[0]), expected);
+    }
+
+    #[test]
+    fn points_take_a_fraction_of_the_legacy_bytes() {
+        use crate::decimation::{DecimationMode, Decimator, Thresholds};
+
+        // A probe that reads +-31 V, like the one in Settings.toml.
+        let curve = ProbeCalibration {
+            coefficients: [-31f32, 62f32, 0f32, 0f32],
+        };
+        // What comes out of the decimator for a second of a 50 Hz sine sampled at 20 kHz, with a
+        // code or two of noise, decimated the way Settings.toml has it.
+        let mut decimator = Decimator::new(
+            DecimationMode::Slope,
+            Thresholds {
+                tolerance_factor: 0.1,
+                min_voltage_difference: 0.3,
+            },
+            1_000_000,
+            50_000,
+        );
+        let mut noise = 12345u32;
+        let mut points = Vec::new();
+        for i in 0..20_000u64 {
+            noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let second = i as f64 / 20_000f64;
+            let codes = 2047.5
+                + 660f64 * libm::sin(2f64 * core::f64::consts::PI * 50f64 * second)
+                + (noise >> 16) as f64 / 65536f64 * 4f64
+                - 2f64;
+            let code = (codes * (1 << CODE_FRACTION_BITS) as f64) as u16;
+            let point = OscilliscopePoint {
+                voltage: curve.volts_at(whole_codes(code)),
+                microsecond: 1_500_000 + i * 50,
+                code,
+                ..point(0)
+            };
+            decimator.push(point, |kept| points.push(kept));
+        }
+        // As big as the frames the WebSocket tasks send.
+        let mut frame = [0u8; 496];
+        let mut encoder =
+            StreamEncoder::new(WireFormat::V2, [[curve; AdcSettings::COUNT]; CHANNELS]);
+        let frames = encode_all_into(&mut encoder, &[&points], &mut frame);
+
+        let decoded: Vec<_> = frames
+            .iter()
+            .flat_map(|frame| decode_points(frame))
+            .collect();
+        assert_eq!(decoded.len(), points.len());
+        let length: usize = frames.iter().map(Vec::len).sum();
+        let ratio = (points.len() * LEGACY_POINT_LENGTH) as f64 / length as f64;
+        assert!(ratio >= 4f64, "{ratio} for {} points", points.len());
+    }
+
+    #[test]
+    fn new_calibrations_apply_from_the_next_frame() {
+        let points: Vec<_> = (0..6).map(point).collect();
+        let mut encoder = encoder(WireFormat::V2);
+        let mut recalibrated = CURVE;
+        recalibrated.coefficients[0] = -1.25;
+        let mut frames = encode_all(&mut encoder, &[&points[..3]]);
+        encoder.set_curves(0, [recalibrated; AdcSettings::COUNT]);
+        frames.extend(encode_all(&mut encoder, &[&points[3..]]));
+
+        let offsets: Vec<_> = frames
+            .iter()
+            .map(|frame| f32::from_le_bytes(frame[24..28].try_into().unwrap()))
+            .collect();
+        assert_eq!(offsets, [-1f32, -1.25]);
+    }
+
+    #[test]
+    fn overlapping_points_are_sent_once_and_gaps_reported() {
+        let points: Vec<_> = (0..14).map(point).collect();
+        let mut encoder = encoder(WireFormat::V2);
+        let frames = encode_all(
+            &mut encoder,
+            &[&points[0..5], &points[3..8], &points[12..14]],
+        );
+
+        let sequences: Vec<_> = frames
+            .iter()
+            .map(|frame| u32::from_le_bytes(frame[4..8].try_into().unwrap()))
+            .collect();
+        assert_eq!(sequences, [0, 1, 2, 3]);
+
+        assert_eq!(decode_points(&frames[0]).len(), 5);
+        assert_eq!(decode_points(&frames[1]).len(), 3);
+        let gap = &frames[2];
+        assert_eq!(gap[1], FRAME_TYPE_GAP);
+        assert_eq!(u32::from_le_bytes(gap[8..12].try_into().unwrap()), 4);
+        assert_eq!(
+            u64::from_le_bytes(gap[16..24].try_into().unwrap()),
+            points[7].microsecond
+        );
+        assert_eq!(
+            u64::from_le_bytes(gap[24..32].try_into().unwrap()),
+            points[12].microsecond
+        );
+        assert_eq!(decode_points(&frames[3]).len(), 2);
+    }
+
+    #[test]
+    fn trigger_frames_come_right_before_the_trigger_point() {
+        let mut points: Vec<_> = (0..6).map(point).collect();
+        points[3].trigger = true;
+        let mut encoder = encoder(WireFormat::V2);
+        // The trigger point arrives twice, from the history and from the live buffer.
+        let frames = encode_all(&mut encoder, &[&points[..4], &points[3..]]);
+
+        let types: Vec<_> = frames.iter().map(|frame| frame[1]).collect();
+        assert_eq!(
+            types,
+            [
+                FRAME_TYPE_POINTS,
+                FRAME_TYPE_TRIGGER,
+                FRAME_TYPE_POINTS,
+                FRAME_TYPE_POINTS
+            ]
+        );
+        assert_eq!(
+            u64::from_le_bytes(frames[1][8..16].try_into().unwrap()),
+            points[3].microsecond
+        );
+        assert_eq!(
+            decode_points(&frames[2]),
+            [(points[3].microsecond, points[3].code)]
+        );
+        assert_eq!(decode_points(&frames[3]).len(), 2);
+    }
+
+    #[test]
+    fn heartbeats_go_between_points_without_using_up_an_index() {
+        let mut points: Vec<_> = (0..4).map(point).collect();
+        let heartbeat = OscilliscopePoint {
+            microsecond: points[1].microsecond + 600,
+            heartbeat: true,
+            ..points[2]
+        };
+        points.insert(2, heartbeat);
+        let frames = encode_all(&mut encoder(WireFormat::V2), &[&points]);
+
+        let types: Vec<_> = frames.iter().map(|frame| frame[1]).collect();
+        assert_eq!(
+            types,
+            [FRAME_TYPE_POINTS, FRAME_TYPE_HEARTBEAT, FRAME_TYPE_POINTS]
+        );
+        assert_eq!(
+            u64::from_le_bytes(frames[1][8..16].try_into().unwrap()),
+            heartbeat.microsecond
+        );
+        assert_eq!(
+            f32::from_le_bytes(frames[1][16..20].try_into().unwrap()),
+            CURVE.volts_at(whole_codes(heartbeat.code)) as f32
+        );
+        assert_eq!(decode_points(&frames[2]).len(), 2);
+
+        let frames = encode_all(&mut encoder(WireFormat::Legacy), &[&points]);
+        let length: usize = frames.iter().map(Vec::len).sum();
+        assert_eq!(length, 4 * LEGACY_POINT_LENGTH);
+    }
+
+    #[test]
+    fn range_changes_are_announced_and_use_their_line() {
+        let six_db = AdcSettings {
+            attenuation: crate::adc::Attenuation::Db6,
+            ..AdcSettings::DEFAULT
+        };
+        let mut points: Vec<_> = (0..6).map(point).collect();
+        for point in &mut points[2..4] {
+            point.adc = six_db;
+        }
+        let mut curves = [CURVE; AdcSettings::COUNT];
+        curves[six_db.index()].coefficients[1] = 1023.75;
+        let mut encoder = StreamEncoder::new(WireFormat::V2, [curves; CHANNELS]);
+        let frames = encode_all(&mut encoder, &[&points]);
+
+        let types: Vec<_> = frames.iter().map(|frame| frame[1]).collect();
+        assert_eq!(
+            types,
+            [
+                FRAME_TYPE_POINTS,
+                FRAME_TYPE_RANGE,
+                FRAME_TYPE_POINTS,
+                FRAME_TYPE_RANGE,
+                FRAME_TYPE_POINTS
+            ]
+        );
+        let range = &frames[1];
+        assert_eq!(range.len(), RANGE_FRAME_LENGTH);
+        assert_eq!(
+            u64::from_le_bytes(range[8..16].try_into().unwrap()),
+            points[2].microsecond
+        );
+        assert_eq!(range[16..18], [2, 0]);
+        assert_eq!(f32::from_le_bytes(range[20..24].try_into().unwrap()), 1.75);
+        assert_eq!(frames[3][16..18], [3, 0]);
+
+        let slopes: Vec<_> = [&frames[0], &frames[2], &frames[4]]
+            .iter()
+            .map(|frame| f32::from_le_bytes(frame[28..32].try_into().unwrap()))
+            .collect();
+        assert_eq!(slopes, [2047.5, 1023.75, 2047.5]);
+        let counts: Vec<_> = [&frames[0], &frames[2], &frames[4]]
+            .iter()
+            .map(|frame| decode_points(frame).len())
+            .collect();
+        assert_eq!(counts, [2, 2, 2]);
+    }
+
+    #[test]
+    fn interleaved_channels_get_frames_of_their_own() {
+        // Both channels number their points on their own.
+        let points: Vec<_> = (0..10)
+            .map(|i| OscilliscopePoint {
+                channel: (i % 2) as u8,
+                ..point(i / 2)
+            })
+            .collect();
+        let mut encoder = encoder(WireFormat::V2);
+        let frames = encode_all(&mut encoder, &[&points[..6], &points[4..]]);
+
+        let channels: Vec<_> = frames.iter().map(|frame| frame[2]).collect();
+        assert_eq!(channels, [0, 1, 0, 1]);
+        let sequences: Vec<_> = frames
+            .iter()
+            .map(|frame| u32::from_le_bytes(frame[4..8].try_into().unwrap()))
+            .collect();
+        assert_eq!(sequences, [0, 1, 2, 3]);
+        let counts: Vec<_> = frames
+            .iter()
+            .map(|frame| decode_points(frame).len())
+            .collect();
+        assert_eq!(counts, [3, 3, 2, 2]);
+    }
+
+    #[test]
+    fn legacy_clients_get_no_gap_frames() {
+        let mut encoder = encoder(WireFormat::Legacy);
+        let frames = encode_all(&mut encoder, &[&[point(0)], &[point(5)]]);
+        assert_eq!(frames.len(), 2);
+        assert!(frames
+            .iter()
+            .all(|frame| frame.len() == LEGACY_POINT_LENGTH));
+
+        // Nor any points but those of the first channel.
+        let second_channel = OscilliscopePoint {
+            channel: 1,
+            ..point(6)
+        };
+        assert!(encode_all(&mut encoder, &[&[second_channel]]).is_empty());
+    }
+}
@@ -0,0 +1,326 @@
+//! Turning a steady stream of ADC conversions into timestamped codes.
+
+/// Turns sample indices into microseconds, for samples taken at a fixed rate.
+///
+/// The rate is whatever the hardware achieves, a whole number of clock ticks per sample, which
+/// is not always exactly the rate that was asked for. Timestamps never drift from the clock,
+/// no matter how long the scope runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleClock {
+    start_us: u64,
+    ticks_per_sample: u32,
+    clock_hz: u32,
+}
+
+impl SampleClock {
+    /// Picks the whole number of ticks per sample that comes closest to `rate_hz`.
+    pub fn for_rate(start_us: u64, rate_hz: u32, clock_hz: u32) -> SampleClock {
+        let ticks_per_sample = ((clock_hz + rate_hz / 2) / rate_hz).max(1);
+        SampleClock {
+            start_us,
+            ticks_per_sample,
+            clock_hz,
+        }
+    }
+
+    pub fn ticks_per_sample(&self) -> u32 {
+        self.ticks_per_sample
+    }
+
+    /// The rate the samples are actually taken at.
+    pub fn rate_hz(&self) -> f64 {
+        self.clock_hz as f64 / self.ticks_per_sample as f64
+    }
+
+    /// When the sample with the given index was taken.
+    pub fn microsecond(&self, index: u64) -> u64 {
+        let ticks = index * self.ticks_per_sample as u64;
+        let clock_hz = self.clock_hz as u64;
+        self.start_us + ticks / clock_hz * 1_000_000 + ticks % clock_hz * 1_000_000 / clock_hz
+    }
+
+    /// The index of the last sample taken at or before `microsecond`.
+    ///
+    /// Timestamps are rounded down to whole microseconds, so a sample counts as taken during the
+    /// whole microsecond it was taken in.
+    pub fn index_at(&self, microsecond: u64) -> u64 {
+        let until_us = microsecond.saturating_sub(self.start_us) as u128 + 1;
+        let sample_us = self.ticks_per_sample as u128 * 1_000_000;
+        ((until_us * self.clock_hz as u128 - 1) / sample_us) as u64
+    }
+}
+
+/// A sample that is due, see `Pacer::poll`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tick {
+    pub index: u64,
+    /// Samples before this one whose time passed without them being taken.
+    pub missed: u64,
+}
+
+/// Paces samples at a fixed rate off a free running microsecond counter.
+pub struct Pacer {
+    clock: SampleClock,
+    next_index: u64,
+}
+
+impl Pacer {
+    pub fn new(start_us: u64, rate_hz: u32) -> Pacer {
+        Pacer {
+            clock: SampleClock::for_rate(start_us, rate_hz, 1_000_000),
+            next_index: 0,
+        }
+    }
+
+    pub fn clock(&self) -> SampleClock {
+        self.clock
+    }
+
+    /// Returns the sample that is due at `now_us`, if one is.
+    ///
+    /// When the caller came too late for more than one sample, only the latest one is taken, and
+    /// the others are counted as missed rather than taken late.
+    pub fn poll(&mut self, now_us: u64) -> Option<Tick> {
+        if now_us < self.clock.microsecond(self.next_index) {
+            return None;
+        }
+
+        let index = self.clock.index_at(now_us);
+        let missed = index - self.next_index;
+        self.next_index = index + 1;
+        Some(Tick { index, missed })
+    }
+}
+
+/// Measures how many samples actually come in per second.
+pub struct RateMeter {
+    window_start_us: u64,
+    samples: u64,
+}
+
+impl RateMeter {
+    const WINDOW_US: u64 = 1_000_000;
+
+    pub fn new(now_us: u64) -> RateMeter {
+        RateMeter {
+            window_start_us: now_us,
+            samples: 0,
+        }
+    }
+
+    /// Counts samples that came in, and returns the rate once a second has passed.
+    pub fn count(&mut self, samples: u32, now_us: u64) -> Option<f64> {
+        self.samples += samples as u64;
+        let elapsed_us = now_us.saturating_sub(self.window_start_us);
+        if elapsed_us < Self::WINDOW_US {
+            return None;
+        }
+
+        let rate = self.samples as f64 * 1e6 / elapsed_us as f64;
+        self.window_start_us = now_us;
+        self.samples = 0;
+        Some(rate)
+    }
+}
+
+/// One conversion as the ADC's digital controller writes it to memory.
+///
+/// Every conversion takes a little endian word: the code in the low 12 bits, then a reserved
+/// bit, then the channel in the next 4 bits and the ADC unit in the bit after that.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DmaSample {
+    pub code: u16,
+    pub channel: u8,
+    pub unit: u8,
+}
+
+impl DmaSample {
+    pub const SIZE: usize = 4;
+
+    pub fn from_word(word: u32) -> DmaSample {
+        DmaSample {
+            code: (word & 0xfff) as u16,
+            channel: ((word >> 13) & 0xf) as u8,
+            unit: ((word >> 17) & 0x1) as u8,
+        }
+    }
+
+    /// Decodes every complete conversion in a block the DMA filled.
+    pub fn decode_block(block: &[u8]) -> impl Iterator<Item = DmaSample> + '_ {
+        block.chunks_exact(Self::SIZE).map(|bytes| {
+            DmaSample::from_word(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        })
+    }
+}
+
+/// Averages every `samples_per_point` consecutive samples into one code.
+///
+/// Each average is stamped with the index of its first sample.
+pub struct Averager {
+    samples_per_point: u32,
+    sum: u32,
+    count: u32,
+    first_index: u64,
+}
+
+impl Averager {
+    pub fn new(samples_per_point: u32) -> Averager {
+        Averager {
+            samples_per_point: samples_per_point.max(1),
+            sum: 0,
+            count: 0,
+            first_index: 0,
+        }
+    }
+
+    /// Takes the next sample, and returns the average and its index once enough have come in.
+    pub fn push(&mut self, code: u16, index: u64) -> Option<(u16, u64)> {
+        if self.count == 0 {
+            self.first_index = index;
+        }
+        self.sum += code as u32;
+        self.count += 1;
+        if self.count < self.samples_per_point {
+            return None;
+        }
+
+        let average = (self.sum / self.count) as u16;
+        self.sum = 0;
+        self.count = 0;
+        Some((average, self.first_index))
+    }
+
+    /// Forgets a partial average, for when samples were lost in the middle of it.
+    pub fn reset(&mut self) {
+        self.sum = 0;
+        self.count = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn timestamps_follow_the_achieved_rate() {
+        // 2.5 MHz / 20 kHz is exactly 125 ticks.
+        let clock = SampleClock::for_rate(1_000, 20_000, 2_500_000);
+        assert_eq!(clock.ticks_per_sample(), 125);
+        assert_eq!(clock.rate_hz(), 20_000f64);
+        assert_eq!(clock.microsecond(0), 1_000);
+        assert_eq!(clock.microsecond(1), 1_050);
+        assert_eq!(clock.microsecond(20_000), 1_001_000);
+
+        // 2.5 MHz / 30 kHz is 83.3 ticks, so the samples come at 30.12 kHz.
+        let clock = SampleClock::for_rate(0, 30_000, 2_500_000);
+        assert_eq!(clock.ticks_per_sample(), 83);
+        assert_eq!(clock.microsecond(2_500_000), 83_000_000);
+    }
+
+    #[test]
+    fn timestamps_do_not_overflow_after_years() {
+        let clock = SampleClock::for_rate(0, 80_000, 2_500_000);
+        let ten_years_of_samples = 10 * 365 * 24 * 3600 * 80_000u64;
+        let seconds = clock.microsecond(ten_years_of_samples) / 1_000_000;
+        assert!(
+            (seconds as f64 - (10 * 365 * 24 * 3600) as f64 * 80_000f64 / clock.rate_hz()).abs()
+                < 1f64
+        );
+    }
+
+    #[test]
+    fn indices_are_found_from_timestamps() {
+        for clock in [
+            SampleClock::for_rate(12_345, 30_000, 2_500_000),
+            SampleClock::for_rate(0, 7, 1_000_000),
+        ] {
+            for index in [0, 1, 2, 999, 1_000_000] {
+                let microsecond = clock.microsecond(index);
+                assert_eq!(clock.index_at(microsecond), index);
+                assert_eq!(clock.index_at(clock.microsecond(index + 1) - 1), index);
+            }
+        }
+    }
+
+    #[test]
+    fn pacer_skips_samples_it_is_too_late_for() {
+        let mut pacer = Pacer::new(5_000, 1000);
+        assert_eq!(
+            pacer.poll(5_000),
+            Some(Tick {
+                index: 0,
+                missed: 0
+            })
+        );
+        assert_eq!(pacer.poll(5_500), None);
+        assert_eq!(
+            pacer.poll(6_020),
+            Some(Tick {
+                index: 1,
+                missed: 0
+            })
+        );
+        assert_eq!(
+            pacer.poll(8_500),
+            Some(Tick {
+                index: 3,
+                missed: 1
+            })
+        );
+        assert_eq!(pacer.poll(8_999), None);
+        assert_eq!(
+            pacer.poll(9_000),
+            Some(Tick {
+                index: 4,
+                missed: 0
+            })
+        );
+        assert_eq!(pacer.clock().microsecond(4), 9_000);
+    }
+
+    #[test]
+    fn rate_is_measured_every_second() {
+        let mut meter = RateMeter::new(0);
+        assert_eq!(meter.count(400, 500_000), None);
+        assert_eq!(meter.count(600, 1_000_000), Some(1000f64));
+        assert_eq!(meter.count(100, 1_500_000), None);
+        assert_eq!(meter.count(100, 3_000_000), Some(100f64));
+    }
+
+    #[test]
+    fn dma_words_are_decoded() {
+        let word = 0xabcu32 | (1 << 12) | (5 << 13) | (1 << 17) | (0x3fff << 18);
+        assert_eq!(
+            DmaSample::from_word(word),
+            DmaSample {
+                code: 0xabc,
+                channel: 5,
+                unit: 1
+            }
+        );
+
+        let mut block = Vec::new();
+        block.extend_from_slice(&(0x123u32 | (2 << 13)).to_le_bytes());
+        block.extend_from_slice(&0xfffu32.to_le_bytes());
+        block.push(0xff);
+        let samples: Vec<_> = DmaSample::decode_block(&block).collect();
+        assert_eq!(samples.len(), 2);
+        assert_eq!((samples[0].code, samples[0].channel), (0x123, 2));
+        assert_eq!((samples[1].code, samples[1].channel), (0xfff, 0));
+    }
+
+    #[test]
+    fn averages_are_stamped_with_their_first_index() {
+        let mut averager = Averager::new(3);
+        let averages: Vec<_> = (0..7u64)
+            .filter_map(|index| averager.push(index as u16 * 10, index))
+            .collect();
+        assert_eq!(averages, [(10, 0), (40, 3)]);
+
+        averager.reset();
+        assert_eq!(averager.push(1, 10), None);
+        assert_eq!(averager.push(2, 11), None);
+        assert_eq!(averager.push(6, 12), Some((3, 10)));
+    }
+}
@@ -0,0 +1,481 @@
+//! Holding repetitive signals still on screen by lining frames up on an edge.
+
+use crate::{
+    control::{AcquisitionMode, AcquisitionState},
+    websocket_logistics::{OscilliscopePoint, CHANNELS},
+};
+use alloc::collections::VecDeque;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slope {
+    Rising,
+    Falling,
+    Either,
+}
+
+impl Slope {
+    /// `"off"` means free running, without a trigger.
+    pub fn from_config(slope: &str) -> Option<Slope> {
+        match slope {
+            "rising" => Some(Slope::Rising),
+            "falling" => Some(Slope::Falling),
+            "either" => Some(Slope::Either),
+            "off" => None,
+            _ => panic!("Unknown trigger slope {slope}."),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Slope::Rising => "rising",
+            Slope::Falling => "falling",
+            Slope::Either => "either",
+        }
+    }
+
+    fn crossed(&self, previous: f64, current: f64, level: f64) -> bool {
+        let rising = previous < level && current >= level;
+        let falling = previous > level && current <= level;
+        match self {
+            Slope::Rising => rising,
+            Slope::Falling => falling,
+            Slope::Either => rising || falling,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TriggerSettings {
+    /// The channel whose signal fires the trigger. Frames include every channel.
+    pub channel: u8,
+    pub slope: Slope,
+    /// Volts at the probes the signal has to cross.
+    pub level_volts: f64,
+    /// How much of every frame comes before the trigger.
+    pub pre_trigger_percent: u8,
+    pub frame_us: u64,
+    /// Shortest time between two triggers, counted from the earlier one. Frames never overlap,
+    /// however short it is.
+    pub holdoff_us: u64,
+}
+
+impl TriggerSettings {
+    fn pre_trigger_us(&self) -> u64 {
+        self.frame_us * self.pre_trigger_percent.min(100) as u64 / 100
+    }
+}
+
+/// Finds edges in the measured points and only lets the points around them through.
+///
+/// Points are looked at twice. [`Trigger::detect`] sees every measured point, marks the one the
+/// trigger fires on, which the decimator then always keeps, and steps through the
+/// [`AcquisitionState`]s. [`Trigger::frame`] sees the kept points, and holds the recent ones back
+/// until a trigger shows which of them are needed.
+///
+/// Points of all channels go through the same trigger, so their frames line up.
+pub struct Trigger {
+    settings: Option<TriggerSettings>,
+    mode: AcquisitionMode,
+    state: AcquisitionState,
+    auto_timeout_us: u64,
+    armed_since_us: Option<u64>,
+    armed_from_us: u64,
+    triggered_until_us: u64,
+    previous_voltage: Option<f64>,
+    frames: [ChannelFrame; CHANNELS],
+    pre_trigger_points: usize,
+}
+
+/// One channel's part of the frames.
+struct ChannelFrame {
+    end_us: Option<u64>,
+    pre_trigger: VecDeque<OscilliscopePoint>,
+}
+
+impl Trigger {
+    /// Without `settings` nothing ever triggers, so only auto mode sends anything.
+    ///
+    /// Up to `pre_trigger_points` kept points of every channel are remembered for the part
+    /// before the trigger.
+    pub fn new(
+        settings: Option<TriggerSettings>,
+        mode: AcquisitionMode,
+        auto_timeout_us: u64,
+        pre_trigger_points: usize,
+    ) -> Trigger {
+        Trigger {
+            settings,
+            mode,
+            state: AcquisitionState::Armed,
+            auto_timeout_us,
+            armed_since_us: None,
+            armed_from_us: 0,
+            triggered_until_us: 0,
+            previous_voltage: None,
+            frames: core::array::from_fn(|_| ChannelFrame {
+                end_us: None,
+                pre_trigger: VecDeque::with_capacity(pre_trigger_points),
+            }),
+            pre_trigger_points,
+        }
+    }
+
+    pub fn settings(&self) -> Option<TriggerSettings> {
+        self.settings
+    }
+
+    pub fn state(&self) -> AcquisitionState {
+        self.state
+    }
+
+    pub fn set_mode(&mut self, mode: AcquisitionMode) {
+        self.mode = mode;
+        if mode != AcquisitionMode::Auto && self.state == AcquisitionState::FreeRunning {
+            self.arm();
+        }
+    }
+
+    /// Arms the trigger again, from whatever state it was in.
+    pub fn run(&mut self) {
+        if self.state != AcquisitionState::Triggered {
+            self.arm();
+        }
+    }
+
+    /// Stops sending points, right away.
+    pub fn stop(&mut self) {
+        self.state = AcquisitionState::Stopped;
+        for frame in &mut self.frames {
+            frame.end_us = None;
+            frame.pre_trigger.clear();
+        }
+    }
+
+    fn arm(&mut self) {
+        self.state = AcquisitionState::Armed;
+        self.armed_since_us = None;
+    }
+
+    /// Marks `point` as the trigger point if the signal crossed the level on the way to it.
+    ///
+    /// Points of every channel move the acquisition along, but only the trigger channel's fire.
+    pub fn detect(&mut self, point: &mut OscilliscopePoint) {
+        let previous = match self.settings {
+            Some(settings) if settings.channel == point.channel => {
+                self.previous_voltage.replace(point.voltage)
+            }
+            _ => None,
+        };
+
+        if self.state == AcquisitionState::Triggered && point.microsecond >= self.triggered_until_us
+        {
+            if self.mode == AcquisitionMode::Single {
+                self.state = AcquisitionState::Done;
+            } else {
+                self.arm();
+            }
+        }
+        if !matches!(
+            self.state,
+            AcquisitionState::Armed | AcquisitionState::FreeRunning
+        ) {
+            return;
+        }
+        let armed_since_us = *self.armed_since_us.get_or_insert(point.microsecond);
+
+        if let (Some(settings), Some(previous)) = (self.settings, previous) {
+            if point.microsecond >= self.armed_from_us
+                && settings
+                    .slope
+                    .crossed(previous, point.voltage, settings.level_volts)
+            {
+                point.trigger = true;
+                self.state = AcquisitionState::Triggered;
+                self.triggered_until_us =
+                    point.microsecond + settings.frame_us - settings.pre_trigger_us();
+                self.armed_from_us = point.microsecond + settings.holdoff_us;
+                return;
+            }
+        }
+
+        if self.mode == AcquisitionMode::Auto
+            && self.state == AcquisitionState::Armed
+            && point.microsecond - armed_since_us >= self.auto_timeout_us
+        {
+            self.state = AcquisitionState::FreeRunning;
+        }
+    }
+
+    /// Hands the points that should be sent to `send`, oldest first.
+    ///
+    /// Frames also get the last point before and the first point after them on every channel,
+    /// so the lines reach all the way to their edges.
+    ///
+    /// Heartbeats only go out while running freely, since triggered frames end where they end.
+    pub fn frame(&mut self, point: OscilliscopePoint, mut send: impl FnMut(OscilliscopePoint)) {
+        if point.heartbeat {
+            if self.state == AcquisitionState::FreeRunning {
+                send(point);
+            }
+            return;
+        }
+        if let (true, Some(settings)) = (point.trigger, self.settings) {
+            let start_us = point.microsecond.saturating_sub(settings.pre_trigger_us());
+            let end_us = point.microsecond + settings.frame_us - settings.pre_trigger_us();
+            for frame in &mut self.frames {
+                let first = frame
+                    .pre_trigger
+                    .iter()
+                    .position(|earlier| earlier.microsecond >= start_us)
+                    .unwrap_or(frame.pre_trigger.len());
+                frame
+                    .pre_trigger
+                    .drain(..first.saturating_sub(1))
+                    .for_each(drop);
+                frame.pre_trigger.drain(..).for_each(&mut send);
+                frame.end_us = Some(end_us);
+            }
+            send(point);
+            return;
+        }
+
+        let frame = &mut self.frames[point.channel as usize];
+        if let Some(end) = frame.end_us {
+            if point.microsecond >= end {
+                frame.end_us = None;
+            }
+            send(point);
+        } else if self.state == AcquisitionState::FreeRunning {
+            send(point);
+        } else if self.pre_trigger_points > 0 && self.state != AcquisitionState::Stopped {
+            if frame.pre_trigger.len() == self.pre_trigger_points {
+                frame.pre_trigger.pop_front();
+            }
+            frame.pre_trigger.push_back(point);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adc::AdcSettings;
+    use alloc::vec::Vec;
+
+    const SETTINGS: TriggerSettings = TriggerSettings {
+        channel: 0,
+        slope: Slope::Rising,
+        level_volts: 1f64,
+        pre_trigger_percent: 25,
+        frame_us: 1000,
+        holdoff_us: 0,
+    };
+
+    /// A square wave between 0 and 2 volts, one point every 10 microseconds.
+    fn square_wave(period_us: u64, length_us: u64) -> impl Iterator<Item = OscilliscopePoint> {
+        (0..length_us / 10).map(move |i| {
+            let microsecond = i * 10;
+            OscilliscopePoint {
+                voltage: if microsecond % period_us < period_us / 2 {
+                    0f64
+                } else {
+                    2f64
+                },
+                microsecond,
+                code: 0,
+                index: i as u32,
+                channel: 0,
+                trigger: false,
+                heartbeat: false,
+                adc: AdcSettings::DEFAULT,
+            }
+        })
+    }
+
+    fn run(
+        settings: TriggerSettings,
+        points: impl Iterator<Item = OscilliscopePoint>,
+    ) -> Vec<OscilliscopePoint> {
+        let mut trigger = Trigger::new(Some(settings), AcquisitionMode::Normal, 0, 1000);
+        feed(&mut trigger, points)
+    }
+
+    fn feed(
+        trigger: &mut Trigger,
+        points: impl Iterator<Item = OscilliscopePoint>,
+    ) -> Vec<OscilliscopePoint> {
+        let mut sent = Vec::new();
+        for mut point in points {
+            trigger.detect(&mut point);
+            trigger.frame(point, |point| sent.push(point));
+        }
+        sent
+    }
+
+    fn trigger_times(sent: &[OscilliscopePoint]) -> Vec<u64> {
+        sent.iter()
+            .filter(|point| point.trigger)
+            .map(|point| point.microsecond)
+            .collect()
+    }
+
+    #[test]
+    fn fires_on_the_chosen_slope() {
+        let rising = run(SETTINGS, square_wave(2000, 10_000));
+        assert_eq!(trigger_times(&rising), [1000, 3000, 5000, 7000, 9000]);
+
+        let falling = TriggerSettings {
+            slope: Slope::Falling,
+            ..SETTINGS
+        };
+        let falling = run(falling, square_wave(2000, 10_000));
+        assert_eq!(trigger_times(&falling), [2000, 4000, 6000, 8000]);
+    }
+
+    #[test]
+    fn either_slope_is_held_off_for_a_frame() {
+        let either = TriggerSettings {
+            slope: Slope::Either,
+            ..SETTINGS
+        };
+        // Edges every 500 microseconds, but a frame lasts 750 after the trigger.
+        let sent = run(either, square_wave(1000, 5000));
+        assert_eq!(trigger_times(&sent), [500, 1500, 2500, 3500, 4500]);
+    }
+
+    #[test]
+    fn holdoff_skips_edges() {
+        let held_off = TriggerSettings {
+            holdoff_us: 3000,
+            ..SETTINGS
+        };
+        let sent = run(held_off, square_wave(2000, 10_000));
+        assert_eq!(trigger_times(&sent), [1000, 5000, 9000]);
+    }
+
+    #[test]
+    fn frames_cover_the_pre_trigger_part() {
+        let sent = run(SETTINGS, square_wave(4000, 4000));
+        let first = sent.first().unwrap().microsecond;
+        let last = sent.last().unwrap().microsecond;
+        // The trigger is at 2000, a quarter of the 1000 microsecond frame comes before it.
+        assert_eq!(trigger_times(&sent), [2000]);
+        assert_eq!(first, 1740);
+        assert_eq!(last, 2750);
+        assert!(sent
+            .windows(2)
+            .all(|pair| pair[0].index + 1 == pair[1].index));
+    }
+
+    #[test]
+    fn nothing_is_sent_without_a_trigger() {
+        let flat = square_wave(2000, 10_000).map(|point| OscilliscopePoint {
+            voltage: 0.5,
+            ..point
+        });
+        assert!(run(SETTINGS, flat).is_empty());
+    }
+
+    #[test]
+    fn single_mode_sends_one_frame_until_run_again() {
+        let mut trigger = Trigger::new(Some(SETTINGS), AcquisitionMode::Single, 0, 1000);
+        let sent = feed(&mut trigger, square_wave(2000, 10_000));
+        assert_eq!(trigger_times(&sent), [1000]);
+        assert_eq!(trigger.state(), AcquisitionState::Done);
+
+        trigger.run();
+        assert_eq!(trigger.state(), AcquisitionState::Armed);
+        let more = square_wave(2000, 20_000).skip(1000);
+        assert_eq!(trigger_times(&feed(&mut trigger, more)), [11_000]);
+    }
+
+    #[test]
+    fn auto_mode_runs_freely_without_triggers() {
+        let flat = square_wave(2000, 10_000).map(|point| OscilliscopePoint {
+            voltage: 0.5,
+            ..point
+        });
+        let mut trigger = Trigger::new(Some(SETTINGS), AcquisitionMode::Auto, 5000, 1000);
+        let sent = feed(&mut trigger, flat);
+        assert_eq!(trigger.state(), AcquisitionState::FreeRunning);
+        assert_eq!(sent.first().unwrap().microsecond, 5000);
+        assert_eq!(sent.len(), 500);
+
+        // Triggers take over again as soon as there are edges, with nothing missing before.
+        let sent = feed(&mut trigger, square_wave(2000, 20_000).skip(1000));
+        assert_eq!(sent[0].microsecond, 10_000);
+        assert_eq!(
+            trigger_times(&sent),
+            [11_000, 13_000, 15_000, 17_000, 19_000]
+        );
+    }
+
+    #[test]
+    fn auto_mode_without_a_trigger_runs_freely() {
+        let mut trigger = Trigger::new(None, AcquisitionMode::Auto, 0, 0);
+        let sent = feed(&mut trigger, square_wave(2000, 10_000));
+        assert_eq!(sent.len(), 1000);
+        assert!(trigger_times(&sent).is_empty());
+    }
+
+    #[test]
+    fn heartbeats_only_go_out_while_running_freely() {
+        let heartbeats = || {
+            square_wave(2000, 10_000).map(|point| OscilliscopePoint {
+                heartbeat: true,
+                ..point
+            })
+        };
+        assert!(run(SETTINGS, heartbeats()).is_empty());
+        let mut trigger = Trigger::new(None, AcquisitionMode::Auto, 0, 0);
+        assert_eq!(feed(&mut trigger, heartbeats()).len(), 1000);
+    }
+
+    #[test]
+    fn frames_include_every_channel() {
+        // The second channel is a copy of the first, shifted by 5 microseconds.
+        let both = square_wave(4000, 4000).flat_map(|point| {
+            let second = OscilliscopePoint {
+                microsecond: point.microsecond + 5,
+                channel: 1,
+                ..point
+            };
+            [point, second]
+        });
+        let sent = run(SETTINGS, both);
+        assert_eq!(trigger_times(&sent), [2000]);
+
+        let times = |channel| -> Vec<u64> {
+            sent.iter()
+                .filter(|point| point.channel == channel)
+                .map(|point| point.microsecond)
+                .collect()
+        };
+        let first_channel = times(0);
+        let second_channel = times(1);
+        assert_eq!(first_channel.first(), Some(&1740));
+        assert_eq!(first_channel.last(), Some(&2750));
+        assert_eq!(second_channel.first(), Some(&1745));
+        assert_eq!(second_channel.last(), Some(&2755));
+        assert_eq!(first_channel.len(), second_channel.len());
+
+        // Only the trigger channel fires, however the others look.
+        let second_only = square_wave(2000, 10_000).map(|point| OscilliscopePoint {
+            channel: 1,
+            ..point
+        });
+        assert!(run(SETTINGS, second_only).is_empty());
+    }
+
+    #[test]
+    fn stopping_cuts_a_frame_short() {
+        let mut trigger = Trigger::new(Some(SETTINGS), AcquisitionMode::Normal, 0, 1000);
+        let sent = feed(&mut trigger, square_wave(2000, 1200));
+        assert_eq!(trigger_times(&sent), [1000]);
+
+        trigger.stop();
+        let sent = feed(&mut trigger, square_wave(2000, 10_000).skip(120));
+        assert!(sent.is_empty());
+        assert_eq!(trigger.state(), AcquisitionState::Stopped);
+    }
+}
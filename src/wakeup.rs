@@ -0,0 +1,173 @@
+//! Waking a task on one core from the other, without a lock.
+//!
+//! The WebSocket tasks wait for points on the network core, while the measuring core appends
+//! them. [`Wakeup`] holds the waker of the one task that waits, and hands it to whichever core
+//! wakes it. A waker that is being swapped is never woken half written, and a wakeup that comes
+//! in while the task registers is passed on instead of being lost.
+
+use core::{cell::UnsafeCell, task::Waker};
+
+// Loom replaces the atomics when model checking the handover on the host.
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicU8, Ordering};
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicU8, Ordering};
+
+/// Nobody is touching the waker.
+const IDLE: u8 = 0;
+/// The waiting task is storing its waker.
+const REGISTERING: u8 = 0b01;
+/// The other core is taking the waker out to wake it.
+const WAKING: u8 = 0b10;
+
+/// Holds the waker of a single task that waits.
+pub struct Wakeup {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+unsafe impl Send for Wakeup {}
+unsafe impl Sync for Wakeup {}
+
+impl Wakeup {
+    pub fn new() -> Wakeup {
+        Wakeup {
+            state: AtomicU8::new(IDLE),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Stores the waker of the waiting task, for the next call to `wake`. Only one task may
+    /// register at a time, and it has to check what it waits for after registering.
+    pub fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(IDLE, REGISTERING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                // Only the registering task gets here, and `wake` keeps out until it is done.
+                let stored = unsafe { &mut *self.waker.get() };
+                if !stored
+                    .as_ref()
+                    .is_some_and(|stored| stored.will_wake(waker))
+                {
+                    *stored = Some(waker.clone());
+                }
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, IDLE, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // A wakeup came in meanwhile and left the waker to us.
+                    let waker = stored.take();
+                    self.state.swap(IDLE, Ordering::AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            // The other core is waking the old waker, so the task runs again anyway.
+            Err(_) => waker.wake_by_ref(),
+        }
+    }
+
+    /// Wakes the registered task, if any. Wakes it once per registration.
+    pub fn wake(&self) {
+        if self.state.fetch_or(WAKING, Ordering::AcqRel) == IDLE {
+            let waker = unsafe { &mut *self.waker.get() }.take();
+            self.state.fetch_and(!WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Default for Wakeup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use alloc::{sync::Arc, task::Wake};
+    use core::sync::atomic::AtomicUsize;
+
+    /// Counts how often it was woken.
+    struct Counter(AtomicUsize);
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn wakes_the_registered_task_once() {
+        let wakeup = Wakeup::new();
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+
+        wakeup.wake();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 0);
+
+        wakeup.register(&waker);
+        wakeup.register(&waker);
+        wakeup.wake();
+        wakeup.wake();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+
+        wakeup.register(&waker);
+        wakeup.wake();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 2);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::Wakeup;
+    use alloc::task::Wake;
+    use core::task::Waker;
+    use loom::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: alloc::sync::Arc<Self>) {
+            self.0.store(true, Ordering::Release);
+        }
+    }
+
+    /// Whatever the interleaving, a task that registers and then finds nothing to do must be
+    /// woken once the other core has something for it.
+    #[test]
+    fn no_wakeup_is_lost() {
+        loom::model(|| {
+            let wakeup = Arc::new(Wakeup::new());
+            let ready = Arc::new(AtomicBool::new(false));
+
+            let producer = {
+                let (wakeup, ready) = (wakeup.clone(), ready.clone());
+                thread::spawn(move || {
+                    ready.store(true, Ordering::Release);
+                    wakeup.wake();
+                })
+            };
+
+            let flag = alloc::sync::Arc::new(Flag(AtomicBool::new(false)));
+            wakeup.register(&Waker::from(flag.clone()));
+            let saw_it = ready.load(Ordering::Acquire);
+
+            producer.join().unwrap();
+            assert!(saw_it || flag.0.load(Ordering::Acquire));
+        });
+    }
+}
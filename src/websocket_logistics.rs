@@ -0,0 +1,1105 @@
+use core::{
+    error::Error,
+    future::{poll_fn, Future},
+    pin::pin,
+    task::Poll,
+};
+use embedded_io_async::{ErrorType, Read, ReadExactError, Write};
+
+use crate::{adc::AdcSettings, protocol::StreamEncoder, wakeup::Wakeup};
+use alloc::boxed::Box;
+
+// Loom replaces the atomics and the cells of the entries when model checking the buffer on the
+// host, so it can tell when both cores touch an entry at once.
+#[cfg(not(loom))]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(loom)]
+use loom::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+/// Holds an entry, with the same way in as loom's cell so the buffer uses both alike.
+#[cfg(not(loom))]
+struct UnsafeCell<T>(core::cell::UnsafeCell<T>);
+
+#[cfg(not(loom))]
+impl<T> UnsafeCell<T> {
+    fn new(value: T) -> UnsafeCell<T> {
+        UnsafeCell(core::cell::UnsafeCell::new(value))
+    }
+
+    fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+        f(self.0.get())
+    }
+
+    fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+        f(self.0.get())
+    }
+}
+
+/// How many probe channels the scope has.
+pub const CHANNELS: usize = 2;
+/// The bits below a whole ADC code that points keep, as many as oversampling adds at most.
+pub const CODE_FRACTION_BITS: u32 = crate::filter::MAX_OVERSAMPLE_BITS as u32;
+
+#[derive(Clone, Copy, Debug)]
+pub struct OscilliscopePoint {
+    pub voltage: f64,
+    /// Time since boot the point was measured at.
+    pub microsecond: u64,
+    /// ADC code the voltage was calculated from, in `1 << CODE_FRACTION_BITS` parts of a code
+    /// so the bits the filters resolve below a code are kept. This is what goes over the wire.
+    pub code: u16,
+    /// Counts the points the producer kept of this channel, so a reader can tell when some were
+    /// lost.
+    pub index: u32,
+    /// The probe channel, counting from 0.
+    pub channel: u8,
+    /// Whether the trigger fired on this point.
+    pub trigger: bool,
+    /// Not a kept point but the newest one held back, to show how far the signal got. Shares
+    /// its index with the next kept point, which can be older, see `Decimator`.
+    pub heartbeat: bool,
+    /// How the ADC was set up for the code.
+    pub adc: AdcSettings,
+}
+
+pub struct CyclicWriter<'a, const L: usize, T> {
+    buffer: &'a CyclicBuffer<L, T>,
+}
+
+pub struct CyclicReader<'a, const L: usize, T> {
+    buffer: &'a CyclicBuffer<L, T>,
+    drops_before: DropCounts,
+}
+
+/// What [`CyclicWriter::append`] does when the reader has not made room for a new entry.
+#[derive(Clone, Copy, Debug)]
+pub enum OverflowPolicy {
+    /// Throw the new entry away and keep everything that is still unread.
+    DropNewest,
+    /// Throw the oldest unread entry away to make room for the new one. While the reader is
+    /// copying entries out they cannot be touched, so the new entry is dropped instead.
+    OverwriteOldest,
+    /// Spin until the reader makes room, dropping the new entry once `timeout_us` has passed.
+    Block {
+        timeout_us: u32,
+        now_us: fn() -> u64,
+    },
+}
+
+impl OverflowPolicy {
+    pub fn from_config(policy: &str, block_timeout_us: u32, now_us: fn() -> u64) -> Self {
+        match policy {
+            "drop_newest" => OverflowPolicy::DropNewest,
+            "overwrite_oldest" => OverflowPolicy::OverwriteOldest,
+            "block" => OverflowPolicy::Block {
+                timeout_us: block_timeout_us,
+                now_us,
+            },
+            _ => panic!("Configuration value for overflow policy '{policy}' is invalid."),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OverflowPolicy::DropNewest => "drop_newest",
+            OverflowPolicy::OverwriteOldest => "overwrite_oldest",
+            OverflowPolicy::Block { .. } => "block",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct DropCounts {
+    pub dropped_newest: usize,
+    pub overwritten_oldest: usize,
+}
+
+impl DropCounts {
+    pub fn total(&self) -> usize {
+        self.dropped_newest + self.overwritten_oldest
+    }
+
+    fn since(&self, before: DropCounts) -> DropCounts {
+        DropCounts {
+            dropped_newest: self.dropped_newest.wrapping_sub(before.dropped_newest),
+            overwritten_oldest: self
+                .overwritten_oldest
+                .wrapping_sub(before.overwritten_oldest),
+        }
+    }
+}
+
+/// Set in `read_index` while the reader copies entries out, so the writer keeps its hands off
+/// them.
+const LEASED: usize = 1 << (usize::BITS - 1);
+
+/// Single producer, single consumer ring buffer that is safe to share between the two cores.
+///
+/// Only the writer stores `write_index` and only the reader stores `read_index`. An entry is
+/// written before `write_index` is published with release ordering, and the reader acquires it
+/// before touching the entry, so a point can never be observed half written. The same goes the
+/// other way around for `read_index`, which hands slots back to the writer.
+///
+/// The one exception is [`OverflowPolicy::OverwriteOldest`], where the writer moves `read_index`
+/// forward itself. It does so with a compare and swap that fails while the reader has marked
+/// the index as [`LEASED`], so entries are never overwritten while they are being read.
+///
+/// A reader waiting in [`CyclicReader::wait_for_batch`] is woken by the writer once `wake_at`
+/// entries are unread.
+pub struct CyclicBuffer<const L: usize, T> {
+    read_index: AtomicUsize,
+    write_index: AtomicUsize,
+    entries: [UnsafeCell<T>; L],
+    writer_available: AtomicBool,
+    reader_available: AtomicBool,
+    /// Whether the writer appends for the reader. Only set once a taken reader is ready for it.
+    reader_subscribed: AtomicBool,
+    overflow_policy: OverflowPolicy,
+    dropped_newest: AtomicUsize,
+    overwritten_oldest: AtomicUsize,
+    wakeup: Wakeup,
+    wake_at: AtomicUsize,
+}
+
+unsafe impl<const L: usize, T: Send> Send for CyclicBuffer<L, T> {}
+unsafe impl<const L: usize, T: Send> Sync for CyclicBuffer<L, T> {}
+unsafe impl<'a, const L: usize, T: Send> Send for CyclicWriter<'a, L, T> {}
+
+impl<const L: usize, T> CyclicBuffer<L, T> {
+    pub fn new(filler: T, overflow_policy: OverflowPolicy) -> CyclicBuffer<L, T>
+    where
+        T: Copy,
+    {
+        CyclicBuffer {
+            read_index: AtomicUsize::new(0),
+            write_index: AtomicUsize::new(0),
+            entries: core::array::from_fn(|_| UnsafeCell::new(filler)),
+            reader_available: AtomicBool::new(true),
+            writer_available: AtomicBool::new(true),
+            reader_subscribed: AtomicBool::new(false),
+            overflow_policy,
+            dropped_newest: AtomicUsize::new(0),
+            overwritten_oldest: AtomicUsize::new(0),
+            wakeup: Wakeup::new(),
+            wake_at: AtomicUsize::new(1),
+        }
+    }
+
+    pub fn take_writer(&self) -> Option<CyclicWriter<'_, L, T>> {
+        if self.writer_available.swap(false, Ordering::Acquire) {
+            Some(CyclicWriter { buffer: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn take_reader(&self) -> Option<CyclicReader<'_, L, T>> {
+        let reader = self.claim_reader()?;
+        self.reader_subscribed.store(true, Ordering::Release);
+        Some(reader)
+    }
+
+    /// Takes the reader without subscribing it, so it can be set up before the writer sees it.
+    fn claim_reader(&self) -> Option<CyclicReader<'_, L, T>> {
+        if self.reader_available.swap(false, Ordering::Acquire) {
+            Some(CyclicReader {
+                buffer: self,
+                drops_before: self.drop_counts(),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_reader_taken(&self) -> bool {
+        !self.reader_available.load(Ordering::Acquire)
+    }
+
+    fn is_reader_subscribed(&self) -> bool {
+        self.reader_subscribed.load(Ordering::Acquire)
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
+    pub fn entry_count(&self) -> usize {
+        let read_index = self.read_index.load(Ordering::Acquire) & !LEASED;
+        let write_index = self.write_index.load(Ordering::Acquire);
+
+        let count = if read_index <= write_index {
+            write_index - read_index
+        } else {
+            L - read_index + write_index
+        };
+        assert!(
+            count < L,
+            "Seemingly more entries in the buffer than it has capacity for."
+        );
+        count
+    }
+
+    pub fn drop_counts(&self) -> DropCounts {
+        DropCounts {
+            dropped_newest: self.dropped_newest.load(Ordering::Relaxed),
+            overwritten_oldest: self.overwritten_oldest.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn missed(&self) -> usize {
+        self.drop_counts().total()
+    }
+
+    pub fn increment_missed(&self) {
+        self.dropped_newest.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<'a, const L: usize, T> CyclicWriter<'a, L, T> {
+    pub fn append(&mut self, value: T) -> Result<(), Box<dyn Error>> {
+        // Only this writer ever stores the write index, so its own view is always current.
+        let write_index = self.buffer.write_index.load(Ordering::Relaxed);
+        let next_write_index = (write_index + 1) % L;
+        let mut blocked_since: Option<u64> = None;
+        loop {
+            let read_index = self.buffer.read_index.load(Ordering::Acquire);
+            if next_write_index != read_index & !LEASED {
+                break;
+            }
+
+            match self.buffer.overflow_policy {
+                OverflowPolicy::DropNewest => (),
+                OverflowPolicy::OverwriteOldest => {
+                    if read_index & LEASED == 0 {
+                        match self.buffer.read_index.compare_exchange(
+                            read_index,
+                            (read_index + 1) % L,
+                            Ordering::AcqRel,
+                            Ordering::Relaxed,
+                        ) {
+                            Ok(_) => {
+                                self.buffer
+                                    .overwritten_oldest
+                                    .fetch_add(1, Ordering::Relaxed);
+                                break;
+                            }
+                            // The reader moved the index meanwhile, and may have made room.
+                            Err(_) => continue,
+                        }
+                    }
+                }
+                OverflowPolicy::Block { timeout_us, now_us } => {
+                    let now = now_us();
+                    let since = *blocked_since.get_or_insert(now);
+                    if now.wrapping_sub(since) < timeout_us as u64 {
+                        core::hint::spin_loop();
+                        continue;
+                    }
+                }
+            }
+
+            self.buffer.increment_missed();
+            return Err(Box::from("Trying to overwrite unread entries."));
+        }
+
+        // The slot is outside of what the reader may copy until the write index is published.
+        self.buffer.entries[write_index].with_mut(|entry| unsafe { entry.write(value) });
+        self.buffer
+            .write_index
+            .store(next_write_index, Ordering::Release);
+        if self.buffer.entry_count() >= self.buffer.wake_at.load(Ordering::Relaxed) {
+            self.buffer.wakeup.wake();
+        }
+
+        Ok(())
+    }
+}
+
+/// Fans every appended entry out to up to `N` readers at once.
+///
+/// Each reader gets a [`CyclicBuffer`] of its own, so every client has its own cursor, lag and
+/// missed counter. A reader that falls behind only loses its own entries and never holds back
+/// the writer or the other readers.
+pub struct BroadcastBuffer<const N: usize, const L: usize, T> {
+    channels: [CyclicBuffer<L, T>; N],
+    writer_available: AtomicBool,
+}
+
+pub struct BroadcastWriter<'a, const N: usize, const L: usize, T> {
+    buffer: &'a BroadcastBuffer<N, L, T>,
+    writers: [CyclicWriter<'a, L, T>; N],
+}
+
+unsafe impl<'a, const N: usize, const L: usize, T: Send> Send for BroadcastWriter<'a, N, L, T> {}
+
+impl<const N: usize, const L: usize, T> BroadcastBuffer<N, L, T> {
+    pub fn new(filler: T, overflow_policy: OverflowPolicy) -> BroadcastBuffer<N, L, T>
+    where
+        T: Copy,
+    {
+        BroadcastBuffer {
+            channels: core::array::from_fn(|_| CyclicBuffer::new(filler, overflow_policy)),
+            writer_available: AtomicBool::new(true),
+        }
+    }
+
+    pub fn take_writer(&self) -> Option<BroadcastWriter<'_, N, L, T>> {
+        if self.writer_available.swap(false, Ordering::Acquire) {
+            Some(BroadcastWriter {
+                buffer: self,
+                writers: core::array::from_fn(|i| self.channels[i].take_writer().unwrap()),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Subscribes a new reader, or returns `None` if all `N` are in use.
+    ///
+    /// The reader starts at the newest entry, whatever a previous reader left unread is skipped.
+    /// Its cursor is moved there before the writer gets to see it, so nothing is appended against
+    /// the cursor the previous reader left behind.
+    pub fn take_reader(&self) -> Option<CyclicReader<'_, L, T>> {
+        self.channels.iter().find_map(|channel| {
+            let mut reader = channel.claim_reader()?;
+            reader.skip_unread();
+            reader.drops_before = channel.drop_counts();
+            channel.reader_subscribed.store(true, Ordering::Release);
+            Some(reader)
+        })
+    }
+
+    pub fn reader_count(&self) -> usize {
+        self.channels
+            .iter()
+            .filter(|channel| channel.is_reader_taken())
+            .count()
+    }
+
+    /// Total number of entries missed by all readers, past and present.
+    pub fn missed(&self) -> usize {
+        self.channels.iter().map(|channel| channel.missed()).sum()
+    }
+}
+
+impl<'a, const N: usize, const L: usize, T: Copy> BroadcastWriter<'a, N, L, T> {
+    /// Appends the value for every subscribed reader. Fails if at least one of them had to miss it.
+    pub fn append(&mut self, value: T) -> Result<(), Box<dyn Error>> {
+        let mut missed_by_any = false;
+        for (channel, writer) in self.buffer.channels.iter().zip(self.writers.iter_mut()) {
+            if channel.is_reader_subscribed() {
+                missed_by_any |= writer.append(value).is_err();
+            }
+        }
+
+        if missed_by_any {
+            Err(Box::from("At least one reader is too far behind."))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Number of entries the subscribed reader furthest behind has not read yet.
+    pub fn lag(&self) -> usize {
+        self.buffer
+            .channels
+            .iter()
+            .filter(|channel| channel.is_reader_subscribed())
+            .map(|channel| channel.entry_count())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl<'a, const L: usize, T> CyclicReader<'a, L, T> {
+    /// Number of entries written but not yet read by this reader.
+    pub fn lag(&self) -> usize {
+        self.buffer.entry_count()
+    }
+
+    /// Entries this reader missed since it was taken, by the way they were lost.
+    pub fn drop_counts(&self) -> DropCounts {
+        self.buffer.drop_counts().since(self.drops_before)
+    }
+
+    pub fn missed(&self) -> usize {
+        self.drop_counts().total()
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.buffer.overflow_policy()
+    }
+
+    fn skip_unread(&self) {
+        let write_index = self.buffer.write_index.load(Ordering::Acquire);
+        self.buffer.read_index.store(write_index, Ordering::Release);
+    }
+
+    /// Waits until at least `batch` entries are unread, or until `timeout` is over.
+    ///
+    /// The writer wakes the waiting task from the other core, so nothing has to poll the buffer.
+    /// `batch` is clamped to what the buffer can hold, and the first wait after it changes can
+    /// take until `timeout`.
+    pub async fn wait_for_batch(&mut self, batch: usize, timeout: impl Future<Output = ()>) {
+        let batch = batch.clamp(1, L - 1);
+        self.buffer.wake_at.store(batch, Ordering::Relaxed);
+        let mut timeout = pin!(timeout);
+        poll_fn(|context| {
+            // Registering first, so entries appended after the check wake the task.
+            self.buffer.wakeup.register(context.waker());
+            if self.buffer.entry_count() >= batch || timeout.as_mut().poll(context).is_ready() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+    }
+
+    /// Copies the oldest unread entries into `into`, as many as there are room for, and hands
+    /// their slots back to the writer. Returns how many were copied.
+    ///
+    /// The read index is only leased for the copy, so an overwriting writer is never held off
+    /// while the entries are being sent.
+    pub fn read(&mut self, into: &mut [T]) -> usize
+    where
+        T: Copy,
+    {
+        // Leasing the read index keeps an overwriting writer from moving it under our feet.
+        let read_index = self.buffer.read_index.fetch_or(LEASED, Ordering::Acquire) & !LEASED;
+        let write_index = self.buffer.write_index.load(Ordering::Acquire);
+
+        let unread = (write_index + L - read_index) % L;
+        let count = unread.min(into.len());
+        for (offset, entry) in into[..count].iter_mut().enumerate() {
+            *entry = self.buffer.entries[(read_index + offset) % L]
+                .with(|entry| unsafe { entry.read() });
+        }
+
+        // Hands the copied slots back to the writer and ends the lease.
+        self.buffer
+            .read_index
+            .store((read_index + count) % L, Ordering::Release);
+        count
+    }
+}
+
+impl<'a, const L: usize, T> Drop for CyclicReader<'a, L, T> {
+    fn drop(&mut self) {
+        self.buffer
+            .reader_subscribed
+            .store(false, Ordering::Release);
+        self.buffer.reader_available.store(true, Ordering::Release);
+    }
+}
+
+pub async fn send_message<W>(to: &mut W, data: &[u8]) -> Result<(), <W as ErrorType>::Error>
+where
+    W: Write,
+{
+    send_frame(to, 0b10000010u8, data).await // FIN and binary data.
+}
+
+pub async fn send_text<W>(to: &mut W, text: &str) -> Result<(), <W as ErrorType>::Error>
+where
+    W: Write,
+{
+    send_frame(to, 0b10000001u8, text.as_bytes()).await // FIN and text data.
+}
+
+/// The longest header of a frame from the server, which is not masked: two bytes, and eight
+/// more for lengths past 16 bits.
+const MAX_HEADER_LENGTH: usize = 10;
+
+/// Sends the points in as few WebSocket messages as `frame` allows, with gap messages wherever
+/// points went missing. Every channel gets frames of its own.
+///
+/// `frame` is where the messages are encoded, its length is the largest message to send.
+pub async fn send_points<W>(
+    to: &mut W,
+    encoder: &mut StreamEncoder,
+    points: &[OscilliscopePoint],
+    frame: &mut [u8],
+) -> Result<(), <W as ErrorType>::Error>
+where
+    W: Write,
+{
+    for channel in 0..CHANNELS as u8 {
+        let mut rest = points;
+        while !rest.is_empty() {
+            let (used, length) = encoder.encode(channel, rest, frame);
+            assert!(
+                used > 0 || length > 0,
+                "Could not fit a single point in a frame."
+            );
+
+            if length > 0 {
+                send_message(to, &frame[..length]).await?;
+            }
+            rest = &rest[used..];
+        }
+    }
+    Ok(())
+}
+
+/// Writes the header and then the data, so neither has to be copied into a frame first.
+async fn send_frame<W>(
+    to: &mut W,
+    fin_rsv_opcode: u8,
+    data: &[u8],
+) -> Result<(), <W as ErrorType>::Error>
+where
+    W: Write,
+{
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    let (header, header_length) = frame_header(fin_rsv_opcode, data.len());
+    to.write_all(&header[..header_length]).await?;
+    to.write_all(data).await
+}
+
+/// The header of an unmasked frame with `length` bytes of payload, and how many of its bytes
+/// are used.
+fn frame_header(fin_rsv_opcode: u8, length: usize) -> ([u8; MAX_HEADER_LENGTH], usize) {
+    let mut header = [0u8; MAX_HEADER_LENGTH];
+    header[0] = fin_rsv_opcode;
+    let header_length = match length {
+        0..=125 => {
+            header[1] = length as u8;
+            2
+        }
+        126..=0xffff => {
+            header[1] = 126;
+            header[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+            4
+        }
+        // Slices are never longer than isize::MAX, so the most significant bit stays clear.
+        _ => {
+            header[1] = 127;
+            header[2..10].copy_from_slice(&(length as u64).to_be_bytes());
+            10
+        }
+    };
+    (header, header_length)
+}
+
+/// A message from a client, unmasked into the buffer given to [`receive_message`].
+#[derive(Debug, PartialEq)]
+pub enum ClientMessage {
+    /// Text of this many bytes.
+    Text(usize),
+    /// Binary data of this many bytes.
+    Binary(usize),
+    Ping(usize),
+    Pong,
+    Close,
+}
+
+#[derive(Debug)]
+pub enum ReceiveError<E> {
+    Io(E),
+    /// The connection ended in the middle of a message.
+    Closed,
+    /// The message does not fit in the buffer.
+    TooLong,
+    /// Fragmented messages, unmasked frames and unknown opcodes are not supported.
+    Unsupported,
+}
+
+impl<E> From<ReadExactError<E>> for ReceiveError<E> {
+    fn from(error: ReadExactError<E>) -> Self {
+        match error {
+            ReadExactError::UnexpectedEof => ReceiveError::Closed,
+            ReadExactError::Other(e) => ReceiveError::Io(e),
+        }
+    }
+}
+
+/// Reads one message from a client. Clients are small and only send short commands, so every
+/// message has to fit in `buffer` and come in a single frame.
+pub async fn receive_message<R>(
+    from: &mut R,
+    buffer: &mut [u8],
+) -> Result<ClientMessage, ReceiveError<<R as ErrorType>::Error>>
+where
+    R: Read,
+{
+    let mut header = [0u8; 2];
+    from.read_exact(&mut header).await?;
+    let fin = header[0] & 0b1000_0000 != 0;
+    let opcode = header[0] & 0b0000_1111;
+    let masked = header[1] & 0b1000_0000 != 0;
+
+    let length = match header[1] & 0b0111_1111 {
+        126 => {
+            let mut length = [0u8; 2];
+            from.read_exact(&mut length).await?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0u8; 8];
+            from.read_exact(&mut length).await?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    // Clients always have to mask what they send.
+    if !fin || !masked {
+        return Err(ReceiveError::Unsupported);
+    }
+    let mut mask = [0u8; 4];
+    from.read_exact(&mut mask).await?;
+
+    if length > buffer.len() as u64 {
+        return Err(ReceiveError::TooLong);
+    }
+    let length = length as usize;
+    let payload = &mut buffer[..length];
+    from.read_exact(payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    match opcode {
+        0x1 => Ok(ClientMessage::Text(length)),
+        0x2 => Ok(ClientMessage::Binary(length)),
+        0x8 => Ok(ClientMessage::Close),
+        0x9 => Ok(ClientMessage::Ping(length)),
+        0xA => Ok(ClientMessage::Pong),
+        _ => Err(ReceiveError::Unsupported),
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Runs a future that never has to wait, like reading from a slice.
+    fn block_on<F: core::future::Future>(future: F) -> F::Output {
+        let mut future = core::pin::pin!(future);
+        let mut context = core::task::Context::from_waker(core::task::Waker::noop());
+        match future.as_mut().poll(&mut context) {
+            core::task::Poll::Ready(output) => output,
+            core::task::Poll::Pending => panic!("The future had to wait."),
+        }
+    }
+
+    /// Builds a frame the way browsers do, masked.
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = alloc::vec![0b1000_0000 | opcode];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0b1000_0000 | length as u8),
+            length => {
+                frame.push(0b1000_0000 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    #[test]
+    fn unmasks_client_messages() {
+        let frames = [client_frame(0x1, b"mode single"), client_frame(0x8, &[])].concat();
+        let mut from = &frames[..];
+        let mut buffer = [0u8; 64];
+
+        let message = block_on(receive_message(&mut from, &mut buffer)).unwrap();
+        assert_eq!(message, ClientMessage::Text(11));
+        assert_eq!(&buffer[..11], b"mode single");
+        let message = block_on(receive_message(&mut from, &mut buffer)).unwrap();
+        assert_eq!(message, ClientMessage::Close);
+        assert!(matches!(
+            block_on(receive_message(&mut from, &mut buffer)),
+            Err(ReceiveError::Closed)
+        ));
+    }
+
+    #[test]
+    fn rejects_messages_that_do_not_fit() {
+        let frame = client_frame(0x2, &[7u8; 200]);
+        let mut buffer = [0u8; 64];
+        assert!(matches!(
+            block_on(receive_message(&mut &frame[..], &mut buffer)),
+            Err(ReceiveError::TooLong)
+        ));
+
+        let mut buffer = [0u8; 256];
+        let message = block_on(receive_message(&mut &frame[..], &mut buffer)).unwrap();
+        assert_eq!(message, ClientMessage::Binary(200));
+        assert!(buffer[..200].iter().all(|byte| *byte == 7));
+    }
+
+    #[test]
+    fn frames_have_the_shortest_header_for_their_length() {
+        let header = |length| {
+            let (header, header_length) = frame_header(0x82, length);
+            header[..header_length].to_vec()
+        };
+        assert_eq!(header(125), [0x82, 125]);
+        assert_eq!(header(126), [0x82, 126, 0, 126]);
+        assert_eq!(header(0xffff), [0x82, 126, 0xff, 0xff]);
+        assert_eq!(header(0x10000), [0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn long_messages_go_out_whole() {
+        let data: Vec<u8> = (0..70_000u32).map(|i| i as u8).collect();
+        let mut sent = alloc::vec![0u8; 70_010];
+        let mut to = &mut sent[..];
+        block_on(send_message(&mut to, &data)).unwrap();
+        assert!(to.is_empty());
+        assert_eq!(sent[..10], [0x82, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70]);
+        assert_eq!(sent[10..], data[..]);
+
+        let mut to = &mut sent[..];
+        block_on(send_message(&mut to, &[])).unwrap();
+        assert_eq!(to.len(), 70_010);
+    }
+
+    fn read_all<const L: usize>(reader: &mut CyclicReader<'_, L, u32>) -> Vec<u32> {
+        let mut entries = [0u32; L];
+        let count = reader.read(&mut entries);
+        entries[..count].to_vec()
+    }
+
+    #[test]
+    fn dropping_newest_keeps_what_is_unread() {
+        let buffer: CyclicBuffer<4, u32> = CyclicBuffer::new(0, OverflowPolicy::DropNewest);
+        let mut writer = buffer.take_writer().unwrap();
+        let mut reader = buffer.take_reader().unwrap();
+
+        let accepted = (1..=5)
+            .filter(|value| writer.append(*value).is_ok())
+            .count();
+        assert_eq!(accepted, 3);
+        assert_eq!(read_all(&mut reader), [1, 2, 3]);
+        assert_eq!(reader.drop_counts().dropped_newest, 2);
+
+        writer.append(6).unwrap();
+        assert_eq!(read_all(&mut reader), [6]);
+    }
+
+    #[test]
+    fn overwriting_oldest_keeps_the_newest() {
+        let buffer: CyclicBuffer<4, u32> = CyclicBuffer::new(0, OverflowPolicy::OverwriteOldest);
+        let mut writer = buffer.take_writer().unwrap();
+        let mut reader = buffer.take_reader().unwrap();
+
+        for value in 1..=5 {
+            writer.append(value).unwrap();
+        }
+        assert_eq!(read_all(&mut reader), [3, 4, 5]);
+        assert_eq!(reader.drop_counts().overwritten_oldest, 2);
+    }
+
+    #[test]
+    fn reading_only_hands_back_what_was_copied() {
+        let buffer: CyclicBuffer<4, u32> = CyclicBuffer::new(0, OverflowPolicy::OverwriteOldest);
+        let mut writer = buffer.take_writer().unwrap();
+        let mut reader = buffer.take_reader().unwrap();
+
+        for value in 1..=3 {
+            writer.append(value).unwrap();
+        }
+        let mut first = [0u32; 1];
+        assert_eq!(reader.read(&mut first), 1);
+        assert_eq!(first, [1]);
+        assert_eq!(reader.lag(), 2);
+
+        // Nothing is leased once the copy is done, so the writer may overwrite what is unread.
+        writer.append(4).unwrap();
+        writer.append(5).unwrap();
+        assert_eq!(read_all(&mut reader), [3, 4, 5]);
+        assert_eq!(reader.drop_counts().overwritten_oldest, 1);
+        assert_eq!(reader.drop_counts().dropped_newest, 0);
+    }
+
+    #[test]
+    fn batches_wrap_around_the_end() {
+        let buffer: CyclicBuffer<4, u32> = CyclicBuffer::new(0, OverflowPolicy::DropNewest);
+        let mut writer = buffer.take_writer().unwrap();
+        let mut reader = buffer.take_reader().unwrap();
+
+        for round in 0..10 {
+            writer.append(round * 2).unwrap();
+            writer.append(round * 2 + 1).unwrap();
+            assert_eq!(read_all(&mut reader), [round * 2, round * 2 + 1]);
+        }
+    }
+
+    /// Remembers whether it was woken.
+    struct Woken(AtomicBool);
+
+    impl alloc::task::Wake for Woken {
+        fn wake(self: alloc::sync::Arc<Self>) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn the_writer_wakes_a_reader_once_a_batch_is_in() {
+        let buffer: CyclicBuffer<8, u32> = CyclicBuffer::new(0, OverflowPolicy::DropNewest);
+        let mut writer = buffer.take_writer().unwrap();
+        let mut reader = buffer.take_reader().unwrap();
+        let woken = alloc::sync::Arc::new(Woken(AtomicBool::new(false)));
+        let waker = core::task::Waker::from(woken.clone());
+        let mut context = core::task::Context::from_waker(&waker);
+
+        {
+            let mut next = core::pin::pin!(reader.wait_for_batch(3, core::future::pending()));
+            assert!(next.as_mut().poll(&mut context).is_pending());
+            writer.append(1).unwrap();
+            writer.append(2).unwrap();
+            assert!(!woken.0.load(Ordering::Relaxed));
+            writer.append(3).unwrap();
+            assert!(woken.0.load(Ordering::Relaxed));
+            assert!(next.as_mut().poll(&mut context).is_ready());
+        }
+        assert_eq!(read_all(&mut reader), [1, 2, 3]);
+    }
+
+    #[test]
+    fn a_reader_gets_what_there_is_once_it_waited_long_enough() {
+        let buffer: CyclicBuffer<8, u32> = CyclicBuffer::new(0, OverflowPolicy::DropNewest);
+        let mut writer = buffer.take_writer().unwrap();
+        let mut reader = buffer.take_reader().unwrap();
+
+        writer.append(1).unwrap();
+        block_on(reader.wait_for_batch(3, core::future::ready(())));
+        assert_eq!(read_all(&mut reader), [1]);
+    }
+
+    #[test]
+    fn broadcast_reaches_every_reader_from_when_it_subscribed() {
+        let buffer: BroadcastBuffer<2, 8, u32> =
+            BroadcastBuffer::new(0, OverflowPolicy::DropNewest);
+        let mut writer = buffer.take_writer().unwrap();
+
+        let mut early = buffer.take_reader().unwrap();
+        writer.append(1).unwrap();
+        let mut late = buffer.take_reader().unwrap();
+        writer.append(2).unwrap();
+        assert!(buffer.take_reader().is_none());
+
+        assert_eq!(writer.lag(), 2);
+        assert_eq!(read_all(&mut early), [1, 2]);
+        assert_eq!(writer.lag(), 1);
+        assert_eq!(read_all(&mut late), [2]);
+        assert_eq!(writer.lag(), 0);
+        assert_eq!(buffer.reader_count(), 2);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::{BroadcastBuffer, CyclicBuffer, OverflowPolicy};
+    use alloc::vec::Vec;
+    use loom::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    fn read_all<const L: usize>(buffer: &CyclicBuffer<L, u32>, into: &mut Vec<u32>) {
+        let mut reader = buffer.take_reader().unwrap();
+        let mut entries = [0u32; L];
+        let count = reader.read(&mut entries);
+        into.extend_from_slice(&entries[..count]);
+    }
+
+    /// Every interleaving of one writer and one reader must deliver exactly the accepted points,
+    /// in order, with nothing torn or duplicated.
+    #[test]
+    fn delivers_accepted_points_exactly_once_in_order() {
+        loom::model(|| {
+            let buffer: Arc<CyclicBuffer<3, u32>> =
+                Arc::new(CyclicBuffer::new(0, OverflowPolicy::DropNewest));
+
+            let producer = {
+                let buffer = buffer.clone();
+                thread::spawn(move || {
+                    let mut writer = buffer.take_writer().unwrap();
+                    let mut accepted = Vec::new();
+                    for value in 1..=3 {
+                        if writer.append(value).is_ok() {
+                            accepted.push(value);
+                        }
+                    }
+                    accepted
+                })
+            };
+
+            let mut received = Vec::new();
+            read_all(&buffer, &mut received);
+            read_all(&buffer, &mut received);
+
+            let accepted = producer.join().unwrap();
+            read_all(&buffer, &mut received);
+
+            assert_eq!(received, accepted);
+            assert_eq!(buffer.missed(), 3 - accepted.len());
+        });
+    }
+
+    /// Overwriting the oldest entries must never hand the reader an entry twice or out of order,
+    /// not even when the writer laps it in the middle of a batch.
+    #[test]
+    fn overwriting_keeps_points_unique_and_in_order() {
+        loom::model(|| {
+            let buffer: Arc<CyclicBuffer<3, u32>> =
+                Arc::new(CyclicBuffer::new(0, OverflowPolicy::OverwriteOldest));
+
+            let producer = {
+                let buffer = buffer.clone();
+                thread::spawn(move || {
+                    let mut writer = buffer.take_writer().unwrap();
+                    for value in 1..=3 {
+                        let _ = writer.append(value);
+                    }
+                })
+            };
+
+            let mut received = Vec::new();
+            read_all(&buffer, &mut received);
+
+            producer.join().unwrap();
+            read_all(&buffer, &mut received);
+
+            assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(received.iter().all(|value| (1..=3).contains(value)));
+            assert_eq!(received.len() + buffer.missed(), 3);
+        });
+    }
+
+    /// The writer must never overwrite an entry while the reader is copying it out, however the
+    /// lease and the overwrite interleave. Loom fails the model if both touch an entry at once.
+    #[test]
+    fn overwriting_keeps_off_entries_that_are_being_copied() {
+        loom::model(|| {
+            let buffer: Arc<CyclicBuffer<3, u32>> =
+                Arc::new(CyclicBuffer::new(0, OverflowPolicy::OverwriteOldest));
+
+            let producer = {
+                let buffer = buffer.clone();
+                thread::spawn(move || {
+                    let mut writer = buffer.take_writer().unwrap();
+                    // The last two have to overwrite, the last one the slot a copy starts at.
+                    for value in 1..=4 {
+                        let _ = writer.append(value);
+                    }
+                })
+            };
+
+            let mut received = Vec::new();
+            read_all(&buffer, &mut received);
+
+            producer.join().unwrap();
+            read_all(&buffer, &mut received);
+
+            assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(received.iter().all(|value| (1..=4).contains(value)));
+            assert_eq!(received.len() + buffer.missed(), 4);
+        });
+    }
+
+    /// A reader that makes room while the writer is about to overwrite must not cost the new
+    /// point, only a reader holding a batch may.
+    #[test]
+    fn overwriting_retries_when_the_reader_makes_room() {
+        loom::model(|| {
+            let buffer: Arc<CyclicBuffer<3, u32>> =
+                Arc::new(CyclicBuffer::new(0, OverflowPolicy::OverwriteOldest));
+            let reader = buffer.take_reader().unwrap();
+            let full = Arc::new(AtomicBool::new(false));
+
+            let producer = {
+                let (buffer, full) = (buffer.clone(), full.clone());
+                thread::spawn(move || {
+                    let mut writer = buffer.take_writer().unwrap();
+                    let filled = (1..=2).all(|value| writer.append(value).is_ok());
+                    full.store(true, Ordering::Release);
+                    // Finds the buffer full, unless the reader got in first.
+                    filled && writer.append(3).is_ok()
+                })
+            };
+            while !full.load(Ordering::Acquire) {
+                thread::yield_now();
+            }
+            // Skipping what is unread moves the read index without leasing it.
+            reader.skip_unread();
+
+            assert!(producer.join().unwrap());
+            assert_eq!(buffer.drop_counts().dropped_newest, 0);
+        });
+    }
+
+    #[test]
+    fn reader_is_handed_out_once() {
+        loom::model(|| {
+            let buffer: Arc<CyclicBuffer<2, u32>> =
+                Arc::new(CyclicBuffer::new(0, OverflowPolicy::DropNewest));
+
+            let other = {
+                let buffer = buffer.clone();
+                thread::spawn(move || buffer.take_reader().map(core::mem::forget).is_some())
+            };
+            let here = buffer.take_reader().map(core::mem::forget).is_some();
+            let there = other.join().unwrap();
+
+            assert!(here ^ there);
+        });
+    }
+
+    /// A reader subscribing while the writer appends must not be charged for what its
+    /// predecessor left unread.
+    #[test]
+    fn a_new_reader_starts_after_what_its_predecessor_left() {
+        loom::model(|| {
+            let buffer: Arc<BroadcastBuffer<1, 3, u32>> =
+                Arc::new(BroadcastBuffer::new(0, OverflowPolicy::DropNewest));
+            let previous = buffer.take_reader().unwrap();
+            let (full, left) = (
+                Arc::new(AtomicBool::new(false)),
+                Arc::new(AtomicBool::new(false)),
+            );
+
+            let producer = {
+                let (buffer, full, left) = (buffer.clone(), full.clone(), left.clone());
+                thread::spawn(move || {
+                    let mut writer = buffer.take_writer().unwrap();
+                    let filled = (1..=2).all(|value| writer.append(value).is_ok());
+                    full.store(true, Ordering::Release);
+                    while !left.load(Ordering::Acquire) {
+                        thread::yield_now();
+                    }
+                    filled && writer.append(3).is_ok()
+                })
+            };
+            while !full.load(Ordering::Acquire) {
+                thread::yield_now();
+            }
+            drop(previous);
+            left.store(true, Ordering::Release);
+            let reader = buffer.take_reader().unwrap();
+
+            assert!(producer.join().unwrap());
+            assert_eq!(reader.missed(), 0);
+        });
+    }
+}